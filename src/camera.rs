@@ -0,0 +1,185 @@
+use crate::sample::*;
+use crate::transform::Transform;
+use crate::vec::*;
+
+use std::f32::consts::PI;
+
+/// pbrt's "perspective" camera, with an optional thin lens.
+pub struct Camera {
+    pub width: usize,
+    pub height: usize,
+    camera_to_world: Transform,
+    world_to_camera: Transform,
+    lens_radius: Float,
+    focal_distance: Float,
+    // Screen window, scaled by tan(fov / 2) so it sits on the z = 1 plane.
+    screen_min: (Float, Float),
+    screen_max: (Float, Float),
+}
+
+impl Camera {
+    pub fn new(
+        camera_to_world: Transform,
+        width: usize,
+        height: usize,
+        fov: Float,
+        lens_radius: Float,
+        focal_distance: Float,
+    ) -> Camera {
+        // Like pbrt, `fov` spans the shorter image axis.
+        let aspect = width as Float / height as Float;
+        let (sx, sy) = if aspect > 1.0 {
+            (aspect, 1.0)
+        } else {
+            (1.0, 1.0 / aspect)
+        };
+        let scale = (fov * PI / 360.0).tan();
+
+        Camera {
+            width,
+            height,
+            world_to_camera: camera_to_world.inverse(),
+            camera_to_world,
+            lens_radius,
+            focal_distance,
+            screen_min: (-sx * scale, -sy * scale),
+            screen_max: (sx * scale, sy * scale),
+        }
+    }
+
+    /// Ray through the point `(x, y)` of the film, in raster coordinates
+    /// (pixels, origin at the top left). `lens` picks the point on the lens.
+    pub fn generate_ray(&self, x: Float, y: Float, lens: (Float, Float)) -> Ray {
+        let u = x / self.width as Float;
+        let v = y / self.height as Float;
+        let px = self.screen_min.0 + u * (self.screen_max.0 - self.screen_min.0);
+        let py = self.screen_max.1 - v * (self.screen_max.1 - self.screen_min.1);
+
+        let mut origin = Vec3::new(0.0, 0.0, 0.0);
+        let mut direction = Vec3::new(px, py, 1.0).to_unit();
+
+        if self.lens_radius > 0.0 {
+            let (lx, ly) = concentric_disk(lens);
+            let focus = &direction * (self.focal_distance / direction.z);
+            origin = Vec3::new(lx * self.lens_radius, ly * self.lens_radius, 0.0);
+            direction = (focus - &origin).to_unit();
+        }
+
+        Ray::new(
+            self.camera_to_world.point(&origin),
+            self.camera_to_world.vector(&direction).to_unit(),
+            0.0,
+        )
+    }
+
+    /// Area of the screen window on the z = 1 plane.
+    fn screen_area(&self) -> Float {
+        (self.screen_max.0 - self.screen_min.0) * (self.screen_max.1 - self.screen_min.1)
+    }
+
+    fn lens_area(&self) -> Float {
+        if self.lens_radius > 0.0 {
+            PI * self.lens_radius * self.lens_radius
+        } else {
+            1.0
+        }
+    }
+
+    /// Cosine between `ray` and the viewing direction, and the raster
+    /// position `ray` would have been generated for, if it's on the film.
+    fn raster(&self, ray: &Ray) -> Option<(Float, (Float, Float))> {
+        let origin = self.world_to_camera.point(&ray.origin);
+        let direction = self.world_to_camera.vector(&ray.direction).to_unit();
+        let cos = direction.z;
+        if cos <= 0.0 {
+            return None;
+        }
+
+        let distance = if self.lens_radius > 0.0 {
+            self.focal_distance
+        } else {
+            1.0
+        };
+        let focus = origin + direction * (distance / cos);
+        let (sx, sy) = (focus.x / focus.z, focus.y / focus.z);
+        let x = (sx - self.screen_min.0) / (self.screen_max.0 - self.screen_min.0);
+        let y = (self.screen_max.1 - sy) / (self.screen_max.1 - self.screen_min.1);
+        if !(0.0..1.0).contains(&x) || !(0.0..1.0).contains(&y) {
+            return None;
+        }
+        Some((cos, (x * self.width as Float, y * self.height as Float)))
+    }
+
+    /// Importance emitted along `ray`, which should start on the lens, and
+    /// the raster position it belongs to.
+    pub fn we(&self, ray: &Ray) -> Option<(Float, (Float, Float))> {
+        let (cos, raster) = self.raster(ray)?;
+        let cos2 = cos * cos;
+        Some((
+            1.0 / (self.screen_area() * self.lens_area() * cos2 * cos2),
+            raster,
+        ))
+    }
+
+    /// Densities of `generate_ray` producing `ray`: `(pdf_pos, pdf_dir)`,
+    /// with respect to lens area and solid angle.
+    pub fn pdf_we(&self, ray: &Ray) -> (Float, Float) {
+        match self.raster(ray) {
+            Some((cos, _)) => (
+                1.0 / self.lens_area(),
+                1.0 / (self.screen_area() * cos * cos * cos),
+            ),
+            None => (0.0, 0.0),
+        }
+    }
+
+    /// Samples a point on the lens as seen from `point`, for connecting
+    /// paths traced from the lights to the camera.
+    pub fn sample_wi(&self, point: &Vec3, u: (Float, Float)) -> Option<CameraSample> {
+        let (lx, ly) = concentric_disk(u);
+        let lens_point = self.camera_to_world.point(&Vec3::new(
+            lx * self.lens_radius,
+            ly * self.lens_radius,
+            0.0,
+        ));
+        let lens_normal = self
+            .camera_to_world
+            .vector(&Vec3::new(0.0, 0.0, 1.0))
+            .to_unit();
+
+        let to_lens = &lens_point - point;
+        let distance = to_lens.norm();
+        if distance == 0.0 {
+            return None;
+        }
+        let wi = &to_lens / distance;
+        let cos = (&lens_normal % &wi).abs();
+        if cos == 0.0 {
+            return None;
+        }
+
+        let (importance, raster) = self.we(&Ray::new(lens_point.clone(), wi.negate(), 0.0))?;
+        Some(CameraSample {
+            pdf: distance * distance / (cos * self.lens_area()),
+            wi,
+            importance,
+            raster,
+            point: lens_point,
+            normal: lens_normal,
+            distance,
+        })
+    }
+}
+
+/// A point on the lens sampled from some reference point.
+pub struct CameraSample {
+    /// Unit direction from the reference point to the lens.
+    pub wi: Vec3,
+    pub importance: Float,
+    /// With respect to solid angle at the reference point.
+    pub pdf: Float,
+    pub raster: (Float, Float),
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub distance: Float,
+}
@@ -0,0 +1,239 @@
+use crate::film::{Film, SplatFilm};
+use crate::integrator::debug::DebugMode;
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"rtckpt4\n";
+
+/// Longest name the header holds.
+const MAX_NAME: usize = 64;
+
+/// Where, and how often, a progressive render saves everything it needs to
+/// carry on after being killed.
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub interval: Duration,
+    /// Start from the checkpoint already at `path`, rather than from
+    /// scratch.
+    pub resume: bool,
+    /// `scene_hash` of the scene being rendered, which a checkpoint must
+    /// match to be resumed.
+    pub scene: u64,
+    /// The integrator's name and the `--debug` mode, which must match too:
+    /// their estimates can't be mixed with another's.
+    pub integrator: &'static str,
+    pub debug: Option<DebugMode>,
+}
+
+/// How far a render had got. Every sample's random numbers come from the
+/// seed, the sampler, its pixel and its pass, so those are all there is to
+/// the RNG state.
+pub struct Progress {
+    pub passes: u32,
+    pub seed: u64,
+    /// The sampler's name and samples per pixel.
+    pub sampler: String,
+    pub samples: u32,
+    /// Time spent rendering, across every run that contributed.
+    pub elapsed: Duration,
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint next to `path` and renames it into place, so
+    /// a render killed mid-write still has the previous one.
+    pub fn save(
+        &self,
+        progress: &Progress,
+        film: &Film,
+        splats: &[SplatFilm],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+
+        let mut out = BufWriter::new(File::create(&partial)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&self.scene.to_le_bytes())?;
+        write_name(&mut out, self.integrator)?;
+        write_name(&mut out, self.debug.map_or("", DebugMode::name))?;
+        write_name(&mut out, &progress.sampler)?;
+        out.write_all(&progress.samples.to_le_bytes())?;
+        out.write_all(&progress.passes.to_le_bytes())?;
+        out.write_all(&progress.seed.to_le_bytes())?;
+        out.write_all(&progress.elapsed.as_secs_f64().to_le_bytes())?;
+        out.write_all(&(splats.len() as u32).to_le_bytes())?;
+        film.write_state(&mut out)?;
+        for splat in splats {
+            splat.write_state(&mut out)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+
+    /// Restores `film` and `splats` to the state `save` wrote, failing if
+    /// the checkpoint is of another scene, or of a differently set-up
+    /// render: another integrator or debug mode. The sampler and seed are
+    /// left to the caller to check.
+    pub fn load(&self, film: &mut Film, splats: &[SplatFilm]) -> Result<Progress, Box<dyn Error>> {
+        let mut input = BufReader::new(
+            File::open(&self.path)
+                .map_err(|e| format!("couldn't open checkpoint {}: {}", self.path.display(), e))?,
+        );
+
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(format!("{} isn't a checkpoint", self.path.display()).into());
+        }
+        let mut u32_bytes = [0; 4];
+        let mut u64_bytes = [0; 8];
+        let mut f64_bytes = [0; 8];
+        input.read_exact(&mut u64_bytes)?;
+        if u64::from_le_bytes(u64_bytes) != self.scene {
+            return Err(
+                format!("checkpoint {} is of a different scene", self.path.display()).into(),
+            );
+        }
+        let integrator = read_name(&mut input)?;
+        let debug = read_name(&mut input)?;
+        if (integrator.as_str(), debug.as_str())
+            != (self.integrator, self.debug.map_or("", DebugMode::name))
+        {
+            let debug = if debug.is_empty() {
+                String::new()
+            } else {
+                format!(" with --debug {}", debug)
+            };
+            return Err(format!(
+                "checkpoint {} was rendered by the {} integrator{}",
+                self.path.display(),
+                integrator,
+                debug
+            )
+            .into());
+        }
+        let sampler = read_name(&mut input)?;
+        input.read_exact(&mut u32_bytes)?;
+        let samples = u32::from_le_bytes(u32_bytes);
+        input.read_exact(&mut u32_bytes)?;
+        let passes = u32::from_le_bytes(u32_bytes);
+        input.read_exact(&mut u64_bytes)?;
+        let seed = u64::from_le_bytes(u64_bytes);
+        input.read_exact(&mut f64_bytes)?;
+        let elapsed = Duration::from_secs_f64(f64::from_le_bytes(f64_bytes).max(0.0));
+        input.read_exact(&mut u32_bytes)?;
+        if u32::from_le_bytes(u32_bytes) as usize != splats.len() {
+            return Err("checkpoint was written by a different integrator".into());
+        }
+
+        film.read_state(&mut input)?;
+        for splat in splats {
+            splat.read_state(&mut input)?;
+        }
+        Ok(Progress {
+            passes,
+            seed,
+            sampler,
+            samples,
+            elapsed,
+        })
+    }
+}
+
+fn write_name(out: &mut dyn Write, name: &str) -> io::Result<()> {
+    out.write_all(&(name.len() as u32).to_le_bytes())?;
+    out.write_all(name.as_bytes())
+}
+
+fn read_name(input: &mut dyn Read) -> Result<String, Box<dyn Error>> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    let length = u32::from_le_bytes(bytes) as usize;
+    if length > MAX_NAME {
+        return Err("checkpoint is corrupt".into());
+    }
+    let mut name = vec![0; length];
+    input.read_exact(&mut name)?;
+    Ok(String::from_utf8(name)?)
+}
+
+/// Identifies a scene by its text, with any included files inlined as
+/// `flatten_file` does: the 64-bit FNV-1a hash of it.
+pub fn scene_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(name: &str, integrator: &'static str, debug: Option<DebugMode>) -> Checkpoint {
+        Checkpoint {
+            path: std::env::temp_dir().join(format!("{}-{}.ckpt", name, std::process::id())),
+            interval: Duration::from_secs(1),
+            resume: true,
+            scene: scene_hash("WorldBegin\nWorldEnd\n"),
+            integrator,
+            debug,
+        }
+    }
+
+    fn save(checkpoint: &Checkpoint) {
+        let progress = Progress {
+            passes: 3,
+            seed: 7,
+            sampler: "halton".to_string(),
+            samples: 16,
+            elapsed: Duration::from_secs(2),
+        };
+        checkpoint.save(&progress, &Film::new(4, 3), &[]).unwrap();
+    }
+
+    /// Loads the checkpoint `written` saved, as the given integrator.
+    fn load(
+        written: &Checkpoint,
+        integrator: &'static str,
+        debug: Option<DebugMode>,
+    ) -> Result<Progress, Box<dyn Error>> {
+        let checkpoint = Checkpoint {
+            integrator,
+            debug,
+            ..written.clone()
+        };
+        checkpoint.load(&mut Film::new(4, 3), &[])
+    }
+
+    #[test]
+    fn resumes_only_the_same_integrator_and_debug_mode() {
+        let written = checkpoint("integrator", "path", None);
+        save(&written);
+        let progress = load(&written, "path", None).unwrap();
+        assert_eq!((progress.passes, progress.seed), (3, 7));
+        let e = load(&written, "ambientocclusion", None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("by the path integrator"), "{}", e);
+        assert!(load(&written, "debug", Some(DebugMode::Normals)).is_err());
+        fs::remove_file(&written.path).unwrap();
+
+        let written = checkpoint("debug", "debug", Some(DebugMode::Normals));
+        save(&written);
+        assert!(load(&written, "debug", Some(DebugMode::Normals)).is_ok());
+        let e = load(&written, "debug", Some(DebugMode::Depth))
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("with --debug normals"), "{}", e);
+        assert!(load(&written, "debug", None).is_err());
+        fs::remove_file(&written.path).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use crate::image::Image;
+use crate::vec::{Float, Vec3};
+
+use rayon::prelude::*;
+
+// Feature bandwidths. Albedo and normal differences are in their natural
+// units; the depth term is relative to the depth of the centre pixel.
+const SIGMA_ALBEDO: Float = 0.1;
+const SIGMA_NORMAL: Float = 0.1;
+const SIGMA_DEPTH: Float = 0.05;
+
+// Strength of the colour term, and the cancellation factor applied to the
+// variance (see Rousselle et al., "Adaptive Rendering with Non-Local Means
+// Filtering", 2012).
+const K: Float = 0.45;
+const ALPHA: Float = 1.0;
+const EPSILON: Float = 1e-4;
+
+#[derive(Copy, Clone)]
+pub enum Filter {
+    /// Cross-bilateral filter: a spatial Gaussian, a variance-normalised
+    /// colour distance at the centre pixel, and the feature weights.
+    Bilateral,
+    /// Joint non-local means: the colour distance is averaged over a small
+    /// patch, which keeps texture and edges that one pixel can't resolve.
+    NonLocalMeans,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "bilateral" => Some(Filter::Bilateral),
+            "nlm" => Some(Filter::NonLocalMeans),
+            _ => None,
+        }
+    }
+}
+
+/// Image-space denoiser that runs on the output of `Integrator::render`.
+///
+/// The filter is guided by whichever of the albedo, normal, depth and
+/// variance buffers the integrator filled in. Albedo is divided out before
+/// filtering and multiplied back afterwards, so texture detail survives.
+/// The denoised image keeps the other buffers, but not the variance.
+pub struct Denoiser {
+    pub filter: Filter,
+    pub radius: usize,
+}
+
+struct Buffers<'a> {
+    width: usize,
+    height: usize,
+    color: Vec<Vec<Vec3>>,
+    variance: Vec<Vec<Vec3>>,
+    albedo: Option<&'a Vec<Vec<Vec3>>>,
+    normal: Option<&'a Vec<Vec<Vec3>>>,
+    depth: Option<&'a Vec<Vec<Float>>>,
+}
+
+impl Denoiser {
+    pub fn new(filter: Filter, radius: usize) -> Denoiser {
+        Denoiser { filter, radius }
+    }
+
+    pub fn denoise(&self, image: &Image) -> Image {
+        let buffers = Buffers::from_image(image);
+        let patch = match self.filter {
+            Filter::Bilateral => 0,
+            Filter::NonLocalMeans => 1,
+        };
+
+        let pixels = (0..buffers.height)
+            .into_par_iter()
+            .map(|y| {
+                (0..buffers.width)
+                    .map(|x| {
+                        let filtered = self.filter_pixel(&buffers, x, y, patch);
+                        match buffers.albedo {
+                            Some(albedo) => filtered * demodulation(&albedo[y][x]),
+                            None => filtered,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // The variance was of the noisy pixels, and says nothing about
+        // these.
+        Image {
+            variance: None,
+            ..image.with_pixels(pixels)
+        }
+    }
+
+    fn filter_pixel(&self, buf: &Buffers, x: usize, y: usize, patch: isize) -> Vec3 {
+        let radius = self.radius as isize;
+        let sigma_spatial = (self.radius as Float / 2.0).max(1.0);
+
+        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+        let mut weights = 0.0;
+
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let qx = x as isize + dx;
+                let qy = y as isize + dy;
+                if qx < 0 || qy < 0 || qx >= buf.width as isize || qy >= buf.height as isize {
+                    continue;
+                }
+                let (qx, qy) = (qx as usize, qy as usize);
+
+                let color = &buf.color[qy][qx];
+                if !color.is_finite() {
+                    continue;
+                }
+
+                let spatial = match self.filter {
+                    Filter::Bilateral => (-((dx * dx + dy * dy) as Float)
+                        / (2.0 * sigma_spatial * sigma_spatial))
+                        .exp(),
+                    Filter::NonLocalMeans => 1.0,
+                };
+
+                let weight = spatial
+                    * buf.color_weight(x, y, qx, qy, patch)
+                    * buf.feature_weight(x, y, qx, qy);
+
+                sum += color * weight;
+                weights += weight;
+            }
+        }
+
+        if weights > 0.0 {
+            sum / weights
+        } else {
+            buf.color[y][x].clone()
+        }
+    }
+}
+
+impl<'a> Buffers<'a> {
+    fn from_image(image: &'a Image) -> Buffers<'a> {
+        let width = image.width();
+        let height = image.height();
+
+        let color: Vec<Vec<Vec3>> = match &image.albedo {
+            Some(albedo) => image
+                .pixels
+                .iter()
+                .zip(albedo)
+                .map(|(row, arow)| {
+                    row.iter()
+                        .zip(arow)
+                        .map(|(c, a)| c / demodulation(a))
+                        .collect()
+                })
+                .collect(),
+            None => image.pixels.clone(),
+        };
+
+        let variance = match (&image.variance, &image.albedo) {
+            (Some(variance), Some(albedo)) => variance
+                .iter()
+                .zip(albedo)
+                .map(|(row, arow)| {
+                    row.iter()
+                        .zip(arow)
+                        .map(|(v, a)| {
+                            let d = demodulation(a);
+                            v / (&d * &d)
+                        })
+                        .collect()
+                })
+                .collect(),
+            (Some(variance), None) => variance.clone(),
+            (None, _) => estimate_variance(&color),
+        };
+
+        Buffers {
+            width,
+            height,
+            color,
+            variance,
+            albedo: image.albedo.as_ref(),
+            normal: image.normal.as_ref(),
+            depth: image.depth.as_ref(),
+        }
+    }
+
+    fn clamped(&self, x: isize, y: isize) -> (usize, usize) {
+        (
+            x.clamp(0, self.width as isize - 1) as usize,
+            y.clamp(0, self.height as isize - 1) as usize,
+        )
+    }
+
+    /// Variance-cancelled colour distance between the patches around `p`
+    /// and `q`, turned into a weight.
+    fn color_weight(&self, px: usize, py: usize, qx: usize, qy: usize, patch: isize) -> Float {
+        let mut distance = 0.0;
+        let mut count = 0.0;
+
+        for dy in -patch..=patch {
+            for dx in -patch..=patch {
+                let (ax, ay) = self.clamped(px as isize + dx, py as isize + dy);
+                let (bx, by) = self.clamped(qx as isize + dx, qy as isize + dy);
+                let a = &self.color[ay][ax];
+                let b = &self.color[by][bx];
+                if !a.is_finite() || !b.is_finite() {
+                    continue;
+                }
+
+                let va = &self.variance[ay][ax];
+                let vb = &self.variance[by][bx];
+                distance += channel_distance(a.x, b.x, va.x, vb.x)
+                    + channel_distance(a.y, b.y, va.y, vb.y)
+                    + channel_distance(a.z, b.z, va.z, vb.z);
+                count += 3.0;
+            }
+        }
+
+        if count == 0.0 {
+            return 0.0;
+        }
+
+        (-(distance / count).max(0.0)).exp()
+    }
+
+    fn feature_weight(&self, px: usize, py: usize, qx: usize, qy: usize) -> Float {
+        let mut weight = 1.0;
+
+        if let Some(albedo) = self.albedo {
+            let diff = &albedo[py][px] - &albedo[qy][qx];
+            weight *= (-(&diff % &diff) / (2.0 * SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+        }
+
+        if let Some(normal) = self.normal {
+            let cos = &normal[py][px] % &normal[qy][qx];
+            weight *= (-(1.0 - cos).max(0.0) / SIGMA_NORMAL).exp();
+        }
+
+        if let Some(depth) = self.depth {
+            let (dp, dq) = (depth[py][px], depth[qy][qx]);
+            if dp.is_finite() && dq.is_finite() {
+                weight *= (-(dp - dq).abs() / (SIGMA_DEPTH * dp.abs().max(EPSILON))).exp();
+            } else if dp.is_finite() != dq.is_finite() {
+                // One of the two pixels escaped to the background.
+                return 0.0;
+            }
+        }
+
+        weight
+    }
+}
+
+fn channel_distance(a: Float, b: Float, va: Float, vb: Float) -> Float {
+    ((a - b).powi(2) - ALPHA * (va + va.min(vb))) / (EPSILON + K * K * (va + vb))
+}
+
+fn demodulation(albedo: &Vec3) -> Vec3 {
+    Vec3::new(albedo.x.max(0.01), albedo.y.max(0.01), albedo.z.max(0.01))
+}
+
+/// Stand-in for a real per-pixel variance buffer: the variance of the
+/// 3x3 neighbourhood around each pixel.
+fn estimate_variance(color: &[Vec<Vec3>]) -> Vec<Vec<Vec3>> {
+    let height = color.len() as isize;
+    let width = color.first().map_or(0, |row| row.len()) as isize;
+
+    (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| {
+                    let mut sum = Vec3::new(0.0, 0.0, 0.0);
+                    let mut sum_sq = Vec3::new(0.0, 0.0, 0.0);
+                    let mut n = 0.0;
+                    for dy in -1..=1 {
+                        for dx in -1..=1 {
+                            let (qx, qy) = (x + dx, y + dy);
+                            if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                                continue;
+                            }
+                            let c = &color[qy as usize][qx as usize];
+                            if !c.is_finite() {
+                                continue;
+                            }
+                            sum += c.clone();
+                            sum_sq += c * c;
+                            n += 1.0;
+                        }
+                    }
+                    if n == 0.0 {
+                        // Every neighbour is NaN or infinite: no spread to estimate.
+                        return Vec3::new(0.0, 0.0, 0.0);
+                    }
+                    let mean = &sum / n;
+                    let var = &sum_sq / n - &mean * &mean;
+                    Vec3::new(var.x.max(0.0), var.y.max(0.0), var.z.max(0.0))
+                })
+                .collect()
+        })
+        .collect()
+}
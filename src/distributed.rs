@@ -0,0 +1,364 @@
+use crate::film::Film;
+use crate::image::Image;
+use crate::integrator::Progressive;
+use crate::parse::{flatten_file, parse_file, parse_source};
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// The protocol: the coordinator greets each worker with `MAGIC`, the
+// scene and the seed, then sends `TASK` and a range of passes, or `DONE`.
+// The worker answers each task with `OK` and the film it rendered, or
+// `FAILED` and why. Numbers are little-endian; strings are prefixed with
+// their length.
+const MAGIC: &[u8; 8] = b"rtdist2\n";
+const DONE: u8 = 0;
+const TASK: u8 = 1;
+const OK: u8 = 0;
+const FAILED: u8 = 1;
+
+/// How long a worker waits between attempts to reach the coordinator.
+const RETRY: Duration = Duration::from_secs(1);
+
+/// Longest string either side will read: a scene, which can hold big
+/// meshes, or why a worker failed. Anything longer is taken to be garbage,
+/// rather than allocated.
+const MAX_SCENE: usize = 256 << 20;
+const MAX_MESSAGE: usize = 64 << 10;
+
+/// Renders the scene at `path` on whichever workers connect to `addr`,
+/// handing out `task_samples` samples per pixel of the image at a time.
+/// Workers may come and go: a task whose worker disconnects goes to the
+/// next one to ask, and once there's nothing new to hand out, idle workers
+/// duplicate tasks still in progress, in case their worker has hung.
+/// The image matches one rendered locally with `seed`, up to the rounding
+/// of adding the workers' films together.
+///
+/// To try it out on one machine, run `ray-trace --worker 127.0.0.1:7878`
+/// a few times, and `ray-trace -f scene.pbrt --coordinator 127.0.0.1:7878`.
+pub fn coordinate(
+    addr: &str,
+    path: &str,
+    task_samples: u32,
+    seed: u64,
+) -> Result<Image, Box<dyn Error>> {
+    let (_, integrator) = parse_file(path, None)?;
+    let passes = integrator
+        .passes()
+        .ok_or("this integrator can't split its samples between workers")?;
+    let scene = Arc::new(flatten_file(path)?);
+    if scene.len() > MAX_SCENE {
+        return Err(format!("the scene is over {} bytes, too big to send", MAX_SCENE).into());
+    }
+
+    let task_samples = task_samples.max(1);
+    let pending: VecDeque<Range<u32>> = (0..passes)
+        .step_by(task_samples as usize)
+        .map(|start| start..(start + task_samples).min(passes))
+        .collect();
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            remaining: pending.len(),
+            pending,
+            running: Vec::new(),
+            film: None,
+            error: None,
+        }),
+        changed: Condvar::new(),
+        resolution: integrator.resolution(),
+    });
+
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("waiting for workers on {}", listener.local_addr()?);
+    {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("warning: couldn't accept a worker: {}", e);
+                        continue;
+                    }
+                };
+                let (shared, scene) = (Arc::clone(&shared), Arc::clone(&scene));
+                thread::spawn(move || {
+                    let peer = stream
+                        .peer_addr()
+                        .map_or_else(|_| "a worker".to_string(), |a| a.to_string());
+                    if let Err(e) = serve(stream, &scene, seed, &shared) {
+                        eprintln!("warning: lost {}: {}", peer, e);
+                    }
+                });
+            }
+        });
+    }
+
+    let mut state = shared.state.lock().unwrap();
+    while state.remaining > 0 && state.error.is_none() {
+        state = shared.changed.wait(state).unwrap();
+    }
+    if let Some(e) = state.error.take() {
+        return Err(e.into());
+    }
+    Ok(match state.film.take() {
+        Some(film) => film.to_image(),
+        None => Image::new(0, 0),
+    })
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// The size every film sent back has to be.
+    resolution: (usize, usize),
+}
+
+struct State {
+    /// Tasks nobody has.
+    pending: VecDeque<Range<u32>>,
+    /// Unfinished tasks that have been handed out, and to how many workers.
+    running: Vec<(Range<u32>, usize)>,
+    remaining: usize,
+    /// The sum of what the workers have sent back so far.
+    film: Option<Film>,
+    /// A worker couldn't render the scene, so no other will either.
+    error: Option<String>,
+}
+
+impl State {
+    /// The task that most needs a worker, if there's anything left to do.
+    fn take_task(&mut self) -> Option<Range<u32>> {
+        if self.error.is_some() {
+            return None;
+        }
+        if let Some(task) = self.pending.pop_front() {
+            self.running.push((task.clone(), 1));
+            return Some(task);
+        }
+        let (task, workers) = self.running.iter_mut().min_by_key(|(_, n)| *n)?;
+        *workers += 1;
+        Some(task.clone())
+    }
+
+    /// Adds in a task's film, unless another worker got there first.
+    fn finish(&mut self, task: &Range<u32>, film: Film) -> Result<(), Box<dyn Error>> {
+        let i = match self.running.iter().position(|(t, _)| t == task) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        match &mut self.film {
+            Some(sum) if (sum.width, sum.height) != (film.width, film.height) => {
+                return Err("film is the wrong size".into());
+            }
+            Some(sum) => sum.merge(&film),
+            None => self.film = Some(film),
+        }
+        self.running.remove(i);
+        self.remaining -= 1;
+        Ok(())
+    }
+
+    /// Puts a task back for someone else, unless another worker has it.
+    fn abandon(&mut self, task: &Range<u32>) {
+        if let Some(i) = self.running.iter().position(|(t, _)| t == task) {
+            self.running[i].1 -= 1;
+            if self.running[i].1 == 0 {
+                self.running.remove(i);
+                self.pending.push_front(task.clone());
+            }
+        }
+    }
+}
+
+/// Hands tasks to the worker on `stream` until there are none left.
+fn serve(stream: TcpStream, scene: &str, seed: u64, shared: &Shared) -> Result<(), Box<dyn Error>> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    out.write_all(MAGIC)?;
+    write_string(&mut out, scene)?;
+    out.write_all(&seed.to_le_bytes())?;
+
+    loop {
+        let task = match shared.state.lock().unwrap().take_task() {
+            Some(task) => task,
+            None => {
+                out.write_all(&[DONE])?;
+                out.flush()?;
+                return Ok(());
+            }
+        };
+
+        let result = run_task(&mut input, &mut out, &task, shared.resolution);
+        let mut state = shared.state.lock().unwrap();
+        let e = match result {
+            Ok(Ok(film)) => match state.finish(&task, film) {
+                Ok(()) => {
+                    shared.changed.notify_all();
+                    continue;
+                }
+                Err(e) => e,
+            },
+            Ok(Err(message)) => {
+                state.error = Some(format!("worker failed: {}", message));
+                shared.changed.notify_all();
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        state.abandon(&task);
+        return Err(e);
+    }
+}
+
+/// Sends `task` and waits for the worker's film, or the reason it
+/// couldn't render one.
+fn run_task(
+    input: &mut dyn Read,
+    out: &mut BufWriter<TcpStream>,
+    task: &Range<u32>,
+    (width, height): (usize, usize),
+) -> Result<Result<Film, String>, Box<dyn Error>> {
+    out.write_all(&[TASK])?;
+    out.write_all(&task.start.to_le_bytes())?;
+    out.write_all(&task.end.to_le_bytes())?;
+    out.flush()?;
+
+    match read_u8(input)? {
+        OK => Ok(Ok(Film::from_state(input, width, height)?)),
+        FAILED => Ok(Err(read_string(input, MAX_MESSAGE)?)),
+        other => Err(format!("unexpected reply {}", other).into()),
+    }
+}
+
+/// Renders whatever the coordinator at `addr` asks for, until it says
+/// it's done. Waits for the coordinator to come up, if need be.
+pub fn work(addr: &str) -> Result<(), Box<dyn Error>> {
+    let stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(RETRY),
+        }
+    };
+    render_for(stream).map_err(|e| format!("lost the coordinator at {}: {}", addr, e).into())
+}
+
+fn render_for(stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err("that isn't a coordinator".into());
+    }
+    let mut parsed = parse_source(&read_string(&mut input, MAX_SCENE)?, None);
+    let mut seed = [0; 8];
+    input.read_exact(&mut seed)?;
+    let seed = u64::from_le_bytes(seed);
+
+    loop {
+        // The coordinator hangs up without a word once it has every task
+        // back, even on workers still busy with a duplicate, so that's as
+        // good as being told it's done.
+        let message = match read_u8(&mut input) {
+            Ok(message) => message,
+            Err(e) if hung_up(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        match message {
+            DONE => return Ok(()),
+            TASK => {}
+            other => return Err(format!("unexpected message {}", other).into()),
+        }
+        let start = read_u32(&mut input)?;
+        let end = read_u32(&mut input)?;
+
+        let film = match &mut parsed {
+            Ok((scene, integrator)) => {
+                let progressive = Progressive {
+                    passes: Some(start..end),
+                    seed,
+                    ..Progressive::default()
+                };
+                integrator
+                    .render_progressive(scene.as_ref(), &progressive, &())
+                    .map_err(|e| e.to_string())
+                    .and_then(|image| {
+                        Film::from_image(&image)
+                            .ok_or_else(|| "integrator didn't count its samples".to_string())
+                    })
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        let replied = match film {
+            Ok(film) => out
+                .write_all(&[OK])
+                .and_then(|()| film.write_state(&mut out)),
+            Err(mut message) => {
+                // Within what the coordinator will read.
+                let mut end = message.len().min(MAX_MESSAGE);
+                while !message.is_char_boundary(end) {
+                    end -= 1;
+                }
+                message.truncate(end);
+                out.write_all(&[FAILED])
+                    .and_then(|()| write_string(&mut out, &message))
+            }
+        };
+        match replied.and_then(|()| out.flush()) {
+            Ok(()) => {}
+            Err(e) if hung_up(&e) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn hung_up(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+fn read_u8(input: &mut dyn Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32(input: &mut dyn Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Reads a string, failing rather than allocate more than `limit` bytes.
+fn read_string(input: &mut dyn Read, limit: usize) -> io::Result<String> {
+    let length = read_u32(input)? as usize;
+    if length > limit {
+        let e = format!(
+            "a string of {} bytes is over the limit of {}",
+            length, limit
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    let mut bytes = vec![0; length];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_string(out: &mut dyn Write, s: &str) -> io::Result<()> {
+    out.write_all(&(s.len() as u32).to_le_bytes())?;
+    out.write_all(s.as_bytes())?;
+    Ok(())
+}
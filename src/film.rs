@@ -0,0 +1,421 @@
+use crate::image::Image;
+use crate::vec::{Float, Vec3};
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Auxiliary values for one camera sample, taken at the first surface hit.
+pub struct Aovs {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: Float,
+}
+
+impl Default for Aovs {
+    /// What a camera ray that escapes the scene records.
+    fn default() -> Aovs {
+        Aovs {
+            albedo: Vec3::new(1.0, 1.0, 1.0),
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            depth: Float::INFINITY,
+        }
+    }
+}
+
+/// Running statistics for one pixel, updated with Welford's algorithm so
+/// the variance stays accurate over millions of samples.
+#[derive(Clone)]
+pub struct Pixel {
+    pub samples: u32,
+    pub mean: Vec3,
+    m2: Vec3,
+    albedo: Vec3,
+    normal: Vec3,
+    depth: Float,
+}
+
+impl Pixel {
+    pub fn new() -> Pixel {
+        Pixel {
+            samples: 0,
+            mean: Vec3::new(0.0, 0.0, 0.0),
+            m2: Vec3::new(0.0, 0.0, 0.0),
+            albedo: Vec3::new(0.0, 0.0, 0.0),
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            depth: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, value: &Vec3, aovs: &Aovs) {
+        self.samples += 1;
+        let delta = value - &self.mean;
+        self.mean += &delta / self.samples as Float;
+        self.m2 += delta * (value - &self.mean);
+
+        self.albedo += aovs.albedo.clone();
+        self.normal += aovs.normal.clone();
+        self.depth += aovs.depth;
+    }
+
+    /// Unbiased variance of the individual samples.
+    pub fn variance(&self) -> Vec3 {
+        if self.samples < 2 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        &self.m2 / (self.samples - 1) as Float
+    }
+
+    /// Variance of the pixel's mean, i.e. how far the estimate is likely to
+    /// be from the converged value.
+    pub fn mean_variance(&self) -> Vec3 {
+        if self.samples == 0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        self.variance() / self.samples as Float
+    }
+
+    /// Standard error of the mean's luminance, relative to the mean. The
+    /// denominator is padded so that dark pixels don't dominate.
+    pub fn relative_error(&self) -> Float {
+        let variance = self.mean_variance().luminance().max(0.0);
+        variance.sqrt() / (self.mean.luminance().max(0.0) + 0.01)
+    }
+
+    /// Adds in the samples `other` took, as if they'd been added here one
+    /// at a time (Chan et al.'s parallel variance).
+    pub fn merge(&mut self, other: &Pixel) {
+        let n = self.samples + other.samples;
+        if n == 0 {
+            return;
+        }
+        let delta = &other.mean - &self.mean;
+        let weight = other.samples as Float / n as Float;
+        let m2 = &delta * &delta * (self.samples as Float * weight);
+        self.mean += &delta * weight;
+        self.m2 += &other.m2 + &m2;
+        self.albedo += other.albedo.clone();
+        self.normal += other.normal.clone();
+        self.depth += other.depth;
+        self.samples = n;
+    }
+
+    fn write_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.samples.to_le_bytes())?;
+        for v in &[&self.mean, &self.m2, &self.albedo, &self.normal] {
+            write_floats(out, &[v.x, v.y, v.z])?;
+        }
+        write_floats(out, &[self.depth])
+    }
+
+    fn read_state(input: &mut dyn Read) -> io::Result<Pixel> {
+        let mut samples = [0; 4];
+        input.read_exact(&mut samples)?;
+        let mut v = [0.0; 13];
+        read_floats(input, &mut v)?;
+        Ok(Pixel {
+            samples: u32::from_le_bytes(samples),
+            mean: Vec3::new(v[0], v[1], v[2]),
+            m2: Vec3::new(v[3], v[4], v[5]),
+            albedo: Vec3::new(v[6], v[7], v[8]),
+            normal: Vec3::new(v[9], v[10], v[11]),
+            depth: v[12],
+        })
+    }
+
+    fn aovs(&self) -> Aovs {
+        if self.samples == 0 {
+            return Aovs::default();
+        }
+        let n = self.samples as Float;
+        Aovs {
+            albedo: &self.albedo / n,
+            normal: &self.normal / n,
+            depth: self.depth / n,
+        }
+    }
+}
+
+impl Default for Pixel {
+    fn default() -> Pixel {
+        Pixel::new()
+    }
+}
+
+/// Accumulates radiance samples for every pixel of the image being rendered.
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec<Pixel>>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            pixels: vec![vec![Pixel::new(); width]; height],
+        }
+    }
+
+    pub fn add_sample(&mut self, x: usize, y: usize, value: &Vec3, aovs: &Aovs) {
+        self.pixels[y][x].add(value, aovs);
+    }
+
+    /// Total number of samples taken, over all pixels.
+    pub fn total_samples(&self) -> u64 {
+        self.pixels.iter().flatten().map(|p| p.samples as u64).sum()
+    }
+
+    /// Average of the pixels' relative errors.
+    pub fn relative_error(&self) -> Float {
+        let n = self.width * self.height;
+        if n == 0 {
+            return 0.0;
+        }
+        let sum: f64 = self
+            .pixels
+            .iter()
+            .flatten()
+            .map(|p| p.relative_error() as f64)
+            .sum();
+        (sum / n as f64) as Float
+    }
+
+    /// Resolves the film into an image whose colour is the per-pixel mean,
+    /// with the variance of that mean, the sample counts and the averaged
+    /// AOVs as extra buffers.
+    pub fn to_image(&self) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        image.pixels = self.map(|p| p.mean.clone());
+        image.variance = Some(self.map(Pixel::mean_variance));
+        image.samples = Some(self.map(|p| p.samples));
+        image.albedo = Some(self.map(|p| p.aovs().albedo));
+        image.normal = Some(self.map(|p| p.aovs().normal));
+        image.depth = Some(self.map(|p| p.aovs().depth));
+        image
+    }
+
+    /// Recovers the statistics behind an image made by `to_image`, if it
+    /// still has its variance and sample counts.
+    pub fn from_image(image: &Image) -> Option<Film> {
+        let (variance, samples) = (image.variance.as_ref()?, image.samples.as_ref()?);
+        let (width, height) = (image.width(), image.height());
+        let mut film = Film::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let n = samples[y][x] as Float;
+                let pixel = &mut film.pixels[y][x];
+                pixel.samples = samples[y][x];
+                pixel.mean = image.pixels[y][x].clone();
+                pixel.m2 = &variance[y][x] * (n * (n - 1.0).max(0.0));
+                if let Some(albedo) = &image.albedo {
+                    pixel.albedo = &albedo[y][x] * n;
+                }
+                if let Some(normal) = &image.normal {
+                    pixel.normal = &normal[y][x] * n;
+                }
+                if let Some(depth) = &image.depth {
+                    pixel.depth = depth[y][x] * n;
+                }
+            }
+        }
+        Some(film)
+    }
+
+    /// Adds in the samples of a film of the same size.
+    pub fn merge(&mut self, other: &Film) {
+        for (row, other) in self.pixels.iter_mut().zip(&other.pixels) {
+            for (pixel, other) in row.iter_mut().zip(other) {
+                pixel.merge(other);
+            }
+        }
+    }
+
+    /// Writes every pixel's running statistics exactly, so that
+    /// `read_state` can carry on from where this film left off.
+    pub fn write_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_size(out, self.width, self.height)?;
+        for pixel in self.pixels.iter().flatten() {
+            pixel.write_state(out)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the pixels with those `write_state` wrote, from a film of
+    /// the same size.
+    pub fn read_state(&mut self, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        *self = Film::from_state(input, self.width, self.height)?;
+        Ok(())
+    }
+
+    /// The film `write_state` wrote, which has to be `width` by `height`;
+    /// checked before anything is allocated, since `input` may not be
+    /// trustworthy.
+    pub fn from_state(input: &mut dyn Read, width: usize, height: usize) -> io::Result<Film> {
+        let size = read_size(input)?;
+        if size != (width, height) {
+            let e = format!(
+                "film is {}x{}, but expected {}x{}",
+                size.0, size.1, width, height
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let mut film = Film::new(width, height);
+        for pixel in film.pixels.iter_mut().flatten() {
+            *pixel = Pixel::read_state(input)?;
+        }
+        Ok(film)
+    }
+
+    fn map<T, F: Fn(&Pixel) -> T>(&self, f: F) -> Vec<Vec<T>> {
+        self.pixels
+            .iter()
+            .map(|row| row.iter().map(&f).collect())
+            .collect()
+    }
+}
+
+/// A float that threads can add to concurrently, stored as its bits.
+struct AtomicFloat(AtomicU32);
+
+impl AtomicFloat {
+    fn new(value: Float) -> AtomicFloat {
+        AtomicFloat(AtomicU32::new(value.to_bits()))
+    }
+
+    fn add(&self, value: Float) {
+        let mut old = self.0.load(Ordering::Relaxed);
+        loop {
+            let new = (Float::from_bits(old) + value).to_bits();
+            match self
+                .0
+                .compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => old = current,
+            }
+        }
+    }
+
+    fn get(&self) -> Float {
+        Float::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, value: Float) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
+
+/// Contributions from paths that land on the film wherever they like
+/// rather than at the pixel being sampled, e.g. when tracing from the
+/// lights. Any thread may splat onto any pixel without locking.
+pub struct SplatFilm {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<[AtomicFloat; 3]>,
+}
+
+impl SplatFilm {
+    pub fn new(width: usize, height: usize) -> SplatFilm {
+        SplatFilm {
+            width,
+            height,
+            pixels: (0..width * height)
+                .map(|_| {
+                    [
+                        AtomicFloat::new(0.0),
+                        AtomicFloat::new(0.0),
+                        AtomicFloat::new(0.0),
+                    ]
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds `value` to the pixel containing raster position `(x, y)`.
+    /// Positions off the film and non-finite values are dropped.
+    pub fn add(&self, (x, y): (Float, Float), value: &Vec3) {
+        if !(value.is_finite() && x >= 0.0 && y >= 0.0) {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let pixel = &self.pixels[y * self.width + x];
+        pixel[0].add(value.x);
+        pixel[1].add(value.y);
+        pixel[2].add(value.z);
+    }
+
+    pub fn write_state(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_size(out, self.width, self.height)?;
+        for pixel in &self.pixels {
+            write_floats(out, &[pixel[0].get(), pixel[1].get(), pixel[2].get()])?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the sums with those `write_state` wrote, from a film of
+    /// the same size.
+    pub fn read_state(&self, input: &mut dyn Read) -> Result<(), Box<dyn Error>> {
+        let (width, height) = read_size(input)?;
+        if (width, height) != (self.width, self.height) {
+            return Err(format!(
+                "film is {}x{}, but expected {}x{}",
+                width, height, self.width, self.height
+            )
+            .into());
+        }
+        let mut v = [0.0; 3];
+        for pixel in &self.pixels {
+            read_floats(input, &mut v)?;
+            for (sum, &value) in pixel.iter().zip(&v) {
+                sum.set(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// The splatted values, each multiplied by `scale`.
+    pub fn to_pixels(&self, scale: Float) -> Vec<Vec<Vec3>> {
+        self.pixels
+            .chunks(self.width.max(1))
+            .map(|row| {
+                row.iter()
+                    .map(|p| Vec3::new(p[0].get(), p[1].get(), p[2].get()) * scale)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn write_floats(out: &mut dyn Write, values: &[Float]) -> io::Result<()> {
+    for value in values {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_floats(input: &mut dyn Read, values: &mut [Float]) -> io::Result<()> {
+    let mut bytes = [0; 4];
+    for value in values.iter_mut() {
+        input.read_exact(&mut bytes)?;
+        *value = Float::from_le_bytes(bytes);
+    }
+    Ok(())
+}
+
+fn write_size(out: &mut dyn Write, width: usize, height: usize) -> io::Result<()> {
+    out.write_all(&(width as u32).to_le_bytes())?;
+    out.write_all(&(height as u32).to_le_bytes())
+}
+
+fn read_size(input: &mut dyn Read) -> io::Result<(usize, usize)> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    let width = u32::from_le_bytes(bytes) as usize;
+    input.read_exact(&mut bytes)?;
+    Ok((width, u32::from_le_bytes(bytes) as usize))
+}
@@ -1,13 +1,357 @@
-use crate::vec::Vec3;
+use crate::vec::{Float, Vec3};
 
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 
+/// A rendered frame, plus whatever auxiliary buffers (AOVs) the integrator
+/// chose to fill in. Buffers are indexed `[y][x]`, with `y = 0` at the top.
+///
+/// `variance` is the variance of each pixel's mean, not of the individual
+/// samples; multiply by `samples` to get the latter back.
 pub struct Image {
-    pixels: Vec<Vec<Vec3>>,
+    pub pixels: Vec<Vec<Vec3>>,
+    pub albedo: Option<Vec<Vec<Vec3>>>,
+    pub normal: Option<Vec<Vec<Vec3>>>,
+    pub depth: Option<Vec<Vec<Float>>>,
+    pub variance: Option<Vec<Vec<Vec3>>>,
+    pub samples: Option<Vec<Vec<u32>>>,
+}
+
+enum Channel<'a> {
+    Float(Box<dyn Fn(usize, usize) -> Float + 'a>),
+    Uint(Box<dyn Fn(usize, usize) -> u32 + 'a>),
 }
 
 impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            pixels: vec![vec![Vec3::new(0.0, 0.0, 0.0); width]; height],
+            albedo: None,
+            normal: None,
+            depth: None,
+            variance: None,
+            samples: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.pixels.first().map_or(0, |row| row.len())
+    }
+
+    pub fn height(&self) -> usize {
+        self.pixels.len()
+    }
+
+    /// Builds a new image from `pixels`, keeping this image's AOV buffers.
+    pub fn with_pixels(&self, pixels: Vec<Vec<Vec3>>) -> Image {
+        Image {
+            pixels,
+            albedo: self.albedo.clone(),
+            normal: self.normal.clone(),
+            depth: self.depth.clone(),
+            variance: self.variance.clone(),
+            samples: self.samples.clone(),
+        }
+    }
+
+    /// Reads a binary (P6) or plain (P3) PPM, undoing the gamma that
+    /// `write_to` applies.
+    pub fn read_ppm(file: &str) -> Result<Image, Box<dyn Error>> {
+        let data = fs::read(file)?;
+
+        // The header is four whitespace-separated tokens, with `#` comments.
+        let mut pos = 0;
+        let mut tokens = Vec::new();
+        while tokens.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                }
+                pos += 1;
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err(format!("{}: truncated PPM header", file).into());
+            }
+            tokens.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+        }
+        pos += 1;
+
+        let width = tokens[1].parse::<usize>()?;
+        let height = tokens[2].parse::<usize>()?;
+        let max = tokens[3].parse::<Float>()?;
+
+        let values: Vec<Float> = match tokens[0].as_str() {
+            "P6" if max < 256.0 => data[pos.min(data.len())..]
+                .iter()
+                .map(|&b| Float::from(b))
+                .collect(),
+            "P3" => String::from_utf8_lossy(&data[pos.min(data.len())..])
+                .split_whitespace()
+                .map(|v| v.parse::<Float>())
+                .collect::<Result<_, _>>()?,
+            magic => return Err(format!("{}: unsupported PPM type {}", file, magic).into()),
+        };
+        if values.len() < width * height * 3 {
+            return Err(format!("{}: not enough pixel data", file).into());
+        }
+
+        let mut image = Image::new(width, height);
+        for (i, rgb) in values.chunks(3).take(width * height).enumerate() {
+            let v = |c: Float| (c / max).powi(2);
+            image.pixels[i / width][i % width] = Vec3::new(v(rgb[0]), v(rgb[1]), v(rgb[2]));
+        }
+        Ok(image)
+    }
+
+    /// Writes the image, picking the format from the file extension: `.exr`,
+    /// `.png`, or PPM for anything else. Only `.exr` keeps the AOV, variance
+    /// and sample-count buffers.
     pub fn write_to(&self, file: &str) -> Result<(), Box<dyn Error>> {
-        todo!()
+        let mut output = BufWriter::new(File::create(file)?);
+        if file.ends_with(".exr") {
+            self.write_exr(&mut output)?;
+        } else if file.ends_with(".png") {
+            self.write_png(&mut output)?;
+        } else {
+            self.write_ppm(&mut output)?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+
+    fn write_ppm(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(b"P3\n")?;
+        output.write_all(format!("{} {}\n", self.width(), self.height()).as_bytes())?;
+        output.write_all(b"255\n")?;
+
+        for row in &self.pixels {
+            for pixel in row {
+                output.write_all(pixel.as_ppm().as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn channels(&self) -> Vec<(String, Channel<'_>)> {
+        let mut channels = rgb_channels("", ["R", "G", "B"], &self.pixels);
+        if let Some(albedo) = &self.albedo {
+            channels.extend(rgb_channels("albedo", ["R", "G", "B"], albedo));
+        }
+        if let Some(normal) = &self.normal {
+            channels.extend(rgb_channels("normal", ["X", "Y", "Z"], normal));
+        }
+        if let Some(variance) = &self.variance {
+            channels.extend(rgb_channels("variance", ["R", "G", "B"], variance));
+        }
+        if let Some(depth) = &self.depth {
+            channels.push((
+                "Z".to_string(),
+                Channel::Float(Box::new(move |x, y| depth[y][x])),
+            ));
+        }
+        if let Some(samples) = &self.samples {
+            channels.push((
+                "samples".to_string(),
+                Channel::Uint(Box::new(move |x, y| samples[y][x])),
+            ));
+        }
+
+        // OpenEXR requires the channel list in byte order.
+        channels.sort_by(|a, b| a.0.cmp(&b.0));
+        channels
+    }
+
+    /// 8-bit RGB PNG, with the same gamma as the PPM. The image data goes
+    /// in stored (uncompressed) deflate blocks, which every decoder reads.
+    pub fn write_png(&self, output: &mut dyn Write) -> io::Result<()> {
+        let (width, height) = (self.width(), self.height());
+
+        // Each scanline starts with its filter type, 0 for none.
+        let mut raw = Vec::with_capacity((3 * width + 1) * height);
+        for row in &self.pixels {
+            raw.push(0);
+            for pixel in row {
+                for c in &[pixel.x, pixel.y, pixel.z] {
+                    raw.push((c.clamp(0.0, 1.0).sqrt() * 255.99).floor() as u8);
+                }
+            }
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            zlib.push(blocks.peek().is_none() as u8);
+            let len = block.len() as u16;
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, deflate, no filtering, no interlacing.
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        output.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'])?;
+        png_chunk(output, b"IHDR", &ihdr)?;
+        png_chunk(output, b"IDAT", &zlib)?;
+        png_chunk(output, b"IEND", &[])
+    }
+
+    /// Uncompressed, single-part, scanline OpenEXR.
+    pub fn write_exr(&self, output: &mut dyn Write) -> io::Result<()> {
+        let (width, height) = (self.width(), self.height());
+        let channels = self.channels();
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+        let mut chlist = Vec::new();
+        for (name, channel) in &channels {
+            chlist.extend_from_slice(name.as_bytes());
+            chlist.push(0);
+            let pixel_type: i32 = match channel {
+                Channel::Uint(_) => 0,
+                Channel::Float(_) => 2,
+            };
+            chlist.extend_from_slice(&pixel_type.to_le_bytes());
+            chlist.extend_from_slice(&[0, 0, 0, 0]); // pLinear + reserved
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+        }
+        chlist.push(0);
+        exr_attribute(&mut header, "channels", "chlist", &chlist);
+
+        exr_attribute(&mut header, "compression", "compression", &[0]);
+
+        let mut window = Vec::new();
+        for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+            window.extend_from_slice(&v.to_le_bytes());
+        }
+        exr_attribute(&mut header, "dataWindow", "box2i", &window);
+        exr_attribute(&mut header, "displayWindow", "box2i", &window);
+        exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        exr_attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        exr_attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1f32.to_le_bytes(),
+        );
+        header.push(0);
+
+        // Every channel type we write is four bytes wide.
+        let line_size = 4 * width * channels.len();
+        let first_line = header.len() + 8 * height;
+
+        output.write_all(&header)?;
+        for y in 0..height {
+            let offset = (first_line + y * (8 + line_size)) as u64;
+            output.write_all(&offset.to_le_bytes())?;
+        }
+
+        for y in 0..height {
+            output.write_all(&(y as i32).to_le_bytes())?;
+            output.write_all(&(line_size as i32).to_le_bytes())?;
+            for (_, channel) in &channels {
+                for x in 0..width {
+                    match channel {
+                        Channel::Float(f) => output.write_all(&f(x, y).to_le_bytes())?,
+                        Channel::Uint(f) => output.write_all(&f(x, y).to_le_bytes())?,
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn rgb_channels<'a>(
+    layer: &str,
+    names: [&str; 3],
+    buf: &'a [Vec<Vec3>],
+) -> Vec<(String, Channel<'a>)> {
+    let name = |channel: &str| {
+        if layer.is_empty() {
+            channel.to_string()
+        } else {
+            format!("{}.{}", layer, channel)
+        }
+    };
+
+    vec![
+        (
+            name(names[0]),
+            Channel::Float(Box::new(move |x, y| buf[y][x].x)),
+        ),
+        (
+            name(names[1]),
+            Channel::Float(Box::new(move |x, y| buf[y][x].y)),
+        ),
+        (
+            name(names[2]),
+            Channel::Float(Box::new(move |x, y| buf[y][x].z)),
+        ),
+    ]
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn png_chunk(output: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(kind)?;
+    output.write_all(data)?;
+    let crc = crc32(crc32(!0, kind), data);
+    output.write_all(&(!crc).to_be_bytes())
+}
+
+/// Carries on the CRC-32 of PNG (and zip) from `crc`, bit by bit.
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + u32::from(byte)) % 65521;
+        b = (b + a) % 65521;
     }
+    (b << 16) | a
 }
@@ -0,0 +1,140 @@
+use crate::camera::Camera;
+use crate::film::Aovs;
+use crate::image::Image;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::{
+    cosine_hemisphere, cosine_hemisphere_pdf, uniform_hemisphere, uniform_hemisphere_pdf, Frame,
+};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec::*;
+
+use rand::Rng;
+
+use std::error::Error;
+use std::f32::consts::PI;
+
+/// Ambient occlusion; pbrt's `Integrator "ambientocclusion"`. Each camera
+/// ray's first hit looks around its hemisphere for anything closer than
+/// `max_distance`. Unlike pbrt-v3 the result is normalized, so an
+/// unoccluded surface comes out as 1 rather than pi.
+pub struct AOIntegrator {
+    camera: Camera,
+    sampler: Box<dyn Sampler>,
+    /// Rays per camera sample.
+    ao_samples: u32,
+    max_distance: Float,
+    cos_sample: bool,
+}
+
+impl AOIntegrator {
+    pub fn new(
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+        ao_samples: u32,
+        max_distance: Float,
+        cos_sample: bool,
+    ) -> AOIntegrator {
+        AOIntegrator {
+            camera,
+            sampler,
+            ao_samples,
+            max_distance,
+            cos_sample,
+        }
+    }
+
+    pub fn from_params(
+        params: &ParamSet,
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+    ) -> Result<AOIntegrator, Box<dyn Error>> {
+        let max_distance = params.float("maxdistance", Float::INFINITY);
+        if max_distance <= 0.0 {
+            return Err(format!("\"maxdistance\" must be positive, not {}", max_distance).into());
+        }
+
+        Ok(AOIntegrator::new(
+            camera,
+            sampler,
+            params.int("nsamples", 64).max(1) as u32,
+            max_distance,
+            params.bool("cossample", true),
+        ))
+    }
+}
+
+impl Integrator for AOIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        self.sampler.seed(progressive.seed);
+        render_pixels(self, scene, progressive, observer)
+    }
+
+    fn passes(&self) -> Option<u32> {
+        Some(self.sampler.samples_per_pixel())
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "ambientocclusion"
+    }
+}
+
+impl SamplerIntegrator for AOIntegrator {
+    fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    fn sampler(&self) -> &dyn Sampler {
+        self.sampler.as_ref()
+    }
+
+    fn li<R: Rng>(&self, ray: Ray, scene: &dyn Scene, rng: &mut R, aovs: &mut Aovs) -> Vec3 {
+        let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+            Some(hit) => hit,
+            None => return Vec3::new(0.0, 0.0, 0.0),
+        };
+        aovs.albedo = hit.material.albedo();
+        aovs.normal = hit.normal.clone();
+        aovs.depth = hit.pos;
+
+        // Look around the side the ray arrived from.
+        let n = if &hit.normal % &ray.direction > 0.0 {
+            hit.normal.negate()
+        } else {
+            hit.normal.clone()
+        };
+        let frame = Frame::new(&n);
+
+        let mut visible = 0.0;
+        for _ in 0..self.ao_samples {
+            let u = (rng.gen(), rng.gen());
+            let (local, pdf) = if self.cos_sample {
+                let local = cosine_hemisphere(u);
+                let pdf = cosine_hemisphere_pdf(local.z);
+                (local, pdf)
+            } else {
+                (uniform_hemisphere(u), uniform_hemisphere_pdf())
+            };
+            if pdf <= 0.0 {
+                continue;
+            }
+            let wi = frame.to_world(&local);
+            if unoccluded(scene, &hit.point, &wi, self.max_distance) {
+                visible += local.z / (PI * pdf);
+            }
+        }
+
+        let ao = visible / self.ao_samples as Float;
+        Vec3::new(ao, ao, ao)
+    }
+}
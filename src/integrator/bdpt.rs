@@ -0,0 +1,892 @@
+use crate::camera::Camera;
+use crate::film::{Aovs, Film, SplatFilm};
+use crate::image::Image;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::Distribution1D;
+use crate::sampler::Sampler;
+use crate::scene::material::{Material, Transport};
+use crate::scene::Scene;
+use crate::vec::*;
+
+use rand::Rng;
+
+use std::error::Error;
+use std::f32::consts::PI;
+use std::sync::Mutex;
+
+/// A contribution to one of the splat films, at a raster position.
+type Splat = (usize, (Float, Float), Vec3);
+
+#[derive(Clone)]
+pub(super) enum VertexKind<'a> {
+    Camera,
+    /// A point on a light. `None` stands for a camera ray that left the
+    /// scene, i.e. all of the infinite lights at once.
+    Light(Option<usize>),
+    Surface {
+        material: &'a Material,
+        wo: Vec3,
+        light: Option<usize>,
+    },
+}
+
+/// One vertex of a camera or light subpath, following pbrt's layout.
+#[derive(Clone)]
+pub(super) struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    point: Vec3,
+    /// Zero for vertices that aren't on a surface.
+    normal: Vec3,
+    /// Throughput from the start of the subpath up to this vertex.
+    beta: Vec3,
+    /// Scattering here was a delta distribution.
+    delta: bool,
+    /// Area density of reaching this vertex from the previous one, and
+    /// from the next one.
+    pdf_fwd: Float,
+    pdf_rev: Float,
+}
+
+impl<'a> Vertex<'a> {
+    fn new(
+        kind: VertexKind<'a>,
+        point: Vec3,
+        normal: Vec3,
+        beta: Vec3,
+        pdf_fwd: Float,
+    ) -> Vertex<'a> {
+        Vertex {
+            kind,
+            point,
+            normal,
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        &self.normal % &self.normal > 0.0
+    }
+
+    /// BSDF for scattering from the previous vertex towards `next`.
+    fn f(&self, next: &Vertex) -> Vec3 {
+        match &self.kind {
+            VertexKind::Surface { material, wo, .. } => {
+                let wi = (&next.point - &self.point).to_unit();
+                material.f(wo, &wi, &self.normal)
+            }
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    fn is_connectible(&self, scene: &dyn Scene) -> bool {
+        match &self.kind {
+            VertexKind::Camera | VertexKind::Light(None) => true,
+            VertexKind::Light(Some(index)) => {
+                !matches!(scene.lights()[*index], Light::Distant { .. })
+            }
+            VertexKind::Surface { material, .. } => !material.is_specular(),
+        }
+    }
+
+    fn light_index(&self) -> Option<usize> {
+        match &self.kind {
+            VertexKind::Light(index) => *index,
+            VertexKind::Surface { light, .. } => *light,
+            VertexKind::Camera => None,
+        }
+    }
+
+    fn is_light(&self) -> bool {
+        matches!(self.kind, VertexKind::Light(_)) || self.light_index().is_some()
+    }
+
+    fn is_delta_light(&self, scene: &dyn Scene) -> bool {
+        match self.kind {
+            VertexKind::Light(Some(index)) => scene.lights()[index].is_delta(),
+            _ => false,
+        }
+    }
+
+    fn is_infinite_light(&self, scene: &dyn Scene) -> bool {
+        match self.kind {
+            VertexKind::Light(None) => true,
+            VertexKind::Light(Some(index)) => scene.lights()[index].is_infinite(),
+            _ => false,
+        }
+    }
+
+    /// Converts a solid-angle density at this vertex into an area density
+    /// at `next`.
+    fn convert_density(&self, pdf: Float, next: &Vertex, scene: &dyn Scene) -> Float {
+        if next.is_infinite_light(scene) {
+            return pdf;
+        }
+        let w = &next.point - &self.point;
+        let dist_sq = &w % &w;
+        if dist_sq == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf / dist_sq;
+        if next.on_surface() {
+            pdf *= (&next.normal % &(w / dist_sq.sqrt())).abs();
+        }
+        pdf
+    }
+}
+
+/// Bidirectional path tracer; pbrt's `Integrator "bdpt"`. Every prefix of
+/// a camera subpath is connected to every prefix of a light subpath, and
+/// the resulting strategies are combined with the balance heuristic.
+pub struct BDPTIntegrator {
+    camera: Camera,
+    sampler: Box<dyn Sampler>,
+    max_depth: u32,
+    strategy: LightStrategy,
+    /// Also write one image per (s, t) strategy, weighted by MIS or not.
+    visualize_strategies: bool,
+    visualize_weights: bool,
+    light_distribution: Distribution1D,
+    world_radius: Float,
+}
+
+/// Index of the (s, t) strategy image, grouped by path depth.
+fn buffer_index(s: usize, t: usize) -> usize {
+    let above = s + t - 2;
+    s + above * (5 + above) / 2
+}
+
+impl BDPTIntegrator {
+    pub fn new(
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+        max_depth: u32,
+        strategy: LightStrategy,
+        visualize_strategies: bool,
+        visualize_weights: bool,
+    ) -> BDPTIntegrator {
+        BDPTIntegrator {
+            camera,
+            sampler,
+            max_depth,
+            strategy,
+            visualize_strategies,
+            visualize_weights,
+            light_distribution: Distribution1D::new(&[]),
+            world_radius: 0.0,
+        }
+    }
+
+    pub fn from_params(
+        params: &ParamSet,
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+    ) -> Result<BDPTIntegrator, Box<dyn Error>> {
+        let name = params.string("lightsamplestrategy", "power");
+        let strategy = LightStrategy::from_name(&name)
+            .ok_or_else(|| format!("unknown light sample strategy \"{}\"", name))?;
+
+        Ok(BDPTIntegrator::new(
+            camera,
+            sampler,
+            params.int("maxdepth", 5).max(0) as u32,
+            strategy,
+            params.bool("visualizestrategies", false),
+            params.bool("visualizeweights", false),
+        ))
+    }
+
+    pub(super) fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    /// Sets up the light distribution and scene bounds for `scene`; must be
+    /// called before generating any subpaths.
+    pub(super) fn preprocess(&mut self, scene: &dyn Scene) {
+        self.light_distribution = self.strategy.distribution(scene.lights());
+        self.world_radius = scene.bounds().bounding_sphere().1;
+    }
+
+    fn visualize(&self) -> bool {
+        self.visualize_strategies || self.visualize_weights
+    }
+
+    /// Extends `path` by tracing `ray` through the scene, recording forward
+    /// and reverse densities as it goes.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a, R: Rng>(
+        &self,
+        scene: &'a dyn Scene,
+        mut ray: Ray,
+        rng: &mut R,
+        mut beta: Vec3,
+        pdf: Float,
+        max_depth: u32,
+        mode: Transport,
+        path: &mut Vec<Vertex<'a>>,
+    ) {
+        if max_depth == 0 {
+            return;
+        }
+        let mut pdf_fwd = pdf;
+        let mut bounces = 0;
+
+        loop {
+            let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+                Some(hit) => hit,
+                None => {
+                    // Camera rays that escape see the infinite lights.
+                    if mode == Transport::Radiance {
+                        let point = ray.point_at(1.0);
+                        let normal = ray.direction.negate();
+                        path.push(Vertex::new(
+                            VertexKind::Light(None),
+                            point,
+                            normal,
+                            beta,
+                            pdf_fwd,
+                        ));
+                    }
+                    break;
+                }
+            };
+
+            let wo = ray.direction.negate();
+            let mut vertex = Vertex::new(
+                VertexKind::Surface {
+                    material: hit.material,
+                    wo: wo.clone(),
+                    light: hit.light,
+                },
+                hit.point.clone(),
+                hit.normal.clone(),
+                beta.clone(),
+                0.0,
+            );
+            let prev = path.last().unwrap();
+            vertex.pdf_fwd = prev.convert_density(pdf_fwd, &vertex, scene);
+            path.push(vertex);
+
+            bounces += 1;
+            if bounces >= max_depth {
+                break;
+            }
+
+            let bs =
+                match hit
+                    .material
+                    .sample(&wo, &hit.normal, (rng.gen(), rng.gen()), rng.gen(), mode)
+                {
+                    Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+                    _ => break,
+                };
+            pdf_fwd = bs.pdf;
+            beta *= &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+            let mut pdf_rev = hit.material.pdf(&bs.wi, &wo, &hit.normal);
+            if bs.specular {
+                path.last_mut().unwrap().delta = true;
+                pdf_rev = 0.0;
+                pdf_fwd = 0.0;
+            }
+            ray = Ray::new(hit.point, bs.wi, 0.0);
+
+            let n = path.len();
+            let rev = path[n - 1].convert_density(pdf_rev, &path[n - 2], scene);
+            path[n - 2].pdf_rev = rev;
+
+            if beta.is_black() {
+                break;
+            }
+        }
+    }
+
+    /// Up to `vertices` vertices of a path starting with a camera ray
+    /// through raster position `(x, y)`.
+    pub(super) fn camera_subpath<'a, R: Rng>(
+        &self,
+        scene: &'a dyn Scene,
+        x: Float,
+        y: Float,
+        rng: &mut R,
+        vertices: usize,
+    ) -> Vec<Vertex<'a>> {
+        if vertices == 0 {
+            return Vec::new();
+        }
+        let ray = self.camera.generate_ray(x, y, (rng.gen(), rng.gen()));
+        let (_, pdf_dir) = self.camera.pdf_we(&ray);
+        let one = Vec3::new(1.0, 1.0, 1.0);
+
+        let mut path = vec![Vertex::new(
+            VertexKind::Camera,
+            ray.origin.clone(),
+            Vec3::new(0.0, 0.0, 0.0),
+            one.clone(),
+            0.0,
+        )];
+        self.random_walk(
+            scene,
+            ray,
+            rng,
+            one,
+            pdf_dir,
+            vertices as u32 - 1,
+            Transport::Radiance,
+            &mut path,
+        );
+        path
+    }
+
+    /// Up to `vertices` vertices of a path leaving a light.
+    pub(super) fn light_subpath<'a, R: Rng>(
+        &self,
+        scene: &'a dyn Scene,
+        rng: &mut R,
+        vertices: usize,
+    ) -> Vec<Vertex<'a>> {
+        let mut path = Vec::new();
+        if vertices == 0 || self.light_distribution.is_empty() {
+            return path;
+        }
+        let (index, light_pdf) = self.light_distribution.sample(rng.gen());
+        if light_pdf == 0.0 {
+            return path;
+        }
+        let light = &scene.lights()[index];
+
+        let es = match light.sample_le((rng.gen(), rng.gen()), (rng.gen(), rng.gen())) {
+            Some(es) if es.pdf_pos > 0.0 && es.pdf_dir > 0.0 && !es.radiance.is_black() => es,
+            _ => return path,
+        };
+        let cos = (&es.normal % &es.ray.direction).abs();
+        let beta = &es.radiance * (cos / (light_pdf * es.pdf_pos * es.pdf_dir));
+        path.push(Vertex::new(
+            VertexKind::Light(Some(index)),
+            es.ray.origin.clone(),
+            es.normal.clone(),
+            es.radiance.clone(),
+            es.pdf_pos * light_pdf,
+        ));
+        let direction = es.ray.direction.clone();
+        self.random_walk(
+            scene,
+            es.ray,
+            rng,
+            beta,
+            es.pdf_dir,
+            vertices as u32 - 1,
+            Transport::Importance,
+            &mut path,
+        );
+
+        // The densities above assumed the path started at a point; for
+        // infinite lights it started on a disk perpendicular to `direction`.
+        if light.is_infinite() {
+            if path.len() > 1 {
+                path[1].pdf_fwd = es.pdf_pos;
+                if path[1].on_surface() {
+                    path[1].pdf_fwd *= (&direction % &path[1].normal).abs();
+                }
+            }
+            path[0].pdf_fwd = self.infinite_light_density(scene, &direction);
+        }
+
+        path
+    }
+
+    /// Density of sampling the infinite lights' emission in direction `w`.
+    fn infinite_light_density(&self, scene: &dyn Scene, w: &Vec3) -> Float {
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        scene
+            .lights()
+            .iter()
+            .enumerate()
+            .filter(|(_, light)| light.is_infinite())
+            .map(|(i, light)| light.pdf_li(&origin, &w.negate()) * self.light_distribution.pdf(i))
+            .sum()
+    }
+
+    /// Area density at `next` of sampling it from `v`, having arrived at
+    /// `v` from `prev`.
+    fn pdf(&self, scene: &dyn Scene, v: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> Float {
+        let wn = (&next.point - &v.point).to_unit();
+        let pdf = match &v.kind {
+            VertexKind::Light(_) => return self.pdf_light(scene, v, next),
+            VertexKind::Camera => self.camera.pdf_we(&Ray::new(v.point.clone(), wn, 0.0)).1,
+            VertexKind::Surface { material, .. } => match prev {
+                Some(prev) => {
+                    let wp = (&prev.point - &v.point).to_unit();
+                    material.pdf(&wp, &wn, &v.normal)
+                }
+                None => return 0.0,
+            },
+        };
+        v.convert_density(pdf, next, scene)
+    }
+
+    /// Area density at `next` of light leaving the light vertex `v`.
+    fn pdf_light(&self, scene: &dyn Scene, v: &Vertex, next: &Vertex) -> Float {
+        let w = &next.point - &v.point;
+        let dist_sq = &w % &w;
+        if dist_sq == 0.0 {
+            return 0.0;
+        }
+        let w = w / dist_sq.sqrt();
+
+        let mut pdf = if v.is_infinite_light(scene) {
+            1.0 / (PI * self.world_radius * self.world_radius)
+        } else {
+            match v.light_index() {
+                Some(index) => scene.lights()[index].pdf_le(&w, &v.normal).1 / dist_sq,
+                None => return 0.0,
+            }
+        };
+        if next.on_surface() {
+            pdf *= (&next.normal % &w).abs();
+        }
+        pdf
+    }
+
+    /// Density of the light subpath starting at `v`, towards `next`.
+    fn pdf_light_origin(&self, scene: &dyn Scene, v: &Vertex, next: &Vertex) -> Float {
+        let w = (&next.point - &v.point).to_unit();
+        if v.is_infinite_light(scene) {
+            return self.infinite_light_density(scene, &w);
+        }
+        match v.light_index() {
+            Some(index) => {
+                let (pdf_pos, _) = scene.lights()[index].pdf_le(&w, &v.normal);
+                pdf_pos * self.light_distribution.pdf(index)
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Radiance emitted from the light vertex `v` towards `prev`.
+    fn le(&self, scene: &dyn Scene, v: &Vertex, prev: &Vertex) -> Vec3 {
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        if !v.is_light() {
+            return black;
+        }
+        let w = (&prev.point - &v.point).to_unit();
+        if v.is_infinite_light(scene) {
+            let ray = Ray::new(v.point.clone(), w.negate(), 0.0);
+            scene
+                .lights()
+                .iter()
+                .filter(|light| light.is_infinite())
+                .fold(black, |l, light| l + light.le(&ray))
+        } else {
+            match v.light_index() {
+                Some(index) => scene.lights()[index].l(&v.normal, &w),
+                None => black,
+            }
+        }
+    }
+
+    /// Geometry term between two surface vertices, including visibility.
+    fn g(&self, scene: &dyn Scene, v0: &Vertex, v1: &Vertex) -> Float {
+        let d = &v0.point - &v1.point;
+        let dist_sq = &d % &d;
+        if dist_sq == 0.0 {
+            return 0.0;
+        }
+        let distance = dist_sq.sqrt();
+        let d = d / distance;
+        let mut g = 1.0 / dist_sq;
+        if v0.on_surface() {
+            g *= (&v0.normal % &d).abs();
+        }
+        if v1.on_surface() {
+            g *= (&v1.normal % &d).abs();
+        }
+        if g == 0.0 || !unoccluded(scene, &v1.point, &d, distance) {
+            return 0.0;
+        }
+        g
+    }
+
+    /// Connects the first `s` light vertices to the first `t` camera
+    /// vertices. Returns the weighted contribution, its MIS weight, and for
+    /// `t = 1` the raster position it lands on.
+    pub(super) fn connect<'a, R: Rng>(
+        &self,
+        scene: &'a dyn Scene,
+        light_path: &[Vertex<'a>],
+        camera_path: &[Vertex<'a>],
+        s: usize,
+        t: usize,
+        rng: &mut R,
+    ) -> (Vec3, Float, Option<(Float, Float)>) {
+        let black = || Vec3::new(0.0, 0.0, 0.0);
+        if t > 1 && s != 0 && matches!(camera_path[t - 1].kind, VertexKind::Light(_)) {
+            return (black(), 0.0, None);
+        }
+
+        let mut sampled = None;
+        let mut raster = None;
+
+        let l = if s == 0 {
+            // The camera subpath found a light on its own.
+            let pt = &camera_path[t - 1];
+            &pt.beta * self.le(scene, pt, &camera_path[t - 2])
+        } else if t == 1 {
+            // Light tracing: connect the light subpath to the lens.
+            let qs = &light_path[s - 1];
+            if !qs.is_connectible(scene) {
+                black()
+            } else {
+                match self.camera.sample_wi(&qs.point, (rng.gen(), rng.gen())) {
+                    Some(cs) if cs.pdf > 0.0 && cs.importance > 0.0 => {
+                        let w = cs.importance / cs.pdf;
+                        let v = Vertex::new(
+                            VertexKind::Camera,
+                            cs.point,
+                            cs.normal,
+                            Vec3::new(w, w, w),
+                            0.0,
+                        );
+                        let mut l = &qs.beta * qs.f(&v) * &v.beta;
+                        if qs.on_surface() {
+                            l = l * (&cs.wi % &qs.normal).abs();
+                        }
+                        if !l.is_black() && !unoccluded(scene, &qs.point, &cs.wi, cs.distance) {
+                            l = black();
+                        }
+                        raster = Some(cs.raster);
+                        sampled = Some(v);
+                        l
+                    }
+                    _ => black(),
+                }
+            }
+        } else if s == 1 {
+            // Next-event estimation: sample a fresh point on a light.
+            let pt = &camera_path[t - 1];
+            if !pt.is_connectible(scene) || self.light_distribution.is_empty() {
+                black()
+            } else {
+                let (index, light_pdf) = self.light_distribution.sample(rng.gen());
+                match scene.lights()[index].sample_li(&pt.point, (rng.gen(), rng.gen())) {
+                    Some(ls) if light_pdf > 0.0 && ls.pdf > 0.0 && !ls.radiance.is_black() => {
+                        let mut v = Vertex::new(
+                            VertexKind::Light(Some(index)),
+                            &pt.point + &ls.wi * ls.distance,
+                            ls.normal.clone(),
+                            &ls.radiance / (ls.pdf * light_pdf),
+                            0.0,
+                        );
+                        v.pdf_fwd = self.pdf_light_origin(scene, &v, pt);
+                        let mut l = &pt.beta * pt.f(&v) * &v.beta;
+                        if pt.on_surface() {
+                            l = l * (&ls.wi % &pt.normal).abs();
+                        }
+                        if !l.is_black() && !unoccluded(scene, &pt.point, &ls.wi, ls.distance) {
+                            l = black();
+                        }
+                        sampled = Some(v);
+                        l
+                    }
+                    _ => black(),
+                }
+            }
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if qs.is_connectible(scene) && pt.is_connectible(scene) {
+                let l = &qs.beta * qs.f(pt) * pt.f(qs) * &pt.beta;
+                if l.is_black() {
+                    l
+                } else {
+                    l * self.g(scene, qs, pt)
+                }
+            } else {
+                black()
+            }
+        };
+
+        if l.is_black() {
+            return (black(), 0.0, raster);
+        }
+        let weight = self.mis_weight(scene, light_path, camera_path, sampled, s, t);
+        (l * weight, weight, raster)
+    }
+
+    /// Balance heuristic weight of strategy (s, t) against every other way
+    /// of sampling the same path.
+    fn mis_weight<'a>(
+        &self,
+        scene: &'a dyn Scene,
+        light_path: &[Vertex<'a>],
+        camera_path: &[Vertex<'a>],
+        sampled: Option<Vertex<'a>>,
+        s: usize,
+        t: usize,
+    ) -> Float {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        let mut lv = light_path[..s].to_vec();
+        let mut cv = camera_path[..t].to_vec();
+        if let Some(v) = sampled {
+            if s == 1 {
+                lv[0] = v;
+            } else if t == 1 {
+                cv[0] = v;
+            }
+        }
+
+        // Densities of sampling the connection's endpoints the other way.
+        let pt_rev = if s > 0 {
+            self.pdf(
+                scene,
+                &lv[s - 1],
+                s.checked_sub(2).map(|i| &lv[i]),
+                &cv[t - 1],
+            )
+        } else {
+            self.pdf_light_origin(scene, &cv[t - 1], &cv[t - 2])
+        };
+        let pt_minus_rev = if t > 1 {
+            Some(if s > 0 {
+                self.pdf(scene, &cv[t - 1], Some(&lv[s - 1]), &cv[t - 2])
+            } else {
+                self.pdf_light(scene, &cv[t - 1], &cv[t - 2])
+            })
+        } else {
+            None
+        };
+        let qs_rev = if s > 0 {
+            Some(self.pdf(
+                scene,
+                &cv[t - 1],
+                t.checked_sub(2).map(|i| &cv[i]),
+                &lv[s - 1],
+            ))
+        } else {
+            None
+        };
+        let qs_minus_rev = if s > 1 {
+            Some(self.pdf(scene, &lv[s - 1], Some(&cv[t - 1]), &lv[s - 2]))
+        } else {
+            None
+        };
+
+        cv[t - 1].pdf_rev = pt_rev;
+        cv[t - 1].delta = false;
+        if let Some(pdf) = pt_minus_rev {
+            cv[t - 2].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_rev {
+            lv[s - 1].pdf_rev = pdf;
+            lv[s - 1].delta = false;
+        }
+        if let Some(pdf) = qs_minus_rev {
+            lv[s - 2].pdf_rev = pdf;
+        }
+
+        // Delta distributions have density 0; treat them as 1 so they
+        // cancel out of the ratios.
+        let remap = |f: Float| if f != 0.0 { f } else { 1.0 };
+        let mut sum = 0.0;
+
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(cv[i].pdf_rev) / remap(cv[i].pdf_fwd);
+            if !cv[i].delta && !cv[i - 1].delta {
+                sum += ri;
+            }
+        }
+
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(lv[i].pdf_rev) / remap(lv[i].pdf_fwd);
+            let delta_light = if i > 0 {
+                lv[i - 1].delta
+            } else {
+                lv[0].is_delta_light(scene)
+            };
+            if !lv[i].delta && !delta_light {
+                sum += ri;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+    /// Radiance for one camera sample through `(x, y)`. Light tracing
+    /// contributions are left in `splats` for film 0, and the strategy
+    /// images', if any, for the rest.
+    fn sample<R: Rng>(
+        &self,
+        scene: &dyn Scene,
+        x: usize,
+        y: usize,
+        rng: &mut R,
+        aovs: &mut Aovs,
+        splats: &mut Vec<Splat>,
+    ) -> Vec3 {
+        let camera_path = self.camera_subpath(
+            scene,
+            x as Float + rng.gen::<Float>(),
+            y as Float + rng.gen::<Float>(),
+            rng,
+            self.max_depth as usize + 2,
+        );
+        let light_path = self.light_subpath(scene, rng, self.max_depth as usize + 1);
+
+        if let Some(Vertex {
+            kind: VertexKind::Surface { material, .. },
+            normal,
+            point,
+            ..
+        }) = camera_path.get(1)
+        {
+            aovs.albedo = material.albedo();
+            aovs.normal = normal.clone();
+            aovs.depth = (point - &camera_path[0].point).norm();
+        }
+
+        let mut l = Vec3::new(0.0, 0.0, 0.0);
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if (s == 1 && t == 1) || s + t < 2 || s + t - 2 > self.max_depth as usize {
+                    continue;
+                }
+
+                let (contribution, weight, raster) =
+                    self.connect(scene, &light_path, &camera_path, s, t, rng);
+                let position = raster.unwrap_or((x as Float, y as Float));
+
+                if self.visualize() && (t != 1 || raster.is_some()) {
+                    let value = if self.visualize_weights {
+                        contribution.clone()
+                    } else if weight == 0.0 {
+                        Vec3::new(0.0, 0.0, 0.0)
+                    } else {
+                        &contribution / weight
+                    };
+                    splats.push((1 + buffer_index(s, t), position, value));
+                }
+
+                if t != 1 {
+                    l += contribution;
+                } else if raster.is_some() && !contribution.is_black() {
+                    splats.push((0, position, contribution));
+                }
+            }
+        }
+        l
+    }
+
+    fn write_strategy_images(&self, buffers: &[SplatFilm], scale: Float) {
+        for depth in 0..=self.max_depth as usize {
+            for s in 0..=depth + 2 {
+                let t = depth + 2 - s;
+                if t == 0 || (s == 1 && t == 1) {
+                    continue;
+                }
+                let image = Image::new(self.camera.width, self.camera.height)
+                    .with_pixels(buffers[1 + buffer_index(s, t)].to_pixels(scale));
+
+                let name = format!("bdpt_d{:02}_s{:02}_t{:02}.exr", depth, s, t);
+                if let Err(e) = image.write_to(&name) {
+                    eprintln!("warning: couldn't write {}: {}", name, e);
+                }
+            }
+        }
+    }
+}
+
+impl Integrator for BDPTIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        self.preprocess(scene);
+        self.sampler.seed(progressive.seed);
+
+        let (width, height) = (self.camera.width, self.camera.height);
+        let buffers = if self.visualize() {
+            1 + buffer_index(0, self.max_depth as usize + 3)
+        } else {
+            1
+        };
+        let splats: Vec<SplatFilm> = (0..buffers)
+            .map(|_| SplatFilm::new(width, height))
+            .collect();
+
+        // Every camera sample traced one light subpath, so the splats are
+        // averaged over the number of samples a pixel got.
+        let splat_scale =
+            |film: &Film| (width * height) as Float / film.total_samples().max(1) as Float;
+
+        let this = &*self;
+        let mut film = Film::new(width, height);
+        let image = progressive.run(
+            &mut film,
+            &splats,
+            self.sampler.as_ref(),
+            |film, pass, samples| {
+                // Splats go on once the pass is done, in pixel order, so
+                // the sums don't depend on how the threads interleaved.
+                let pending = Mutex::new(Vec::new());
+                render_pass(
+                    film,
+                    pass,
+                    this.sampler.as_ref(),
+                    samples,
+                    |x, y, rng, aovs| {
+                        let mut local = Vec::new();
+                        let l = this.sample(scene, x, y, rng, aovs, &mut local);
+                        if !local.is_empty() {
+                            pending.lock().unwrap().push((y * width + x, local));
+                        }
+                        l
+                    },
+                );
+
+                let mut pending = pending.into_inner().unwrap();
+                pending.sort_by_key(|&(pixel, _)| pixel);
+                for (film, position, value) in pending.into_iter().flat_map(|(_, local)| local) {
+                    splats[film].add(position, &value);
+                }
+            },
+            |film| {
+                let mut image = film.to_image();
+                let light = splats[0].to_pixels(splat_scale(film));
+                for (row, splats) in image.pixels.iter_mut().zip(light) {
+                    for (pixel, splat) in row.iter_mut().zip(splats) {
+                        *pixel += splat;
+                    }
+                }
+                image
+            },
+            observer,
+        )?;
+
+        if self.visualize() {
+            self.write_strategy_images(&splats, splat_scale(&film));
+        }
+
+        Ok(image)
+    }
+
+    fn passes(&self) -> Option<u32> {
+        Some(self.sampler.samples_per_pixel())
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "bdpt"
+    }
+}
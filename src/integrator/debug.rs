@@ -0,0 +1,283 @@
+use crate::camera::Camera;
+use crate::film::Film;
+use crate::image::Image;
+use crate::integrator::*;
+use crate::sampler::{IndependentSampler, Sampler};
+use crate::scene::material::Transport;
+use crate::scene::Scene;
+use crate::vec::*;
+
+use rand::Rng;
+
+use std::error::Error;
+
+/// What a `DebugIntegrator` shows.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DebugMode {
+    Normals,
+    GeometricNormals,
+    UV,
+    /// Distance to the first hit, scaled so the furthest is white; rays
+    /// that miss are white too.
+    Depth,
+    Barycentrics,
+    PrimitiveIDs,
+    MaterialIDs,
+    /// How many boxes and primitives the acceleration structure tested.
+    Heatmap,
+    /// Every surface lit by a uniform white environment and nothing else;
+    /// materials that neither absorb nor lose energy disappear.
+    WhiteFurnace,
+}
+
+impl DebugMode {
+    pub const NAMES: &'static [&'static str] = &[
+        "normals",
+        "geometric-normals",
+        "uv",
+        "depth",
+        "barycentrics",
+        "primitive-ids",
+        "material-ids",
+        "heatmap",
+        "white-furnace",
+    ];
+
+    pub fn name(self) -> &'static str {
+        // In the same order as the variants.
+        DebugMode::NAMES[self as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<DebugMode> {
+        match name {
+            "normals" => Some(DebugMode::Normals),
+            "geometric-normals" => Some(DebugMode::GeometricNormals),
+            "uv" => Some(DebugMode::UV),
+            "depth" => Some(DebugMode::Depth),
+            "barycentrics" => Some(DebugMode::Barycentrics),
+            "primitive-ids" => Some(DebugMode::PrimitiveIDs),
+            "material-ids" => Some(DebugMode::MaterialIDs),
+            "heatmap" => Some(DebugMode::Heatmap),
+            "white-furnace" => Some(DebugMode::WhiteFurnace),
+            _ => None,
+        }
+    }
+}
+
+/// Cheap visualizations of the scene for tracking down problems with it,
+/// or with the renderer. All modes but `WhiteFurnace` trace one ray
+/// through the centre of each pixel, so IDs aren't blurred together, and
+/// so make just one pass whatever the scene's sampler.
+pub struct DebugIntegrator {
+    camera: Camera,
+    sampler: Box<dyn Sampler>,
+    mode: DebugMode,
+}
+
+/// Bounces before the white furnace gives up on a path.
+const FURNACE_DEPTH: u32 = 64;
+
+impl DebugIntegrator {
+    pub fn new(camera: Camera, sampler: Box<dyn Sampler>, mode: DebugMode) -> DebugIntegrator {
+        let sampler = match mode {
+            DebugMode::WhiteFurnace => sampler,
+            _ => Box::new(IndependentSampler::new(1)),
+        };
+        DebugIntegrator {
+            camera,
+            sampler,
+            mode,
+        }
+    }
+
+    /// The value for one sample of pixel `(x, y)`. Depth and heatmap
+    /// values are raw, to be normalized once the whole image is known.
+    fn pixel<R: Rng>(&self, scene: &dyn Scene, x: usize, y: usize, rng: &mut R) -> Vec3 {
+        let black = Vec3::new(0.0, 0.0, 0.0);
+
+        if self.mode == DebugMode::WhiteFurnace {
+            let ray = self.camera.generate_ray(
+                x as Float + rng.gen::<Float>(),
+                y as Float + rng.gen::<Float>(),
+                (rng.gen(), rng.gen()),
+            );
+            return white_furnace(ray, scene, rng);
+        }
+
+        let ray = self
+            .camera
+            .generate_ray(x as Float + 0.5, y as Float + 0.5, (0.5, 0.5));
+        if self.mode == DebugMode::Heatmap {
+            let steps = scene.traversal_steps(&ray, EPSILON, Float::INFINITY) as Float;
+            return Vec3::new(steps, steps, steps);
+        }
+
+        let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+            Some(hit) => hit,
+            // Negative, since the film drops infinite samples.
+            None if self.mode == DebugMode::Depth => return Vec3::new(-1.0, -1.0, -1.0),
+            None => return black,
+        };
+        let direction = |n: &Vec3| (n + Vec3::new(1.0, 1.0, 1.0)) * 0.5;
+        match self.mode {
+            DebugMode::Normals => direction(&hit.normal),
+            DebugMode::GeometricNormals => direction(&hit.geometric_normal),
+            DebugMode::UV => Vec3::new(hit.uv.0, hit.uv.1, 0.0),
+            DebugMode::Depth => Vec3::new(hit.pos, hit.pos, hit.pos),
+            DebugMode::Barycentrics => match hit.barycentric {
+                Some((b1, b2)) => Vec3::new(1.0 - b1 - b2, b1, b2),
+                None => black,
+            },
+            DebugMode::PrimitiveIDs => id_color(hit.primitive),
+            DebugMode::MaterialIDs => id_color(hit.material_id),
+            DebugMode::Heatmap | DebugMode::WhiteFurnace => unreachable!(),
+        }
+    }
+
+    /// The film's image, with depth and heatmap values normalized so the
+    /// largest is 1.
+    fn image(&self, film: &Film) -> Image {
+        let mut image = film.to_image();
+        if self.mode == DebugMode::Depth || self.mode == DebugMode::Heatmap {
+            let max = image
+                .pixels
+                .iter()
+                .flatten()
+                .map(|v| v.x)
+                .fold(0.0, Float::max);
+            for v in image.pixels.iter_mut().flatten() {
+                let t = if v.x < 0.0 {
+                    1.0
+                } else if max > 0.0 {
+                    v.x / max
+                } else {
+                    0.0
+                };
+                *v = if self.mode == DebugMode::Heatmap {
+                    heat(t)
+                } else {
+                    Vec3::new(t, t, t)
+                };
+            }
+        }
+        image
+    }
+}
+
+impl Integrator for DebugIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        self.sampler.seed(progressive.seed);
+        let (width, height) = (self.camera.width, self.camera.height);
+        let this = &*self;
+        let mut film = Film::new(width, height);
+        progressive.run(
+            &mut film,
+            &[],
+            self.sampler.as_ref(),
+            |film, pass, samples| {
+                render_pass(
+                    film,
+                    pass,
+                    this.sampler.as_ref(),
+                    samples,
+                    |x, y, rng, _| this.pixel(scene, x, y, rng),
+                )
+            },
+            |film| this.image(film),
+            observer,
+        )
+    }
+
+    fn passes(&self) -> Option<u32> {
+        Some(self.sampler.samples_per_pixel())
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "debug"
+    }
+}
+
+/// Throughput of a path that bounces around until it escapes, at which
+/// point it sees radiance 1.
+fn white_furnace<R: Rng>(mut ray: Ray, scene: &dyn Scene, rng: &mut R) -> Vec3 {
+    let mut beta = Vec3::new(1.0, 1.0, 1.0);
+    for bounces in 0..FURNACE_DEPTH {
+        let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+            Some(hit) => hit,
+            None => return beta,
+        };
+        let wo = ray.direction.negate();
+        let bs = match hit.material.sample(
+            &wo,
+            &hit.normal,
+            (rng.gen(), rng.gen()),
+            rng.gen(),
+            Transport::Radiance,
+        ) {
+            Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+            _ => break,
+        };
+        beta *= &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+        ray = Ray::new(hit.point, bs.wi, 0.0);
+
+        if bounces > 3 {
+            let q = (1.0 - beta.max_component()).max(0.05);
+            if rng.gen::<Float>() < q {
+                break;
+            }
+            beta = beta / (1.0 - q);
+        }
+    }
+    Vec3::new(0.0, 0.0, 0.0)
+}
+
+/// Black through blue, green and yellow to red as `t` goes from 0 to 1.
+pub fn heat(t: Float) -> Vec3 {
+    const STOPS: [(Float, Float, Float); 5] = [
+        (0.0, 0.0, 0.0),
+        (0.0, 0.0, 1.0),
+        (0.0, 1.0, 0.0),
+        (1.0, 1.0, 0.0),
+        (1.0, 0.0, 0.0),
+    ];
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as Float;
+    let i = (t as usize).min(STOPS.len() - 2);
+    let f = t - i as Float;
+    let (a, b) = (STOPS[i], STOPS[i + 1]);
+    Vec3::new(
+        a.0 + (b.0 - a.0) * f,
+        a.1 + (b.1 - a.1) * f,
+        a.2 + (b.2 - a.2) * f,
+    )
+}
+
+/// How many samples each pixel of `image` got, relative to the most any
+/// pixel got, or `None` if the integrator didn't keep count.
+pub fn sample_heatmap(image: &Image) -> Option<Image> {
+    let samples = image.samples.as_ref()?;
+    let max = samples.iter().flatten().cloned().max().unwrap_or(0).max(1) as Float;
+    let pixels = samples
+        .iter()
+        .map(|row| row.iter().map(|&n| heat(n as Float / max)).collect())
+        .collect();
+    Some(Image::new(image.width(), image.height()).with_pixels(pixels))
+}
+
+/// A colour that's unlikely to be confused with that of nearby IDs.
+fn id_color(id: usize) -> Vec3 {
+    let mut h = (id as u32).wrapping_add(1).wrapping_mul(0x9e37_79b9);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    let channel = |shift: u32| 0.2 + 0.8 * ((h >> shift) & 0xff) as Float / 255.0;
+    Vec3::new(channel(0), channel(8), channel(16))
+}
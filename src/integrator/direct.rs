@@ -0,0 +1,198 @@
+use crate::camera::Camera;
+use crate::film::Aovs;
+use crate::image::Image;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::Distribution1D;
+use crate::sampler::Sampler;
+use crate::scene::material::Transport;
+use crate::scene::Scene;
+use crate::vec::*;
+
+use rand::Rng;
+
+use std::error::Error;
+
+/// pbrt's "strategy" for the direct lighting integrator.
+#[derive(Copy, Clone, PartialEq)]
+pub enum DirectStrategy {
+    /// Every light, each with its own number of samples.
+    All,
+    /// One light per shading point, picked uniformly.
+    One,
+}
+
+impl DirectStrategy {
+    pub fn from_name(name: &str) -> Option<DirectStrategy> {
+        match name {
+            "all" => Some(DirectStrategy::All),
+            "one" => Some(DirectStrategy::One),
+            _ => None,
+        }
+    }
+}
+
+/// Direct illumination only, plus perfect specular reflection and
+/// refraction; pbrt's `Integrator "directlighting"`. Every shading point
+/// samples both the lights and the BSDF, weighted with the power heuristic.
+pub struct DirectLightingIntegrator {
+    camera: Camera,
+    sampler: Box<dyn Sampler>,
+    max_depth: u32,
+    strategy: DirectStrategy,
+    /// Samples per light for `DirectStrategy::All`, indexed like
+    /// `Scene::lights`.
+    light_samples: Vec<u32>,
+    light_distribution: Distribution1D,
+}
+
+impl DirectLightingIntegrator {
+    pub fn new(
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+        max_depth: u32,
+        strategy: DirectStrategy,
+        light_samples: Vec<u32>,
+    ) -> DirectLightingIntegrator {
+        DirectLightingIntegrator {
+            camera,
+            sampler,
+            max_depth,
+            strategy,
+            light_samples,
+            light_distribution: Distribution1D::new(&[]),
+        }
+    }
+
+    pub fn from_params(
+        params: &ParamSet,
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+        light_samples: Vec<u32>,
+    ) -> Result<DirectLightingIntegrator, Box<dyn Error>> {
+        let name = params.string("strategy", "all");
+        let strategy = DirectStrategy::from_name(&name)
+            .ok_or_else(|| format!("unknown direct lighting strategy \"{}\"", name))?;
+
+        Ok(DirectLightingIntegrator::new(
+            camera,
+            sampler,
+            params.int("maxdepth", 5).max(0) as u32,
+            strategy,
+            light_samples,
+        ))
+    }
+
+    fn li_depth<R: Rng>(
+        &self,
+        ray: Ray,
+        scene: &dyn Scene,
+        rng: &mut R,
+        aovs: &mut Aovs,
+        depth: u32,
+    ) -> Vec3 {
+        let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+            Some(hit) => hit,
+            None => {
+                return scene
+                    .lights()
+                    .iter()
+                    .fold(Vec3::new(0.0, 0.0, 0.0), |l, light| l + light.le(&ray))
+            }
+        };
+        let wo = ray.direction.negate();
+
+        if depth == 0 {
+            aovs.albedo = hit.material.albedo();
+            aovs.normal = hit.normal.clone();
+            aovs.depth = hit.pos;
+        }
+
+        let mut l = emitted(&hit, &wo, scene);
+
+        if !hit.material.is_specular() {
+            l += match self.strategy {
+                DirectStrategy::All => self.sample_all_lights(&hit, &wo, scene, rng),
+                DirectStrategy::One => {
+                    sample_one_light(&hit, &wo, scene, &self.light_distribution, rng)
+                }
+            };
+        } else if depth + 1 < self.max_depth {
+            // pbrt follows both the reflected and the refracted ray; picking
+            // one by its Fresnel weight has the same expectation.
+            if let Some(bs) = hit.material.sample(
+                &wo,
+                &hit.normal,
+                (rng.gen(), rng.gen()),
+                rng.gen(),
+                Transport::Radiance,
+            ) {
+                if bs.pdf > 0.0 && !bs.f.is_black() {
+                    let beta = &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+                    let ray = Ray::new(hit.point.clone(), bs.wi, 0.0);
+                    l += beta * self.li_depth(ray, scene, rng, aovs, depth + 1);
+                }
+            }
+        }
+
+        l
+    }
+
+    fn sample_all_lights<R: Rng>(
+        &self,
+        hit: &HitRecord,
+        wo: &Vec3,
+        scene: &dyn Scene,
+        rng: &mut R,
+    ) -> Vec3 {
+        let mut l = Vec3::new(0.0, 0.0, 0.0);
+        for index in 0..scene.lights().len() {
+            let n = self.light_samples.get(index).cloned().unwrap_or(1);
+            let mut ld = Vec3::new(0.0, 0.0, 0.0);
+            for _ in 0..n {
+                ld += estimate_direct(hit, wo, index, scene, rng);
+            }
+            l += ld / n as Float;
+        }
+        l
+    }
+}
+
+impl Integrator for DirectLightingIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        self.sampler.seed(progressive.seed);
+        self.light_distribution = LightStrategy::Uniform.distribution(scene.lights());
+        render_pixels(self, scene, progressive, observer)
+    }
+
+    fn passes(&self) -> Option<u32> {
+        Some(self.sampler.samples_per_pixel())
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "directlighting"
+    }
+}
+
+impl SamplerIntegrator for DirectLightingIntegrator {
+    fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    fn sampler(&self) -> &dyn Sampler {
+        self.sampler.as_ref()
+    }
+
+    fn li<R: Rng>(&self, ray: Ray, scene: &dyn Scene, rng: &mut R, aovs: &mut Aovs) -> Vec3 {
+        self.li_depth(ray, scene, rng, aovs, 0)
+    }
+}
@@ -0,0 +1,217 @@
+use crate::camera::Camera;
+use crate::film::{Film, SplatFilm};
+use crate::image::Image;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::Distribution1D;
+use crate::sampler::Sampler;
+use crate::scene::material::Transport;
+use crate::scene::Scene;
+use crate::vec::*;
+
+use rand::Rng;
+
+use std::error::Error;
+use std::sync::Mutex;
+
+/// A contribution to the film, at a raster position.
+type Splat = ((Float, Float), Vec3);
+
+/// Light tracing: paths start at the lights and every vertex is connected
+/// to the lens, splatting onto whichever pixel it lands in; pbrt-v4's
+/// `Integrator "lightpath"`. Specular surfaces can't be connected to, so
+/// it never sees lights in mirrors or through glass, but it does render
+/// caustics on diffuse surfaces well. Since it relies on nothing but the
+/// camera's importance function, comparing it against the path tracer is
+/// a good check of that code.
+pub struct LightPathIntegrator {
+    camera: Camera,
+    sampler: Box<dyn Sampler>,
+    max_depth: u32,
+    light_distribution: Distribution1D,
+}
+
+impl LightPathIntegrator {
+    pub fn new(camera: Camera, sampler: Box<dyn Sampler>, max_depth: u32) -> LightPathIntegrator {
+        LightPathIntegrator {
+            camera,
+            sampler,
+            max_depth,
+            light_distribution: Distribution1D::new(&[]),
+        }
+    }
+
+    pub fn from_params(
+        params: &ParamSet,
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+    ) -> Result<LightPathIntegrator, Box<dyn Error>> {
+        Ok(LightPathIntegrator::new(
+            camera,
+            sampler,
+            params.int("maxdepth", 5).max(0) as u32,
+        ))
+    }
+
+    /// Adds to `splats` `value`, carried by a path arriving at `point` from a surface
+    /// with normal `n` (zero if none), through the lens. `value` excludes
+    /// the cosine at `point` and the camera's importance.
+    fn connect<R: Rng>(
+        &self,
+        scene: &dyn Scene,
+        point: &Vec3,
+        n: &Vec3,
+        value: impl Fn(&Vec3) -> Vec3,
+        splats: &mut Vec<Splat>,
+        rng: &mut R,
+    ) {
+        let cs = match self.camera.sample_wi(point, (rng.gen(), rng.gen())) {
+            Some(cs) if cs.pdf > 0.0 && cs.importance > 0.0 => cs,
+            _ => return,
+        };
+        let cos = if n % n > 0.0 { (n % &cs.wi).abs() } else { 1.0 };
+        let l = value(&cs.wi) * (cos * cs.importance / cs.pdf);
+        if !l.is_black() && unoccluded(scene, point, &cs.wi, cs.distance) {
+            splats.push((cs.raster, l));
+        }
+    }
+
+    /// Traces one light path, leaving what it contributes in `splats`.
+    fn trace<R: Rng>(&self, scene: &dyn Scene, splats: &mut Vec<Splat>, rng: &mut R) {
+        let (index, light_pdf) = self.light_distribution.sample(rng.gen());
+        if light_pdf == 0.0 {
+            return;
+        }
+        let light = &scene.lights()[index];
+        let es = match light.sample_le((rng.gen(), rng.gen()), (rng.gen(), rng.gen())) {
+            Some(es) if es.pdf_pos > 0.0 && es.pdf_dir > 0.0 && !es.radiance.is_black() => es,
+            _ => return,
+        };
+
+        // The light itself, seen directly. Point lights have no surface,
+        // so no cosine there.
+        let n = match light {
+            Light::Area { .. } => es.normal.clone(),
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        };
+        self.connect(
+            scene,
+            &es.ray.origin,
+            &n,
+            |w| light.emitted_towards(&es.normal, w) / (light_pdf * es.pdf_pos),
+            splats,
+            rng,
+        );
+
+        let cos = (&es.normal % &es.ray.direction).abs();
+        let mut beta = &es.radiance * (cos / (light_pdf * es.pdf_pos * es.pdf_dir));
+        let mut ray = es.ray;
+
+        for _ in 0..self.max_depth {
+            let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+                Some(hit) => hit,
+                None => return,
+            };
+            let wo = ray.direction.negate();
+
+            if !hit.material.is_specular() {
+                self.connect(
+                    scene,
+                    &hit.point,
+                    &hit.normal,
+                    |w| &beta * hit.material.f(&wo, w, &hit.normal),
+                    splats,
+                    rng,
+                );
+            }
+
+            let bs = match hit.material.sample(
+                &wo,
+                &hit.normal,
+                (rng.gen(), rng.gen()),
+                rng.gen(),
+                Transport::Importance,
+            ) {
+                Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+                _ => return,
+            };
+            let beta_new = &beta * &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+            if beta.luminance() <= 0.0 {
+                return;
+            }
+
+            // Russian roulette keeps the paths' power roughly constant.
+            let q = (1.0 - beta_new.luminance() / beta.luminance()).max(0.0);
+            if rng.gen::<Float>() < q {
+                return;
+            }
+            beta = beta_new / (1.0 - q);
+            ray = Ray::new(hit.point, bs.wi, 0.0);
+        }
+    }
+}
+
+impl Integrator for LightPathIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        progressive.reject_error_driven(self.name())?;
+        self.sampler.seed(progressive.seed);
+        self.light_distribution = LightStrategy::Power.distribution(scene.lights());
+
+        let (width, height) = (self.camera.width, self.camera.height);
+        let splats = [SplatFilm::new(width, height)];
+        let this = &*self;
+        let mut film = Film::new(width, height);
+        progressive.run(
+            &mut film,
+            &splats,
+            self.sampler.as_ref(),
+            |film, pass, samples| {
+                // As many light paths as there would be camera samples, each
+                // drawing its numbers from one of them. The film only counts
+                // them; what they see goes on once the pass is done, in
+                // pixel order, as with BDPT's splats.
+                let pending = Mutex::new(Vec::new());
+                render_pass(
+                    film,
+                    pass,
+                    this.sampler.as_ref(),
+                    samples,
+                    |x, y, rng, _| {
+                        if !this.light_distribution.is_empty() {
+                            let mut local = Vec::new();
+                            this.trace(scene, &mut local, rng);
+                            if !local.is_empty() {
+                                pending.lock().unwrap().push((y * width + x, local));
+                            }
+                        }
+                        Vec3::new(0.0, 0.0, 0.0)
+                    },
+                );
+
+                let mut pending = pending.into_inner().unwrap();
+                pending.sort_by_key(|&(pixel, _)| pixel);
+                for (position, value) in pending.into_iter().flat_map(|(_, local)| local) {
+                    splats[0].add(position, &value);
+                }
+            },
+            |film| {
+                let scale = (width * height) as Float / film.total_samples().max(1) as Float;
+                Image::new(width, height).with_pixels(splats[0].to_pixels(scale))
+            },
+            observer,
+        )
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "lightpath"
+    }
+}
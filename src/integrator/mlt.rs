@@ -0,0 +1,449 @@
+use crate::camera::Camera;
+use crate::film::SplatFilm;
+use crate::image::Image;
+use crate::integrator::bdpt::BDPTIntegrator;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::Distribution1D;
+use crate::sampler::IndependentSampler;
+use crate::scene::Scene;
+use crate::tiles::Tile;
+use crate::vec::*;
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore};
+use rayon::prelude::*;
+
+use std::error::Error;
+use std::f32::consts::PI;
+
+/// Sample streams, so that changing the length of one subpath doesn't
+/// shift the random numbers the others see.
+const CAMERA_STREAM: usize = 0;
+const LIGHT_STREAM: usize = 1;
+const CONNECTION_STREAM: usize = 2;
+const STREAMS: usize = 3;
+
+/// `sample_rng` sample numbers for each bootstrap sample's random numbers,
+/// and for each chain's choice of where to start and which mutations to
+/// accept. Bootstrap samples and chains are numbered in place of a pixel.
+const BOOTSTRAP_SAMPLE: u32 = 0;
+const CHAIN_SAMPLE: u32 = 1;
+
+/// A contribution to the film, at a raster position.
+type Splat = ((Float, Float), Vec3);
+
+/// One coordinate of a point in primary sample space, with enough history
+/// to undo a rejected mutation.
+#[derive(Clone, Default)]
+struct PrimarySample {
+    value: Float,
+    last_modified: u64,
+    backup_value: Float,
+    backup_modified: u64,
+}
+
+impl PrimarySample {
+    fn backup(&mut self) {
+        self.backup_value = self.value;
+        self.backup_modified = self.last_modified;
+    }
+
+    fn restore(&mut self) {
+        self.value = self.backup_value;
+        self.last_modified = self.backup_modified;
+    }
+}
+
+/// A point in primary sample space, i.e. the random numbers a path was
+/// built from, that mutates between iterations. Coordinates are created
+/// and mutated lazily, as the path tracing code asks for them through the
+/// `RngCore` interface.
+struct MLTSampler {
+    rng: StdRng,
+    sigma: Float,
+    large_step_probability: Float,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    stream: usize,
+    index: usize,
+}
+
+impl MLTSampler {
+    /// Samplers created from the same `rng` produce the same paths until
+    /// they're first mutated; that's how chains pick up from the bootstrap.
+    fn new(rng: StdRng, sigma: Float, large_step_probability: Float) -> MLTSampler {
+        MLTSampler {
+            rng,
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            stream: 0,
+            index: 0,
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<Float>() < self.large_step_probability;
+    }
+
+    fn start_stream(&mut self, stream: usize) {
+        self.stream = stream;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.restore();
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> Float {
+        let index = self.stream + STREAMS * self.index;
+        self.index += 1;
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+
+        let iteration = self.iteration;
+        let sample = &mut self.samples[index];
+
+        // Catch up on a large step this coordinate missed while unused.
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modified = self.last_large_step;
+        }
+
+        sample.backup();
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // The sum of n small steps is a single step with sqrt(n) times
+            // the standard deviation.
+            let steps = (iteration - sample.last_modified) as Float;
+            let sigma = self.sigma * steps.sqrt();
+            sample.value += normal(&mut self.rng) * sigma;
+            sample.value -= sample.value.floor();
+            if sample.value >= 1.0 {
+                sample.value = 0.0;
+            }
+        }
+        sample.last_modified = iteration;
+        sample.value
+    }
+}
+
+/// The random numbers behind bootstrap sample `index`.
+fn bootstrap_rng(seed: u64, index: u64) -> StdRng {
+    sample_rng(
+        seed,
+        index as usize,
+        (index >> 32) as usize,
+        0,
+        BOOTSTRAP_SAMPLE,
+    )
+}
+
+/// Standard normal variate, by the Box-Muller transform.
+fn normal<R: Rng>(rng: &mut R) -> Float {
+    let u1 = 1.0 - rng.gen::<Float>();
+    let u2: Float = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+impl RngCore for MLTSampler {
+    fn next_u32(&mut self) -> u32 {
+        (self.next() as f64 * 4_294_967_296.0) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next() as f64 * 18_446_744_073_709_551_616.0) as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Where a Markov chain is, and the path it's on.
+struct Chain {
+    sampler: MLTSampler,
+    /// Decides whether mutations are accepted.
+    rng: StdRng,
+    depth: u32,
+    l: Vec3,
+    raster: (Float, Float),
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.);
+/// pbrt's `Integrator "mlt"`. Markov chains wander over the random numbers
+/// that drive a bidirectional path tracer, spending their time on the
+/// paths that carry the most light, which makes them good at caustics and
+/// other hard-to-find transport.
+pub struct MLTIntegrator {
+    bdpt: BDPTIntegrator,
+    max_depth: u32,
+    bootstrap_samples: u32,
+    chains: u32,
+    mutations_per_pixel: u32,
+    large_step_probability: Float,
+    sigma: Float,
+}
+
+impl MLTIntegrator {
+    pub fn new(
+        camera: Camera,
+        max_depth: u32,
+        bootstrap_samples: u32,
+        chains: u32,
+        mutations_per_pixel: u32,
+        large_step_probability: Float,
+        sigma: Float,
+    ) -> MLTIntegrator {
+        MLTIntegrator {
+            bdpt: BDPTIntegrator::new(
+                camera,
+                Box::new(IndependentSampler::new(1)),
+                max_depth,
+                LightStrategy::Power,
+                false,
+                false,
+            ),
+            max_depth,
+            bootstrap_samples,
+            chains,
+            mutations_per_pixel,
+            large_step_probability,
+            sigma,
+        }
+    }
+
+    pub fn from_params(params: &ParamSet, camera: Camera) -> Result<MLTIntegrator, Box<dyn Error>> {
+        let large_step_probability = params.float("largestepprobability", 0.3);
+        if !(0.0..=1.0).contains(&large_step_probability) {
+            return Err(format!(
+                "\"largestepprobability\" must be between 0 and 1, not {}",
+                large_step_probability
+            )
+            .into());
+        }
+
+        Ok(MLTIntegrator::new(
+            camera,
+            params.int("maxdepth", 5).max(0) as u32,
+            params.int("bootstrapsamples", 100_000).max(1) as u32,
+            params.int("chains", 1000).max(1) as u32,
+            params.int("mutationsperpixel", 100).max(1) as u32,
+            large_step_probability,
+            params.float("sigma", 0.01),
+        ))
+    }
+
+    /// Radiance of the path of length `depth` that the sampler's current
+    /// point describes, and where on the film it lands. Picks one of the
+    /// BDPT strategies for that length and scales by their number.
+    fn l(&self, scene: &dyn Scene, sampler: &mut MLTSampler, depth: u32) -> (Vec3, (Float, Float)) {
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        let camera = self.bdpt.camera();
+
+        sampler.start_stream(CAMERA_STREAM);
+        let (s, t, strategies) = if depth == 0 {
+            (0, 2, 1)
+        } else {
+            let strategies = depth as usize + 2;
+            let s = ((sampler.next() * strategies as Float) as usize).min(strategies - 1);
+            (s, strategies - s, strategies)
+        };
+        let raster = (
+            sampler.next() * camera.width as Float,
+            sampler.next() * camera.height as Float,
+        );
+
+        let camera_path = self
+            .bdpt
+            .camera_subpath(scene, raster.0, raster.1, sampler, t);
+        if camera_path.len() != t {
+            return (black, raster);
+        }
+
+        sampler.start_stream(LIGHT_STREAM);
+        let light_path = self.bdpt.light_subpath(scene, sampler, s);
+        if light_path.len() != s {
+            return (black, raster);
+        }
+
+        sampler.start_stream(CONNECTION_STREAM);
+        let (l, _, splat) = self
+            .bdpt
+            .connect(scene, &light_path, &camera_path, s, t, sampler);
+        if !l.is_finite() {
+            return (black, raster);
+        }
+        (l * strategies as Float, splat.unwrap_or(raster))
+    }
+
+    /// Mutates `chain`'s path once, leaving what it contributes in
+    /// `splats`.
+    fn mutate(&self, scene: &dyn Scene, chain: &mut Chain, splats: &mut Vec<Splat>) {
+        chain.sampler.start_iteration();
+        let (l_proposed, p_proposed) = self.l(scene, &mut chain.sampler, chain.depth);
+
+        // Splat both states, weighted by their acceptance probability,
+        // rather than just the one that wins.
+        let (y_current, y_proposed) = (chain.l.luminance(), l_proposed.luminance());
+        let accept = if y_current > 0.0 {
+            (y_proposed / y_current).min(1.0)
+        } else {
+            1.0
+        };
+        if accept > 0.0 && y_proposed > 0.0 {
+            splats.push((p_proposed, &l_proposed * (accept / y_proposed)));
+        }
+        if accept < 1.0 && y_current > 0.0 {
+            splats.push((chain.raster, &chain.l * ((1.0 - accept) / y_current)));
+        }
+
+        if chain.rng.gen::<Float>() < accept {
+            chain.l = l_proposed;
+            chain.raster = p_proposed;
+            chain.sampler.accept();
+        } else {
+            chain.sampler.reject();
+        }
+    }
+}
+
+impl Integrator for MLTIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        progressive.reject_filmless(self.name())?;
+        self.bdpt.preprocess(scene);
+        let (width, height) = (self.bdpt.camera().width, self.bdpt.camera().height);
+        let depths = self.max_depth as u64 + 1;
+        let this = &*self;
+
+        // Bootstrap: estimate the image's total brightness, and keep the
+        // samples around as starting points for the chains, in proportion
+        // to their contribution.
+        let weights: Vec<Float> = (0..this.bootstrap_samples as u64 * depths)
+            .into_par_iter()
+            .map(|index| {
+                if observer.cancelled() {
+                    return 0.0;
+                }
+                let rng = bootstrap_rng(progressive.seed, index);
+                let mut sampler = MLTSampler::new(rng, this.sigma, this.large_step_probability);
+                this.l(scene, &mut sampler, (index % depths) as u32)
+                    .0
+                    .luminance()
+            })
+            .collect();
+        let b = weights.iter().map(|&w| w as f64).sum::<f64>() / this.bootstrap_samples as f64;
+        if b <= 0.0 || observer.cancelled() {
+            return Ok(Image::new(width, height));
+        }
+        let bootstrap = Distribution1D::new(&weights);
+
+        let mut chains: Vec<Chain> = (0..this.chains as usize)
+            .map(|chain| {
+                let mut rng = sample_rng(progressive.seed, chain, 0, 0, CHAIN_SAMPLE);
+                let (index, _) = bootstrap.sample(rng.gen());
+                let index = index as u64;
+                let depth = (index % depths) as u32;
+                let mut sampler = MLTSampler::new(
+                    bootstrap_rng(progressive.seed, index),
+                    this.sigma,
+                    this.large_step_probability,
+                );
+                let (l, raster) = this.l(scene, &mut sampler, depth);
+                Chain {
+                    sampler,
+                    rng,
+                    depth,
+                    l,
+                    raster,
+                }
+            })
+            .collect();
+
+        // Each pass makes as many mutations as there are pixels, shared
+        // between the chains. Their splats go on once the pass is done, in
+        // chain order, so the sums don't depend on how the threads
+        // interleaved.
+        let film = SplatFilm::new(width, height);
+        let mutations = (width * height) as u64;
+        progressive.run_passes(
+            this.name(),
+            &mut chains,
+            this.mutations_per_pixel,
+            |chains, pass| {
+                pass.tracker.start_pass(pass.index, chains.len());
+                let count = chains.len() as u64;
+                let splats: Vec<Vec<Splat>> = chains
+                    .par_iter_mut()
+                    .enumerate()
+                    .map(|(i, chain)| {
+                        let i = i as u64;
+                        let mut local = Vec::new();
+                        if pass.tracker.cancelled() {
+                            return local;
+                        }
+                        pass.tracker.start_tile();
+                        for _ in 0..(i + 1) * mutations / count - i * mutations / count {
+                            this.mutate(scene, chain, &mut local);
+                        }
+                        pass.tracker.tile_done(&Tile::whole(width, height));
+                        local
+                    })
+                    .collect();
+
+                if pass.tracker.cancelled() {
+                    return;
+                }
+                for (position, value) in splats.into_iter().flatten() {
+                    film.add(position, &value);
+                }
+            },
+            |_, passes| {
+                let scale = (b / passes.max(1) as f64) as Float;
+                Image::new(width, height).with_pixels(film.to_pixels(scale))
+            },
+            observer,
+        )
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        self.bdpt.resolution()
+    }
+
+    fn name(&self) -> &'static str {
+        "mlt"
+    }
+}
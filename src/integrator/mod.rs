@@ -1,5 +1,606 @@
+pub mod ao;
+pub mod bdpt;
+pub mod debug;
+pub mod direct;
+pub mod lightpath;
+pub mod mlt;
+pub mod path;
+pub mod sppm;
+
+use crate::camera::Camera;
+use crate::checkpoint::{Checkpoint, Progress};
+use crate::film::{Aovs, Film, SplatFilm};
 use crate::image::Image;
+use crate::progress::{Observer, Tracker};
+use crate::sample::Distribution1D;
+use crate::sampler::{SampleStream, Sampler};
+use crate::scene::light::Light;
+use crate::scene::material::Transport;
+use crate::scene::shape::HitRecord;
+use crate::scene::Scene;
+use crate::tiles::{for_each_tile, TileOrder};
+use crate::vec::*;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::error::Error;
+use std::ops::Range;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Rays start this far along their direction, so they don't re-hit the
+/// surface they were spawned from.
+pub const EPSILON: Float = 0.001;
 
 pub trait Integrator {
-    fn render(&mut self) -> Image;
+    fn render(&mut self, scene: &dyn Scene) -> Image {
+        self.render_progressive(scene, &Progressive::default(), &())
+            .unwrap()
+    }
+
+    /// Renders in passes until `progressive` says to stop or `observer`
+    /// cancels, keeping `observer` up to date. Fails, rather than ignore
+    /// them, on settings in `progressive` the integrator can't honour.
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>>;
+
+    /// How many passes a plain `render` makes, for integrators whose
+    /// `render_progressive` can be told to make just some of them with
+    /// `Progressive::passes`.
+    fn passes(&self) -> Option<u32> {
+        None
+    }
+
+    /// Width and height of the image it renders.
+    fn resolution(&self) -> (usize, usize);
+
+    /// What a scene's `Integrator` directive calls it, or "debug".
+    fn name(&self) -> &'static str;
+}
+
+/// When a progressive render stops, and how often it shows its work.
+#[derive(Clone, Default)]
+pub struct Progressive {
+    /// Don't start another pass after this much wall-clock time.
+    pub time_budget: Option<Duration>,
+    /// Stop once `Film::relative_error` is this low.
+    pub noise_threshold: Option<Float>,
+    pub snapshot_interval: Option<Duration>,
+    /// Only add samples to pixels that still need them, and stop once
+    /// none do.
+    pub adaptive: Option<Adaptive>,
+    pub checkpoint: Option<Checkpoint>,
+    /// Make only these of the passes a plain render would, e.g. for a share
+    /// of a distributed render.
+    pub passes: Option<Range<u32>>,
+    /// Renders with the same seed come out the same, however many threads
+    /// they run on.
+    pub seed: u64,
+    pub tile_order: TileOrder,
+}
+
+/// Which pass of a progressive render is being made.
+#[derive(Copy, Clone)]
+pub struct Pass<'a> {
+    pub index: u32,
+    pub seed: u64,
+    pub tile_order: TileOrder,
+    pub tracker: &'a Tracker<'a>,
+}
+
+/// Per-pixel sample counts driven by each pixel's estimated error.
+#[derive(Clone)]
+pub struct Adaptive {
+    /// `Pixel::relative_error` below which a pixel is done.
+    pub threshold: Float,
+    pub min_samples: u32,
+    /// `None` for the integrator's usual samples per pixel.
+    pub max_samples: Option<u32>,
+}
+
+impl Adaptive {
+    /// Which pixels get another sample: those short of the maximum that are
+    /// also short of the minimum, or whose error, or a neighbour's, is above
+    /// the threshold. Looking at the neighbours keeps pixels that happen to
+    /// have missed a rare, bright path so far from stopping too early.
+    fn active(&self, film: &Film, samples_per_pixel: u32) -> Vec<Vec<bool>> {
+        let max_samples = self.max_samples.unwrap_or(samples_per_pixel);
+        let noisy: Vec<Vec<bool>> = film
+            .pixels
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|p| p.relative_error() > self.threshold)
+                    .collect()
+            })
+            .collect();
+
+        (0..film.height)
+            .map(|y| {
+                (0..film.width)
+                    .map(|x| {
+                        let samples = film.pixels[y][x].samples;
+                        // The cap wins over a minimum set above it.
+                        if samples >= max_samples {
+                            return false;
+                        }
+                        if samples < self.min_samples {
+                            return true;
+                        }
+                        (y.saturating_sub(1)..(y + 2).min(film.height)).any(|ny| {
+                            (x.saturating_sub(1)..(x + 2).min(film.width)).any(|nx| noisy[ny][nx])
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Progressive {
+    /// Calls `pass` to add samples to `film` until done, then returns
+    /// `image(film)`. Each pass gives each pixel `(x, y)` as many samples as
+    /// the function it's handed says: one, or none for pixels adaptive
+    /// sampling is done with. Without a time budget, noise threshold or
+    /// adaptive sampling, it stops after `sampler`'s samples per pixel, the
+    /// same as a plain render.
+    ///
+    /// `pass` is also told the pass's index, to seed its random numbers
+    /// from. Checkpoints hold that, the seed, which sampler it was,
+    /// `film` and `splats`, which is all it
+    /// takes for a resumed render to come out the same as one that wasn't
+    /// interrupted, provided passes don't depend on the order threads
+    /// splat in.
+    pub fn run<P, F>(
+        &self,
+        film: &mut Film,
+        splats: &[SplatFilm],
+        sampler: &dyn Sampler,
+        mut pass: P,
+        image: F,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>>
+    where
+        P: FnMut(&mut Film, Pass<'_>, &(dyn Fn(usize, usize) -> u32 + Sync)),
+        F: Fn(&Film) -> Image,
+    {
+        let samples = sampler.samples_per_pixel();
+        let (mut passes, end) = match &self.passes {
+            Some(range) => (range.start, range.end),
+            None => (0, samples),
+        };
+        let mut earlier = Duration::from_secs(0);
+        if let Some(checkpoint) = self.checkpoint.as_ref().filter(|c| c.resume) {
+            let progress = checkpoint.load(film, splats)?;
+            if progress.seed != self.seed {
+                return Err(format!("checkpoint was rendered with seed {}", progress.seed).into());
+            }
+            if (progress.sampler.as_str(), progress.samples) != (sampler.name(), samples) {
+                return Err(format!(
+                    "checkpoint was rendered with the {} sampler at {} samples per pixel",
+                    progress.sampler, progress.samples
+                )
+                .into());
+            }
+            passes = progress.passes;
+            earlier = progress.elapsed;
+        }
+        let fixed =
+            self.time_budget.is_none() && self.noise_threshold.is_none() && self.adaptive.is_none();
+        let tracker = Tracker::new(
+            observer,
+            earlier,
+            passes,
+            Some(end).filter(|_| fixed),
+            self.time_budget,
+        );
+        let start = Instant::now();
+        let mut last_snapshot = start;
+        let mut last_checkpoint = start;
+
+        loop {
+            let this_pass = Pass {
+                index: passes,
+                seed: self.seed,
+                tile_order: self.tile_order,
+                tracker: &tracker,
+            };
+            match self.adaptive.as_ref().map(|a| a.active(film, samples)) {
+                Some(active) => {
+                    if !active.iter().flatten().any(|&a| a) {
+                        return Ok(image(film));
+                    }
+                    pass(film, this_pass, &|x, y| active[y][x] as u32);
+                }
+                None => pass(film, this_pass, &|_, _| 1),
+            }
+            passes += 1;
+            // A cancelled pass may have skipped tiles, so it's no use for a
+            // checkpoint.
+            if tracker.cancelled() {
+                return Ok(image(film));
+            }
+
+            let done = if fixed {
+                passes >= end
+            } else {
+                let out_of_time = self.time_budget.is_some_and(|b| tracker.elapsed() >= b);
+                // One sample per pixel says nothing about the variance.
+                let converged = passes > 1
+                    && self
+                        .noise_threshold
+                        .is_some_and(|t| film.relative_error() <= t);
+                out_of_time || converged
+            };
+            if done {
+                return Ok(image(film));
+            }
+
+            if let Some(interval) = self.snapshot_interval {
+                if last_snapshot.elapsed() >= interval {
+                    observer.snapshot(image(film));
+                    last_snapshot = Instant::now();
+                }
+            }
+
+            if let Some(checkpoint) = &self.checkpoint {
+                if last_checkpoint.elapsed() >= checkpoint.interval {
+                    let progress = Progress {
+                        passes,
+                        seed: self.seed,
+                        sampler: sampler.name().to_string(),
+                        samples,
+                        elapsed: tracker.elapsed(),
+                    };
+                    if let Err(e) = checkpoint.save(&progress, film, splats) {
+                        eprintln!(
+                            "warning: couldn't write checkpoint to {}: {}",
+                            checkpoint.path.display(),
+                            e
+                        );
+                    }
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+    }
+
+    /// Fails if `self` stops at a noise threshold or samples adaptively,
+    /// both of which go by the error of the camera samples in a `Film`,
+    /// for `integrator`, whose film has no radiance in it.
+    pub fn reject_error_driven(&self, integrator: &str) -> Result<(), Box<dyn Error>> {
+        if self.noise_threshold.is_some() {
+            return Err(format!(
+                "the {} integrator can't stop at a noise threshold",
+                integrator
+            )
+            .into());
+        }
+        if self.adaptive.is_some() {
+            return Err(format!("the {} integrator can't sample adaptively", integrator).into());
+        }
+        Ok(())
+    }
+
+    /// Fails if `self` asks for anything `run_passes` can't do for
+    /// `integrator`.
+    pub fn reject_filmless(&self, integrator: &str) -> Result<(), Box<dyn Error>> {
+        self.reject_error_driven(integrator)?;
+        if self.passes.is_some() {
+            return Err(format!(
+                "the {} integrator can't render a share of its passes",
+                integrator
+            )
+            .into());
+        }
+        if self.checkpoint.is_some() {
+            return Err(format!("the {} integrator can't write checkpoints", integrator).into());
+        }
+        Ok(())
+    }
+
+    /// Like `run`, for integrators whose passes don't add samples to a
+    /// `Film`, such as SPPM's iterations. Calls `pass` on `state` until
+    /// the time budget runs out, or `passes` times without one, and returns
+    /// `image` of `state` and the number of passes made. A pass the
+    /// observer cancels doesn't count, so `pass` should leave out whatever
+    /// it did once it sees the cancellation.
+    pub fn run_passes<S, P, F>(
+        &self,
+        integrator: &str,
+        state: &mut S,
+        passes: u32,
+        mut pass: P,
+        image: F,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>>
+    where
+        P: FnMut(&mut S, Pass<'_>),
+        F: Fn(&S, u32) -> Image,
+    {
+        self.reject_filmless(integrator)?;
+        let end = Some(passes).filter(|_| self.time_budget.is_none());
+        let tracker = Tracker::new(observer, Duration::from_secs(0), 0, end, self.time_budget);
+        let mut last_snapshot = Instant::now();
+        let mut made = 0;
+        loop {
+            pass(
+                state,
+                Pass {
+                    index: made,
+                    seed: self.seed,
+                    tile_order: self.tile_order,
+                    tracker: &tracker,
+                },
+            );
+            if tracker.cancelled() {
+                return Ok(image(state, made));
+            }
+            made += 1;
+
+            let done = match self.time_budget {
+                Some(budget) => tracker.elapsed() >= budget,
+                None => made >= passes,
+            };
+            if done {
+                return Ok(image(state, made));
+            }
+
+            if let Some(interval) = self.snapshot_interval {
+                if last_snapshot.elapsed() >= interval {
+                    observer.snapshot(image(state, made));
+                    last_snapshot = Instant::now();
+                }
+            }
+        }
+    }
+}
+
+/// An integrator that estimates the radiance along one camera ray at a
+/// time. `render_pixels` drives it over every sample of every pixel.
+pub trait SamplerIntegrator: Sync {
+    fn camera(&self) -> &Camera;
+
+    fn sampler(&self) -> &dyn Sampler;
+
+    /// Radiance arriving along `ray`. Implementations fill in `aovs` at
+    /// the first surface they hit.
+    fn li<R: Rng>(&self, ray: Ray, scene: &dyn Scene, rng: &mut R, aovs: &mut Aovs) -> Vec3;
+}
+
+/// Renders `integrator`'s image in passes, as `progressive` directs.
+pub fn render_pixels<I: SamplerIntegrator>(
+    integrator: &I,
+    scene: &dyn Scene,
+    progressive: &Progressive,
+    observer: &dyn Observer,
+) -> Result<Image, Box<dyn Error>> {
+    let camera = integrator.camera();
+    let sampler = integrator.sampler();
+    let mut film = Film::new(camera.width, camera.height);
+
+    progressive.run(
+        &mut film,
+        &[],
+        sampler,
+        |film, pass, samples| {
+            render_pass(film, pass, sampler, samples, |x, y, rng, aovs| {
+                let ray = camera.generate_ray(
+                    x as Float + rng.gen::<Float>(),
+                    y as Float + rng.gen::<Float>(),
+                    (rng.gen(), rng.gen()),
+                );
+                integrator.li(ray, scene, rng, aovs)
+            })
+        },
+        Film::to_image,
+        observer,
+    )
+}
+
+/// Adds `samples(x, y)` samples to each pixel of `film`, a tile at a
+/// time. `sample` returns the radiance for one sample of pixel `(x, y)`,
+/// drawing its random numbers from `sampler` and filling in its AOVs.
+/// The pass's index is the sample's index. Once the observer cancels, the
+/// tiles not yet started are skipped.
+pub fn render_pass<S, F>(
+    film: &mut Film,
+    pass: Pass<'_>,
+    sampler: &dyn Sampler,
+    samples: S,
+    sample: F,
+) where
+    S: Fn(usize, usize) -> u32 + Sync,
+    F: Fn(usize, usize, &mut SampleStream<'_>, &mut Aovs) -> Vec3 + Sync,
+{
+    let tiles = pass.tile_order.tiles(film.width, film.height);
+    pass.tracker.start_pass(pass.index, tiles.len());
+    let film = Mutex::new(film);
+    for_each_tile(&tiles, |tile| {
+        if pass.tracker.cancelled() {
+            return;
+        }
+        pass.tracker.start_tile();
+        let mut values = Vec::new();
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                for i in 0..samples(x, y) {
+                    let mut rng = SampleStream::new(sampler, x, y, pass.index + i);
+                    let mut aovs = Aovs::default();
+                    let l = sample(x, y, &mut rng, &mut aovs);
+                    let l = if l.is_finite() {
+                        l
+                    } else {
+                        Vec3::new(0.0, 0.0, 0.0)
+                    };
+                    values.push((x, y, l, aovs));
+                }
+            }
+        }
+
+        {
+            let mut film = film.lock().unwrap();
+            for (x, y, l, aovs) in values {
+                film.add_sample(x, y, &l, &aovs);
+            }
+        }
+        pass.tracker.tile_done(tile);
+    });
+}
+
+/// The random numbers for sample `sample` of pixel `(x, y)` in pass
+/// `pass`. Every sample gets its own stream, so no sample's numbers depend
+/// on which thread took it, or what that thread did before.
+pub fn sample_rng(seed: u64, x: usize, y: usize, pass: u32, sample: u32) -> StdRng {
+    let mut key = [0; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..12].copy_from_slice(&(x as u32).to_le_bytes());
+    key[12..16].copy_from_slice(&(y as u32).to_le_bytes());
+    key[16..20].copy_from_slice(&pass.to_le_bytes());
+    key[20..24].copy_from_slice(&sample.to_le_bytes());
+    StdRng::from_seed(key)
+}
+
+/// How to pick the light to sample for next-event estimation.
+#[derive(Copy, Clone)]
+pub enum LightStrategy {
+    Uniform,
+    Power,
+}
+
+impl LightStrategy {
+    /// pbrt's "lightsamplestrategy". "spatial" is treated as "power".
+    pub fn from_name(name: &str) -> Option<LightStrategy> {
+        match name {
+            "uniform" => Some(LightStrategy::Uniform),
+            "power" | "spatial" => Some(LightStrategy::Power),
+            _ => None,
+        }
+    }
+
+    pub fn distribution(self, lights: &[Light]) -> Distribution1D {
+        let weights: Vec<Float> = match self {
+            LightStrategy::Uniform => lights.iter().map(|_| 1.0).collect(),
+            LightStrategy::Power => lights.iter().map(|l| l.power().luminance()).collect(),
+        };
+        Distribution1D::new(&weights)
+    }
+}
+
+pub fn power_heuristic(nf: Float, f_pdf: Float, ng: Float, g_pdf: Float) -> Float {
+    let f = nf * f_pdf;
+    let g = ng * g_pdf;
+    if f * f + g * g == 0.0 {
+        return 0.0;
+    }
+    (f * f) / (f * f + g * g)
+}
+
+/// Direct light from one light source, sampling both the light and the
+/// BSDF and combining them with the power heuristic.
+pub fn estimate_direct<R: Rng>(
+    hit: &HitRecord,
+    wo: &Vec3,
+    light_index: usize,
+    scene: &dyn Scene,
+    rng: &mut R,
+) -> Vec3 {
+    let light = &scene.lights()[light_index];
+    let material = hit.material;
+    let n = &hit.normal;
+    let mut ld = Vec3::new(0.0, 0.0, 0.0);
+
+    if let Some(ls) = light.sample_li(&hit.point, (rng.gen(), rng.gen())) {
+        if ls.pdf > 0.0 && !ls.radiance.is_black() {
+            let f = material.f(wo, &ls.wi, n) * (&ls.wi % n).abs();
+            if !f.is_black() && unoccluded(scene, &hit.point, &ls.wi, ls.distance) {
+                if light.is_delta() {
+                    ld += f * ls.radiance / ls.pdf;
+                } else {
+                    let scattering_pdf = material.pdf(wo, &ls.wi, n);
+                    let weight = power_heuristic(1.0, ls.pdf, 1.0, scattering_pdf);
+                    ld += f * ls.radiance * (weight / ls.pdf);
+                }
+            }
+        }
+    }
+
+    if light.is_delta() {
+        return ld;
+    }
+
+    let bs = match material.sample(
+        wo,
+        n,
+        (rng.gen(), rng.gen()),
+        rng.gen(),
+        Transport::Radiance,
+    ) {
+        Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+        _ => return ld,
+    };
+    let f = &bs.f * (&bs.wi % n).abs();
+
+    let mut weight = 1.0;
+    if !bs.specular {
+        let light_pdf = light.pdf_li(&hit.point, &bs.wi);
+        if light_pdf == 0.0 {
+            return ld;
+        }
+        weight = power_heuristic(1.0, bs.pdf, 1.0, light_pdf);
+    }
+
+    let ray = Ray::new(hit.point.clone(), bs.wi.clone(), 0.0);
+    let li = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+        Some(light_hit) => {
+            if light_hit.light == Some(light_index) {
+                light.l(&light_hit.normal, &bs.wi.negate())
+            } else {
+                Vec3::new(0.0, 0.0, 0.0)
+            }
+        }
+        None => light.le(&ray),
+    };
+
+    if !li.is_black() {
+        ld += f * li * (weight / bs.pdf);
+    }
+    ld
+}
+
+/// Direct light from one light, picked from `distribution`.
+pub fn sample_one_light<R: Rng>(
+    hit: &HitRecord,
+    wo: &Vec3,
+    scene: &dyn Scene,
+    distribution: &Distribution1D,
+    rng: &mut R,
+) -> Vec3 {
+    if distribution.is_empty() {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    let (index, pdf) = distribution.sample(rng.gen());
+    if pdf == 0.0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    estimate_direct(hit, wo, index, scene, rng) / pdf
+}
+
+/// Is the segment from `point` along `wi` clear for `distance`?
+pub fn unoccluded(scene: &dyn Scene, point: &Vec3, wi: &Vec3, distance: Float) -> bool {
+    let ray = Ray::new(point.clone(), wi.clone(), 0.0);
+    !scene.occluded(&ray, EPSILON, distance * (1.0 - EPSILON) - EPSILON)
+}
+
+/// Emission from the surface at `hit`, seen from direction `wo`.
+pub fn emitted(hit: &HitRecord, wo: &Vec3, scene: &dyn Scene) -> Vec3 {
+    match hit.light {
+        Some(index) => scene.lights()[index].l(&hit.normal, wo),
+        None => Vec3::new(0.0, 0.0, 0.0),
+    }
 }
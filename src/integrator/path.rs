@@ -0,0 +1,177 @@
+use crate::camera::Camera;
+use crate::film::Aovs;
+use crate::image::Image;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::Distribution1D;
+use crate::sampler::Sampler;
+use crate::scene::material::Transport;
+use crate::scene::Scene;
+use crate::vec::*;
+
+use rand::Rng;
+
+use std::error::Error;
+
+/// Unidirectional path tracer with next-event estimation and Russian
+/// roulette; pbrt's `Integrator "path"`.
+///
+/// Supersedes the `color()` loop in `archive/camera.rs`, which stopped
+/// after 100 bounces or once the throughput dropped below 0.02 and so was
+/// biased dark.
+pub struct PathIntegrator {
+    camera: Camera,
+    sampler: Box<dyn Sampler>,
+    max_depth: u32,
+    rr_threshold: Float,
+    strategy: LightStrategy,
+    light_distribution: Distribution1D,
+}
+
+impl PathIntegrator {
+    pub fn new(
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+        max_depth: u32,
+        rr_threshold: Float,
+        strategy: LightStrategy,
+    ) -> PathIntegrator {
+        PathIntegrator {
+            camera,
+            sampler,
+            max_depth,
+            rr_threshold,
+            strategy,
+            light_distribution: Distribution1D::new(&[]),
+        }
+    }
+
+    pub fn from_params(
+        params: &ParamSet,
+        camera: Camera,
+        sampler: Box<dyn Sampler>,
+    ) -> Result<PathIntegrator, Box<dyn Error>> {
+        let name = params.string("lightsamplestrategy", "spatial");
+        let strategy = LightStrategy::from_name(&name)
+            .ok_or_else(|| format!("unknown light sample strategy \"{}\"", name))?;
+
+        Ok(PathIntegrator::new(
+            camera,
+            sampler,
+            params.int("maxdepth", 5).max(0) as u32,
+            params.float("rrthreshold", 1.0),
+            strategy,
+        ))
+    }
+}
+
+impl Integrator for PathIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        self.sampler.seed(progressive.seed);
+        self.light_distribution = self.strategy.distribution(scene.lights());
+        render_pixels(self, scene, progressive, observer)
+    }
+
+    fn passes(&self) -> Option<u32> {
+        Some(self.sampler.samples_per_pixel())
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "path"
+    }
+}
+
+impl SamplerIntegrator for PathIntegrator {
+    fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    fn sampler(&self) -> &dyn Sampler {
+        self.sampler.as_ref()
+    }
+
+    fn li<R: Rng>(&self, ray: Ray, scene: &dyn Scene, rng: &mut R, aovs: &mut Aovs) -> Vec3 {
+        let mut l = Vec3::new(0.0, 0.0, 0.0);
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+        let mut ray = ray;
+        let mut specular_bounce = false;
+        // Radiance scale from refraction, which Russian roulette should
+        // ignore so that it doesn't kill paths inside glass.
+        let mut eta_scale = 1.0;
+        let mut bounces = 0;
+
+        loop {
+            let hit = scene.hit(&ray, EPSILON, Float::INFINITY);
+            let wo = ray.direction.negate();
+
+            // Emission is otherwise accounted for by next-event estimation.
+            if bounces == 0 || specular_bounce {
+                match &hit {
+                    Some(hit) => l += &beta * emitted(hit, &wo, scene),
+                    None => {
+                        for light in scene.lights() {
+                            l += &beta * light.le(&ray);
+                        }
+                    }
+                }
+            }
+
+            let hit = match hit {
+                Some(hit) => hit,
+                None => break,
+            };
+
+            if bounces == 0 {
+                aovs.albedo = hit.material.albedo();
+                aovs.normal = hit.normal.clone();
+                aovs.depth = hit.pos;
+            }
+
+            if bounces >= self.max_depth {
+                break;
+            }
+
+            if !hit.material.is_specular() {
+                l += &beta * sample_one_light(&hit, &wo, scene, &self.light_distribution, rng);
+            }
+
+            let bs = match hit.material.sample(
+                &wo,
+                &hit.normal,
+                (rng.gen(), rng.gen()),
+                rng.gen(),
+                Transport::Radiance,
+            ) {
+                Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+                _ => break,
+            };
+
+            beta *= &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+            specular_bounce = bs.specular;
+            eta_scale *= bs.eta * bs.eta;
+            ray = Ray::new(hit.point, bs.wi, 0.0);
+
+            let rr_beta = (&beta * eta_scale).max_component();
+            if rr_beta < self.rr_threshold && bounces > 3 {
+                let q = (1.0 - rr_beta).max(0.05);
+                if rng.gen::<Float>() < q {
+                    break;
+                }
+                beta = beta / (1.0 - q);
+            }
+
+            bounces += 1;
+        }
+
+        l
+    }
+}
@@ -0,0 +1,518 @@
+use crate::camera::Camera;
+use crate::image::Image;
+use crate::integrator::*;
+use crate::parse::ParamSet;
+use crate::sample::Distribution1D;
+use crate::scene::material::{Material, Transport};
+use crate::scene::Scene;
+use crate::tiles::{Tile, TILE_SIZE};
+use crate::vec::*;
+
+use rand::Rng;
+use rayon::prelude::*;
+
+use std::error::Error;
+use std::f32::consts::PI;
+
+/// How much of each iteration's photons a pixel keeps; pbrt's gamma.
+const ALPHA: Float = 2.0 / 3.0;
+
+/// `sample_rng` sample numbers for the camera path through each pixel, and
+/// for each photon, which is numbered in place of a pixel.
+const CAMERA_SAMPLE: u32 = 0;
+const PHOTON_SAMPLE: u32 = 1;
+
+/// Photons traced per task, and tasks whose deposits are added to the
+/// pixels at a time. Only a batch's deposits are ever held at once.
+const PHOTON_CHUNK: usize = 1024;
+const CHUNKS_PER_BATCH: usize = 64;
+
+/// Where a camera path first reached a non-specular surface, waiting for
+/// photons to land nearby.
+struct VisiblePoint<'a> {
+    point: Vec3,
+    normal: Vec3,
+    wo: Vec3,
+    material: &'a Material,
+    /// Throughput of the camera path up to here.
+    beta: Vec3,
+}
+
+struct SPPMPixel<'a> {
+    radius: Float,
+    /// Emitted and directly reflected light, summed over iterations.
+    ld: Vec3,
+    /// What this iteration's camera path added to that, once the iteration
+    /// is done.
+    ld_iteration: Vec3,
+    vp: Option<VisiblePoint<'a>>,
+    /// Photons gathered this iteration: their flux, and how many.
+    phi: Vec3,
+    m: u32,
+    /// Photon count and flux carried over from earlier iterations.
+    n: Float,
+    tau: Vec3,
+}
+
+/// Uniform grid over the visible points, hashed into one bucket per pixel.
+struct Grid {
+    min: Vec3,
+    extent: Vec3,
+    resolution: [i64; 3],
+    buckets: Vec<Vec<usize>>,
+}
+
+impl Grid {
+    fn build(pixels: &[SPPMPixel]) -> Option<Grid> {
+        let mut bounds: Option<(Vec3, Vec3)> = None;
+        let mut max_radius: Float = 0.0;
+        for pixel in pixels {
+            if let Some(vp) = &pixel.vp {
+                let r = Vec3::new(pixel.radius, pixel.radius, pixel.radius);
+                let (lo, hi) = (&vp.point - &r, &vp.point + &r);
+                bounds = Some(match bounds {
+                    None => (lo, hi),
+                    Some((min, max)) => (min.elem_min(&lo), max.elem_max(&hi)),
+                });
+                max_radius = max_radius.max(pixel.radius);
+            }
+        }
+        let (min, max) = bounds?;
+
+        let extent = &max - &min;
+        let base = extent.max_component() / max_radius;
+        let res = |e: Float| ((base * e / extent.max_component()) as i64).max(1);
+        let mut grid = Grid {
+            resolution: [res(extent.x), res(extent.y), res(extent.z)],
+            min,
+            extent,
+            buckets: vec![Vec::new(); pixels.len()],
+        };
+
+        for (index, pixel) in pixels.iter().enumerate() {
+            if let Some(vp) = &pixel.vp {
+                let r = Vec3::new(pixel.radius, pixel.radius, pixel.radius);
+                let lo = grid.cell(&(&vp.point - &r));
+                let hi = grid.cell(&(&vp.point + &r));
+                for z in lo[2]..=hi[2] {
+                    for y in lo[1]..=hi[1] {
+                        for x in lo[0]..=hi[0] {
+                            // Neighbouring cells can hash to the same bucket.
+                            let hash = grid.hash([x, y, z]);
+                            let bucket = &mut grid.buckets[hash];
+                            if bucket.last() != Some(&index) {
+                                bucket.push(index);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Some(grid)
+    }
+
+    fn cell(&self, p: &Vec3) -> [i64; 3] {
+        let offset = p - &self.min;
+        let coord = |o: Float, e: Float, r: i64| {
+            let c = if e > 0.0 {
+                (o / e * r as Float) as i64
+            } else {
+                0
+            };
+            c.clamp(0, r - 1)
+        };
+        [
+            coord(offset.x, self.extent.x, self.resolution[0]),
+            coord(offset.y, self.extent.y, self.resolution[1]),
+            coord(offset.z, self.extent.z, self.resolution[2]),
+        ]
+    }
+
+    fn contains(&self, p: &Vec3) -> bool {
+        let offset = p - &self.min;
+        (0.0..=self.extent.x).contains(&offset.x)
+            && (0.0..=self.extent.y).contains(&offset.y)
+            && (0.0..=self.extent.z).contains(&offset.z)
+    }
+
+    fn hash(&self, cell: [i64; 3]) -> usize {
+        let h = (cell[0].wrapping_mul(73_856_093))
+            ^ (cell[1].wrapping_mul(19_349_663))
+            ^ (cell[2].wrapping_mul(83_492_791));
+        (h as u64 % self.buckets.len() as u64) as usize
+    }
+
+    fn pixels_near(&self, p: &Vec3) -> &[usize] {
+        if !self.contains(p) {
+            return &[];
+        }
+        &self.buckets[self.hash(self.cell(p))]
+    }
+}
+
+/// Stochastic progressive photon mapping (Hachisuka and Jensen); pbrt's
+/// `Integrator "sppm"`. Each iteration finds a visible point per pixel,
+/// then shoots photons and gathers those landing within the pixel's
+/// radius, which shrinks as photons accumulate. The estimate converges
+/// even for light paths that no other integrator here can sample, such as
+/// caustics seen through glass.
+pub struct SPPMIntegrator {
+    camera: Camera,
+    iterations: u32,
+    max_depth: u32,
+    /// Photons shot per iteration; `None` shoots one per pixel.
+    photons_per_iteration: Option<usize>,
+    initial_radius: Float,
+}
+
+impl SPPMIntegrator {
+    pub fn new(
+        camera: Camera,
+        iterations: u32,
+        max_depth: u32,
+        photons_per_iteration: Option<usize>,
+        initial_radius: Float,
+    ) -> SPPMIntegrator {
+        SPPMIntegrator {
+            camera,
+            iterations,
+            max_depth,
+            photons_per_iteration,
+            initial_radius,
+        }
+    }
+
+    pub fn from_params(
+        params: &ParamSet,
+        camera: Camera,
+    ) -> Result<SPPMIntegrator, Box<dyn Error>> {
+        let radius = params.float("radius", 1.0);
+        if radius <= 0.0 {
+            return Err(format!("SPPM \"radius\" must be positive, not {}", radius).into());
+        }
+        let photons = params.int("photonsperiteration", -1);
+
+        Ok(SPPMIntegrator::new(
+            camera,
+            params.int("numiterations", 64).max(1) as u32,
+            params.int("maxdepth", 5).max(0) as u32,
+            if photons > 0 {
+                Some(photons as usize)
+            } else {
+                None
+            },
+            radius,
+        ))
+    }
+
+    /// Follows a camera ray through specular bounces to its visible point,
+    /// setting `pixel.ld_iteration` to what it sees directly.
+    fn camera_pass<'a, R: Rng>(
+        &self,
+        scene: &'a dyn Scene,
+        x: usize,
+        y: usize,
+        pixel: &mut SPPMPixel<'a>,
+        light_distribution: &Distribution1D,
+        rng: &mut R,
+    ) {
+        let mut ray = self.camera.generate_ray(
+            x as Float + rng.gen::<Float>(),
+            y as Float + rng.gen::<Float>(),
+            (rng.gen(), rng.gen()),
+        );
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+        let mut specular_bounce = false;
+        pixel.ld_iteration = Vec3::new(0.0, 0.0, 0.0);
+        pixel.vp = None;
+
+        for depth in 0..self.max_depth {
+            let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+                Some(hit) => hit,
+                None => {
+                    for light in scene.lights() {
+                        pixel.ld_iteration += &beta * light.le(&ray);
+                    }
+                    return;
+                }
+            };
+            let wo = ray.direction.negate();
+
+            if depth == 0 || specular_bounce {
+                pixel.ld_iteration += &beta * emitted(&hit, &wo, scene);
+            }
+
+            if !hit.material.is_specular() {
+                pixel.ld_iteration +=
+                    &beta * sample_one_light(&hit, &wo, scene, light_distribution, rng);
+                pixel.vp = Some(VisiblePoint {
+                    point: hit.point,
+                    normal: hit.normal,
+                    wo,
+                    material: hit.material,
+                    beta,
+                });
+                return;
+            }
+
+            let bs = match hit.material.sample(
+                &wo,
+                &hit.normal,
+                (rng.gen(), rng.gen()),
+                rng.gen(),
+                Transport::Radiance,
+            ) {
+                Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+                _ => return,
+            };
+            specular_bounce = bs.specular;
+            beta *= &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+
+            let y = beta.luminance();
+            if y < 0.25 {
+                let q = (1.0 - y).max(0.0);
+                if rng.gen::<Float>() < q {
+                    return;
+                }
+                beta = beta / (1.0 - q);
+            }
+            ray = Ray::new(hit.point, bs.wi, 0.0);
+        }
+    }
+
+    /// Traces one photon, returning the flux it deposits at nearby visible
+    /// points as `(pixel, flux)` pairs.
+    fn trace_photon<R: Rng>(
+        &self,
+        scene: &dyn Scene,
+        pixels: &[SPPMPixel],
+        grid: &Grid,
+        light_distribution: &Distribution1D,
+        rng: &mut R,
+        deposits: &mut Vec<(usize, Vec3)>,
+    ) {
+        let (index, light_pdf) = light_distribution.sample(rng.gen());
+        if light_pdf == 0.0 {
+            return;
+        }
+        let es =
+            match scene.lights()[index].sample_le((rng.gen(), rng.gen()), (rng.gen(), rng.gen())) {
+                Some(es) if es.pdf_pos > 0.0 && es.pdf_dir > 0.0 && !es.radiance.is_black() => es,
+                _ => return,
+            };
+        let cos = (&es.normal % &es.ray.direction).abs();
+        let mut beta = &es.radiance * (cos / (light_pdf * es.pdf_pos * es.pdf_dir));
+        let mut ray = es.ray;
+
+        for depth in 0..self.max_depth {
+            let hit = match scene.hit(&ray, EPSILON, Float::INFINITY) {
+                Some(hit) => hit,
+                None => return,
+            };
+            let wo = ray.direction.negate();
+
+            // Direct lighting is already in `ld`.
+            if depth > 0 {
+                for &p in grid.pixels_near(&hit.point) {
+                    let pixel = &pixels[p];
+                    let vp = pixel.vp.as_ref().unwrap();
+                    let d = &vp.point - &hit.point;
+                    if &d % &d > pixel.radius * pixel.radius {
+                        continue;
+                    }
+                    let phi = &beta * vp.material.f(&vp.wo, &wo, &vp.normal);
+                    deposits.push((p, phi));
+                }
+            }
+
+            let bs = match hit.material.sample(
+                &wo,
+                &hit.normal,
+                (rng.gen(), rng.gen()),
+                rng.gen(),
+                Transport::Importance,
+            ) {
+                Some(bs) if bs.pdf > 0.0 && !bs.f.is_black() => bs,
+                _ => return,
+            };
+            let beta_new = &beta * &bs.f * ((&bs.wi % &hit.normal).abs() / bs.pdf);
+
+            // Russian roulette keeps photons' power roughly constant.
+            if beta.luminance() <= 0.0 {
+                return;
+            }
+            let q = (1.0 - beta_new.luminance() / beta.luminance()).max(0.0);
+            if rng.gen::<Float>() < q {
+                return;
+            }
+            beta = beta_new / (1.0 - q);
+            ray = Ray::new(hit.point, bs.wi, 0.0);
+        }
+    }
+}
+
+impl Integrator for SPPMIntegrator {
+    fn render_progressive(
+        &mut self,
+        scene: &dyn Scene,
+        progressive: &Progressive,
+        observer: &dyn Observer,
+    ) -> Result<Image, Box<dyn Error>> {
+        let (width, height) = (self.camera.width, self.camera.height);
+        let photons = self.photons_per_iteration.unwrap_or(width * height);
+        let direct_distribution = LightStrategy::Uniform.distribution(scene.lights());
+        let photon_distribution = LightStrategy::Power.distribution(scene.lights());
+
+        let mut pixels: Vec<SPPMPixel> = (0..width * height)
+            .map(|_| SPPMPixel {
+                radius: self.initial_radius,
+                ld: Vec3::new(0.0, 0.0, 0.0),
+                ld_iteration: Vec3::new(0.0, 0.0, 0.0),
+                vp: None,
+                phi: Vec3::new(0.0, 0.0, 0.0),
+                m: 0,
+                n: 0.0,
+                tau: Vec3::new(0.0, 0.0, 0.0),
+            })
+            .collect();
+
+        let this = &*self;
+        progressive.run_passes(
+            this.name(),
+            &mut pixels,
+            self.iterations,
+            |pixels, pass| {
+                // The camera paths go a band of rows at a time, then the
+                // photons a chunk at a time, each reported as it's done.
+                let bands = height.div_ceil(TILE_SIZE);
+                let chunks = if photon_distribution.is_empty() {
+                    0
+                } else {
+                    photons.div_ceil(PHOTON_CHUNK)
+                };
+                pass.tracker.start_pass(pass.index, bands + chunks);
+
+                pixels
+                    .par_chunks_mut(width * TILE_SIZE)
+                    .enumerate()
+                    .for_each(|(band, rows)| {
+                        if pass.tracker.cancelled() {
+                            return;
+                        }
+                        pass.tracker.start_tile();
+                        let y0 = band * TILE_SIZE;
+                        for (i, pixel) in rows.iter_mut().enumerate() {
+                            let (x, y) = (i % width, y0 + i / width);
+                            let mut rng = sample_rng(pass.seed, x, y, pass.index, CAMERA_SAMPLE);
+                            this.camera_pass(scene, x, y, pixel, &direct_distribution, &mut rng);
+                        }
+                        pass.tracker.tile_done(&Tile {
+                            x0: 0,
+                            y0,
+                            x1: width,
+                            y1: (y0 + TILE_SIZE).min(height),
+                        });
+                    });
+                if pass.tracker.cancelled() {
+                    return;
+                }
+
+                if chunks > 0 {
+                    if let Some(grid) = Grid::build(pixels) {
+                        // Each batch's deposits are added in chunk order, so
+                        // the sums don't depend on how the threads
+                        // interleaved.
+                        for batch in (0..chunks).step_by(CHUNKS_PER_BATCH) {
+                            let deposits: Vec<Vec<(usize, Vec3)>> = (batch
+                                ..(batch + CHUNKS_PER_BATCH).min(chunks))
+                                .into_par_iter()
+                                .map(|c| {
+                                    let mut local = Vec::new();
+                                    if pass.tracker.cancelled() {
+                                        return local;
+                                    }
+                                    pass.tracker.start_tile();
+                                    let end = ((c + 1) * PHOTON_CHUNK).min(photons);
+                                    for photon in c * PHOTON_CHUNK..end {
+                                        let mut rng = sample_rng(
+                                            pass.seed,
+                                            photon,
+                                            0,
+                                            pass.index,
+                                            PHOTON_SAMPLE,
+                                        );
+                                        this.trace_photon(
+                                            scene,
+                                            pixels,
+                                            &grid,
+                                            &photon_distribution,
+                                            &mut rng,
+                                            &mut local,
+                                        );
+                                    }
+                                    pass.tracker.tile_done(&Tile::whole(width, height));
+                                    local
+                                })
+                                .collect();
+
+                            for (p, phi) in deposits.into_iter().flatten() {
+                                pixels[p].phi += phi;
+                                pixels[p].m += 1;
+                            }
+                        }
+                    }
+                }
+
+                // An iteration that was cut short is left out altogether.
+                if pass.tracker.cancelled() {
+                    return;
+                }
+
+                // Shrink each radius in proportion to the photons it caught,
+                // keeping only a fraction of them so the estimate stays
+                // consistent.
+                pixels.par_iter_mut().for_each(|pixel| {
+                    pixel.ld = &pixel.ld + &pixel.ld_iteration;
+                    if pixel.m > 0 {
+                        let m = pixel.m as Float;
+                        let n = pixel.n + ALPHA * m;
+                        let radius = pixel.radius * (n / (pixel.n + m)).sqrt();
+                        let beta = pixel.vp.as_ref().map(|vp| vp.beta.clone()).unwrap();
+                        let scale = (radius * radius) / (pixel.radius * pixel.radius);
+                        pixel.tau = (&pixel.tau + beta * &pixel.phi) * scale;
+                        pixel.n = n;
+                        pixel.radius = radius;
+                        pixel.m = 0;
+                        pixel.phi = Vec3::new(0.0, 0.0, 0.0);
+                    }
+                    pixel.vp = None;
+                });
+            },
+            |pixels, iterations| {
+                let iterations = iterations.max(1) as Float;
+                let total_photons = iterations * photons as Float;
+                let radiance = pixels
+                    .chunks(width)
+                    .map(|row| {
+                        row.iter()
+                            .map(|p| {
+                                let area = PI * p.radius * p.radius;
+                                &p.ld / iterations + &p.tau / (total_photons * area)
+                            })
+                            .collect()
+                    })
+                    .collect();
+                Image::new(width, height).with_pixels(radiance)
+            },
+            observer,
+        )
+    }
+
+    fn resolution(&self) -> (usize, usize) {
+        (self.camera.width, self.camera.height)
+    }
+
+    fn name(&self) -> &'static str {
+        "sppm"
+    }
+}
@@ -1,18 +1,48 @@
+// Most of the renderer is still being ported over from archive/.
+#![allow(dead_code)]
+
 extern crate clap;
 extern crate rand;
 
 use std::error::Error;
-use std::fs::File;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use clap::{App, Arg};
 
+mod camera;
+mod checkpoint;
+use checkpoint::{scene_hash, Checkpoint};
+mod denoise;
+mod distributed;
+mod film;
+use denoise::{Denoiser, Filter};
+
 mod image;
+use image::Image;
 mod integrator;
+use integrator::debug::DebugMode;
+use integrator::{Adaptive, Progressive};
+mod preview;
+mod progress;
+mod sample;
+mod sampler;
 mod scene;
+mod serve;
+use progress::{Observer, Status};
+mod tiles;
+use tiles::{Tile, TileOrder};
+mod transform;
 mod vec;
 
 mod parse;
-use parse::parse_file;
+use parse::{flatten_file, parse_file};
+
+mod post;
+use post::{Aperture, Effect};
 
 fn main() -> Result<(), Box<dyn Error>> {
     let matches = App::new("Raytrace")
@@ -25,7 +55,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .long("output-file")
                 .default_value("out.ppm")
                 .takes_value(true)
-                .help("Output file name"),
+                .help("Output file name; .exr also keeps the AOV and variance channels"),
         )
         .arg(
             Arg::with_name("input-file")
@@ -34,16 +64,496 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .takes_value(true)
                 .help("Input file in pbrtv3 format"),
         )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .takes_value(true)
+                .possible_values(DebugMode::NAMES)
+                .help("Render a debug visualization instead of the scene's integrator"),
+        )
+        .arg(
+            Arg::with_name("denoise")
+                .long("denoise")
+                .takes_value(true)
+                .possible_values(&["bilateral", "nlm"])
+                .help("Filter the rendered image with the AOV-guided denoiser"),
+        )
+        .arg(
+            Arg::with_name("denoise-radius")
+                .long("denoise-radius")
+                .default_value("7")
+                .takes_value(true)
+                .help("Half-width of the denoiser's search window, in pixels"),
+        )
+        .arg(
+            Arg::with_name("keep-noisy")
+                .long("keep-noisy")
+                .requires("denoise")
+                .help("Also write the unfiltered image, with a -noisy suffix"),
+        )
+        .arg(
+            Arg::with_name("bloom")
+                .long("bloom")
+                .takes_value(true)
+                .help("Bloom everything brighter than this radiance"),
+        )
+        .arg(
+            Arg::with_name("bloom-strength")
+                .long("bloom-strength")
+                .default_value("0.1")
+                .takes_value(true)
+                .help("How much of the blurred highlights to add back"),
+        )
+        .arg(
+            Arg::with_name("bloom-radius")
+                .long("bloom-radius")
+                .default_value("8")
+                .takes_value(true)
+                .help("Standard deviation of the bloom blur, in pixels"),
+        )
+        .arg(
+            Arg::with_name("glare")
+                .long("glare")
+                .takes_value(true)
+                .help("Diffraction glare from an aperture: a blade count, or a PPM mask"),
+        )
+        .arg(
+            Arg::with_name("glare-strength")
+                .long("glare-strength")
+                .default_value("0.2")
+                .takes_value(true)
+                .help("Fraction of the highlight energy spread by the glare"),
+        )
+        .arg(
+            Arg::with_name("glare-threshold")
+                .long("glare-threshold")
+                .default_value("1")
+                .takes_value(true)
+                .help("Spread glare from everything brighter than this radiance"),
+        )
+        .arg(
+            Arg::with_name("vignette")
+                .long("vignette")
+                .takes_value(true)
+                .help("Cos^4 vignetting; tangent of the angle to the image corner"),
+        )
+        .arg(
+            Arg::with_name("time-budget")
+                .long("time-budget")
+                .takes_value(true)
+                .help("Keep adding samples for this many seconds, ignoring pixelsamples"),
+        )
+        .arg(
+            Arg::with_name("noise-threshold")
+                .long("noise-threshold")
+                .takes_value(true)
+                .help("Keep adding samples until the mean relative error is below this"),
+        )
+        .arg(
+            Arg::with_name("adaptive")
+                .long("adaptive")
+                .takes_value(true)
+                .help("Stop sampling pixels once their relative error is below this"),
+        )
+        .arg(
+            Arg::with_name("min-spp")
+                .long("min-spp")
+                .default_value("16")
+                .takes_value(true)
+                .help("Samples every pixel gets with --adaptive"),
+        )
+        .arg(
+            Arg::with_name("max-spp")
+                .long("max-spp")
+                .takes_value(true)
+                .requires("adaptive")
+                .help("Most samples a pixel gets with --adaptive; defaults to pixelsamples"),
+        )
+        .arg(
+            Arg::with_name("sample-heatmap")
+                .long("sample-heatmap")
+                .takes_value(true)
+                .help("Also write an image of how many samples each pixel got"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
+                .help("Periodically save the render's state here, for --resume"),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .long("checkpoint-interval")
+                .default_value("600")
+                .takes_value(true)
+                .help("Seconds between checkpoints"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .requires("checkpoint")
+                .help("Carry on from the last checkpoint instead of starting over"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .default_value("0")
+                .takes_value(true)
+                .help("Seed for the random numbers; the same seed gives the same image"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .help("How many threads to render on; defaults to one per CPU"),
+        )
+        .arg(
+            Arg::with_name("tile-order")
+                .long("tile-order")
+                .default_value("spiral")
+                .takes_value(true)
+                .possible_values(TileOrder::NAMES)
+                .help("Order to render tiles in"),
+        )
+        .arg(
+            Arg::with_name("coordinator")
+                .long("coordinator")
+                .takes_value(true)
+                .value_name("address")
+                .conflicts_with_all(&[
+                    "debug",
+                    "time-budget",
+                    "noise-threshold",
+                    "adaptive",
+                    "checkpoint",
+                    "snapshot-interval",
+                ])
+                .help("Listen here for workers and have them render the scene"),
+        )
+        .arg(
+            Arg::with_name("task-samples")
+                .long("task-samples")
+                .default_value("4")
+                .takes_value(true)
+                .help("Samples per pixel in each task the coordinator hands out"),
+        )
+        .arg(
+            Arg::with_name("worker")
+                .long("worker")
+                .takes_value(true)
+                .value_name("address")
+                .conflicts_with("coordinator")
+                .help("Render for the coordinator at this address, until it's done"),
+        )
+        .arg(
+            Arg::with_name("serve")
+                .long("serve")
+                .takes_value(true)
+                .value_name("address")
+                .conflicts_with_all(&[
+                    "input-file",
+                    "coordinator",
+                    "worker",
+                    "checkpoint",
+                    "denoise",
+                    "sample-heatmap",
+                ])
+                .help("Render scenes sent to this address over HTTP, one job at a time"),
+        )
+        .arg(
+            Arg::with_name("preview")
+                .long("preview")
+                .conflicts_with_all(&["coordinator", "serve"])
+                .help("Draw the image so far in the terminal, in 24-bit colour"),
+        )
+        .arg(
+            Arg::with_name("preview-width")
+                .long("preview-width")
+                .default_value("80")
+                .takes_value(true)
+                .help("Width of the --preview, in characters"),
+        )
+        .arg(
+            Arg::with_name("snapshot-interval")
+                .long("snapshot-interval")
+                .takes_value(true)
+                .help("Write the image so far to the output file every this many seconds"),
+        )
         .get_matches();
 
+    if let Some(threads) = matches.value_of("threads") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.parse()?)
+            .build_global()?;
+    }
+
+    if let Some(addr) = matches.value_of("worker") {
+        return distributed::work(addr);
+    }
+
     let output_file = matches.value_of("output-file").unwrap();
-    let input_file = matches.value_of("input-file").unwrap();
 
-    let (scene, mut integrator) = parse_file(input_file)?;
+    let mut effects = Vec::new();
+    if let Some(threshold) = matches.value_of("bloom") {
+        effects.push(Effect::Bloom {
+            threshold: threshold.parse()?,
+            strength: matches.value_of("bloom-strength").unwrap().parse()?,
+            radius: matches.value_of("bloom-radius").unwrap().parse()?,
+        });
+    }
+    if let Some(aperture) = matches.value_of("glare") {
+        effects.push(Effect::Glare {
+            aperture: Aperture::from_arg(aperture)?,
+            threshold: matches.value_of("glare-threshold").unwrap().parse()?,
+            strength: matches.value_of("glare-strength").unwrap().parse()?,
+        });
+    }
+    if let Some(strength) = matches.value_of("vignette") {
+        effects.push(Effect::Vignette {
+            strength: strength.parse()?,
+        });
+    }
+
+    let seconds = |name| -> Result<Option<Duration>, Box<dyn Error>> {
+        match matches.value_of(name) {
+            Some(s) => Ok(Some(Duration::from_secs_f64(s.parse()?))),
+            None => Ok(None),
+        }
+    };
+    let seed = matches.value_of("seed").unwrap().parse()?;
+    let preview = if matches.is_present("preview") {
+        Some(
+            matches
+                .value_of("preview-width")
+                .unwrap()
+                .parse::<usize>()?,
+        )
+    } else {
+        None
+    };
+    let mut progressive = Progressive {
+        time_budget: seconds("time-budget")?,
+        noise_threshold: match matches.value_of("noise-threshold") {
+            Some(t) => Some(t.parse()?),
+            None => None,
+        },
+        // The preview is drawn from snapshots too, so take them as often as
+        // either wants them.
+        snapshot_interval: match (seconds("snapshot-interval")?, preview) {
+            (Some(interval), Some(_)) => Some(interval.min(PREVIEW_INTERVAL)),
+            (interval, preview) => interval.or(preview.map(|_| PREVIEW_INTERVAL)),
+        },
+        adaptive: match matches.value_of("adaptive") {
+            Some(threshold) => Some(Adaptive {
+                threshold: threshold.parse()?,
+                min_samples: matches.value_of("min-spp").unwrap().parse()?,
+                max_samples: match matches.value_of("max-spp") {
+                    Some(max) => Some(max.parse()?),
+                    None => None,
+                },
+            }),
+            None => None,
+        },
+        // Set once the scene is read, since it has to know the integrator.
+        checkpoint: None,
+        passes: None,
+        seed,
+        tile_order: TileOrder::from_name(matches.value_of("tile-order").unwrap()).unwrap(),
+    };
+    let debug = matches.value_of("debug").and_then(DebugMode::from_name);
+
+    if let Some(addr) = matches.value_of("serve") {
+        let settings = serve::Settings {
+            progressive,
+            debug,
+            effects,
+        };
+        return serve::serve(addr, settings);
+    }
 
-    let image = integrator.render();
+    let input_file = matches
+        .value_of("input-file")
+        .ok_or("no input file; pass one with -f, or use --serve")?;
+    let image = if let Some(addr) = matches.value_of("coordinator") {
+        let task_samples = matches.value_of("task-samples").unwrap().parse()?;
+        distributed::coordinate(addr, input_file, task_samples, seed)?
+    } else {
+        let (scene, mut integrator) = parse_file(input_file, debug)?;
+        if let Some(path) = matches.value_of("checkpoint") {
+            progressive.checkpoint = Some(Checkpoint {
+                path: path.into(),
+                interval: seconds("checkpoint-interval")?.unwrap(),
+                resume: matches.is_present("resume"),
+                scene: scene_hash(&flatten_file(input_file)?),
+                integrator: integrator.name(),
+                debug,
+            });
+        }
+        let report = Report {
+            output_file,
+            effects: &effects,
+            show_progress: preview.is_some() || io::stderr().is_terminal(),
+            snapshot_interval: seconds("snapshot-interval")?,
+            preview,
+            terminal: Mutex::new(Terminal {
+                redrawn: None,
+                line: String::new(),
+                preview_lines: 0,
+            }),
+            last_written: Mutex::new(Instant::now()),
+        };
+        let image = integrator.render_progressive(scene.as_ref(), &progressive, &report)?;
+        if let Some(columns) = preview {
+            // The last snapshot is from before the last pass, at best.
+            let shown = post::apply_all(&effects, image.with_pixels(image.pixels.clone()));
+            report.draw_preview(&preview::half_blocks(&shown, columns));
+        }
+        if report.terminal.into_inner().unwrap().redrawn.is_some() {
+            eprintln!();
+        }
+        image
+    };
 
-    image.write_to(output_file)?;
+    if let Some(file) = matches.value_of("sample-heatmap") {
+        match integrator::debug::sample_heatmap(&image) {
+            Some(heatmap) => heatmap.write_to(file)?,
+            None => eprintln!("warning: this integrator doesn't count samples per pixel"),
+        }
+    }
+
+    match matches.value_of("denoise") {
+        None => post::apply_all(&effects, image).write_to(output_file)?,
+        Some(name) => {
+            let filter = Filter::from_name(name).unwrap();
+            let radius = matches
+                .value_of("denoise-radius")
+                .unwrap()
+                .parse::<usize>()?;
+            let denoised = Denoiser::new(filter, radius).denoise(&image);
+
+            if matches.is_present("keep-noisy") {
+                post::apply_all(&effects, image).write_to(&suffixed(output_file, "noisy"))?;
+            }
+            post::apply_all(&effects, denoised).write_to(output_file)?;
+        }
+    }
 
     Ok(())
 }
+
+/// How often `--preview` is redrawn, at most.
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shows how the render is going on a terminal, optionally with a preview
+/// of the image above the progress line, and writes snapshots over the
+/// output file.
+struct Report<'a> {
+    output_file: &'a str,
+    effects: &'a [Effect],
+    show_progress: bool,
+    /// How often to write snapshots, which may be less often than they're
+    /// taken for the preview.
+    snapshot_interval: Option<Duration>,
+    /// The preview's width in characters, if there is one.
+    preview: Option<usize>,
+    terminal: Mutex<Terminal>,
+    last_written: Mutex<Instant>,
+}
+
+struct Terminal {
+    /// When the progress line was last redrawn, and what it said.
+    redrawn: Option<Instant>,
+    line: String,
+    /// How many lines the preview above it takes up.
+    preview_lines: usize,
+}
+
+impl Report<'_> {
+    /// Replaces the preview above the progress line with `lines`.
+    fn draw_preview(&self, lines: &str) {
+        let mut terminal = self.terminal.lock().unwrap();
+        let mut out = String::from("\r");
+        if terminal.preview_lines > 0 {
+            out += &format!("\x1b[{}A", terminal.preview_lines);
+        }
+        out += lines;
+        out += &format!("{:<70}", terminal.line);
+        terminal.preview_lines = lines.matches('\n').count();
+        terminal.redrawn = Some(Instant::now());
+        let mut stderr = io::stderr().lock();
+        let _ = stderr
+            .write_all(out.as_bytes())
+            .and_then(|()| stderr.flush());
+    }
+}
+
+impl Observer for Report<'_> {
+    fn tile_done(&self, _tile: &Tile, status: &Status) {
+        if !self.show_progress {
+            return;
+        }
+        let mut terminal = self.terminal.lock().unwrap();
+        if terminal
+            .redrawn
+            .is_some_and(|t| t.elapsed() < Duration::from_millis(250))
+        {
+            return;
+        }
+        terminal.redrawn = Some(Instant::now());
+
+        let mut line = format!(
+            "pass {}, tile {}/{}, {:.1} Mrays/s",
+            status.pass + 1,
+            status.tiles_done,
+            status.tiles,
+            status.rays_per_second / 1e6
+        );
+        if let (Some(fraction), Some(eta)) = (status.fraction, status.eta) {
+            line += &format!(", {:.0}% done, {}s left", fraction * 100.0, eta.as_secs());
+        }
+        eprint!("\r{:<70}", line);
+        terminal.line = line;
+    }
+
+    fn snapshot(&self, image: Image) {
+        let image = post::apply_all(self.effects, image);
+        if let Some(columns) = self.preview {
+            self.draw_preview(&preview::half_blocks(&image, columns));
+        }
+
+        let interval = match self.snapshot_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        {
+            let mut last_written = self.last_written.lock().unwrap();
+            if last_written.elapsed() < interval {
+                return;
+            }
+            *last_written = Instant::now();
+        }
+        // Write next to the output and rename, so that a render killed
+        // mid-write still leaves the previous snapshot intact.
+        let partial = suffixed(self.output_file, "partial");
+        let written = image
+            .write_to(&partial)
+            .and_then(|()| Ok(fs::rename(&partial, self.output_file)?));
+        if let Err(e) = written {
+            eprintln!(
+                "warning: couldn't write snapshot to {}: {}",
+                self.output_file, e
+            );
+        }
+    }
+}
+
+/// `out.ppm` -> `out-<suffix>.ppm`
+fn suffixed(file: &str, suffix: &str) -> String {
+    let path = Path::new(file);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(file);
+    let name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}-{}.{}", stem, suffix, ext),
+        None => format!("{}-{}", stem, suffix),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
@@ -1,8 +1,918 @@
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::camera::Camera;
+use crate::integrator::ao::AOIntegrator;
+use crate::integrator::bdpt::BDPTIntegrator;
+use crate::integrator::debug::{DebugIntegrator, DebugMode};
+use crate::integrator::direct::DirectLightingIntegrator;
+use crate::integrator::lightpath::LightPathIntegrator;
+use crate::integrator::mlt::MLTIntegrator;
+use crate::integrator::path::PathIntegrator;
+use crate::integrator::sppm::SPPMIntegrator;
 use crate::integrator::Integrator;
-use crate::scene::Scene;
+use crate::sample::Frame;
+use crate::sampler::{
+    BlueNoiseSampler, HaltonSampler, IndependentSampler, PMJ02Sampler, Sampler, SobolSampler,
+    StratifiedSampler,
+};
+use crate::scene::light::Light;
+use crate::scene::material::{conductor_reflectance, roughness_to_exponent, Material};
+use crate::scene::shape::{Primitive, Shape, Sphere, Triangle, TriangleMesh};
+use crate::scene::{Scene, World};
+use crate::transform::Transform;
+use crate::vec::{Float, Vec3};
 
-pub fn parse_file(path: &str) -> Result<(Box<dyn Scene>, Box<dyn Integrator>), Box<dyn Error>> {
-    todo!()
+pub type Parsed = (Box<dyn Scene>, Box<dyn Integrator>);
+
+/// Reads a pbrt scene. `debug` replaces the integrator the file asks for
+/// with a `DebugIntegrator`.
+pub fn parse_file(path: &str, debug: Option<DebugMode>) -> Result<Parsed, Box<dyn Error>> {
+    let mut parser = Parser::new();
+    parser.parse_file(Path::new(path))?;
+    parser.finish(debug)
+}
+
+/// Reads a pbrt scene from `src`, which can't include other files: it may
+/// have come from another machine, which has no business reading ours.
+pub fn parse_source(src: &str, debug: Option<DebugMode>) -> Result<Parsed, Box<dyn Error>> {
+    let mut parser = Parser::new();
+    parser.parse_source(src, Path::new("<scene>"), None)?;
+    parser.finish(debug)
+}
+
+/// The scene at `path` as a single file, with everything it includes
+/// pasted in, for machines that can't see the same files.
+pub fn flatten_file(path: &str) -> Result<String, Box<dyn Error>> {
+    let mut out = String::new();
+    flatten(Path::new(path), &mut out)?;
+    Ok(out)
+}
+
+fn flatten(path: &Path, out: &mut String) -> Result<(), Box<dyn Error>> {
+    let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let tokens = tokenize(&src).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Ident(d) if d == "Include" || d == "Import" => match tokens.next() {
+                Some(Token::Str(file)) => flatten(&dir.join(file), out)?,
+                t => {
+                    let e = format!(
+                        "{}: {}: expected a string, found {:?}",
+                        path.display(),
+                        d,
+                        t
+                    );
+                    return Err(e.into());
+                }
+            },
+            Token::Ident(d) => {
+                out.push('\n');
+                out.push_str(&d);
+            }
+            Token::Str(s) => {
+                out.push_str(" \"");
+                for c in s.chars() {
+                    match c {
+                        '"' | '\\' => {
+                            out.push('\\');
+                            out.push(c);
+                        }
+                        '\n' => out.push_str("\\n"),
+                        '\t' => out.push_str("\\t"),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Token::Num(n) => out.push_str(&format!(" {}", n)),
+            Token::Open => out.push_str(" ["),
+            Token::Close => out.push_str(" ]"),
+        }
+    }
+    out.push('\n');
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+enum Token {
+    Str(String),
+    Num(Float),
+    Ident(String),
+    Open,
+    Close,
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = Vec::new();
+    let mut chars = src.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            for c in chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        } else if c == '[' {
+            chars.next();
+            tokens.push(Token::Open);
+        } else if c == ']' {
+            chars.next();
+            tokens.push(Token::Close);
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some(c) => s.push(c),
+                        None => return Err("unterminated string".into()),
+                    },
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated string".into()),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '[' || c == ']' || c == '"' || c == '#' {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            match s.parse::<Float>() {
+                Ok(v) => tokens.push(Token::Num(v)),
+                Err(_) => tokens.push(Token::Ident(s)),
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Num(Float),
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Clone, Debug)]
+struct Param {
+    ty: String,
+    name: String,
+    values: Vec<Value>,
+}
+
+/// The `"type name" [values]` list that follows most pbrt directives.
+#[derive(Clone, Debug, Default)]
+pub struct ParamSet {
+    params: Vec<Param>,
+}
+
+impl ParamSet {
+    fn find(&self, name: &str) -> Option<&Param> {
+        self.params.iter().find(|p| p.name == name)
+    }
+
+    pub fn floats(&self, name: &str) -> Option<Vec<Float>> {
+        let param = self.find(name)?;
+        Some(
+            param
+                .values
+                .iter()
+                .filter_map(|v| match v {
+                    Value::Num(n) => Some(*n),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+
+    pub fn float(&self, name: &str, default: Float) -> Float {
+        self.floats(name)
+            .and_then(|v| v.first().cloned())
+            .unwrap_or(default)
+    }
+
+    pub fn ints(&self, name: &str) -> Option<Vec<i64>> {
+        self.floats(name)
+            .map(|v| v.into_iter().map(|f| f as i64).collect())
+    }
+
+    pub fn int(&self, name: &str, default: i64) -> i64 {
+        self.ints(name)
+            .and_then(|v| v.first().cloned())
+            .unwrap_or(default)
+    }
+
+    pub fn string(&self, name: &str, default: &str) -> String {
+        match self.find(name).and_then(|p| p.values.first()) {
+            Some(Value::Str(s)) => s.clone(),
+            _ => default.to_string(),
+        }
+    }
+
+    pub fn bool(&self, name: &str, default: bool) -> bool {
+        match self.find(name).and_then(|p| p.values.first()) {
+            Some(Value::Bool(b)) => *b,
+            Some(Value::Str(s)) => s == "true",
+            _ => default,
+        }
+    }
+
+    /// A colour parameter. Only RGB values are understood; anything else
+    /// (spectra, blackbodies, textures) falls back to `default`.
+    pub fn rgb(&self, name: &str, default: Vec3) -> Vec3 {
+        let param = match self.find(name) {
+            Some(p) => p,
+            None => return default,
+        };
+        match param.ty.as_str() {
+            "rgb" | "color" => match self.floats(name).as_deref() {
+                Some([r, g, b, ..]) => Vec3::new(*r, *g, *b),
+                _ => default,
+            },
+            ty => {
+                eprintln!(
+                    "warning: \"{} {}\" is not supported, using default",
+                    ty, name
+                );
+                default
+            }
+        }
+    }
+
+    pub fn points(&self, name: &str) -> Option<Vec<Vec3>> {
+        let v = self.floats(name)?;
+        Some(
+            v.chunks_exact(3)
+                .map(|p| Vec3::new(p[0], p[1], p[2]))
+                .collect(),
+        )
+    }
+
+    pub fn point(&self, name: &str, default: Vec3) -> Vec3 {
+        self.points(name)
+            .and_then(|p| p.into_iter().next())
+            .unwrap_or(default)
+    }
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    ctm: Transform,
+    material: Arc<Material>,
+    /// Emission, two-sidedness and sample count of the current AreaLightSource.
+    area_light: Option<(Vec3, bool, u32)>,
+}
+
+struct Parser {
+    state: GraphicsState,
+    stack: Vec<GraphicsState>,
+    named_materials: HashMap<String, Arc<Material>>,
+    coordinate_systems: HashMap<String, Transform>,
+
+    camera: Option<(ParamSet, Transform)>,
+    film: ParamSet,
+    sampler: (String, ParamSet),
+    integrator: (String, ParamSet),
+
+    primitives: Vec<Primitive>,
+    lights: Vec<Light>,
+    /// Each light's "nsamples", for the direct lighting integrator.
+    light_samples: Vec<u32>,
+}
+
+struct Tokens {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Where included files are looked for, if they're allowed.
+    dir: Option<PathBuf>,
+}
+
+impl Tokens {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn num(&mut self, directive: &str) -> Result<Float, Box<dyn Error>> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(n),
+            t => Err(format!("{}: expected a number, found {:?}", directive, t).into()),
+        }
+    }
+
+    fn nums(&mut self, directive: &str, n: usize) -> Result<Vec<Float>, Box<dyn Error>> {
+        (0..n).map(|_| self.num(directive)).collect()
+    }
+
+    /// Numbers, optionally wrapped in brackets (`Transform [ ... ]`).
+    fn bracketed_nums(&mut self, directive: &str, n: usize) -> Result<Vec<Float>, Box<dyn Error>> {
+        if let Some(Token::Open) = self.peek() {
+            self.next();
+            let v = self.nums(directive, n)?;
+            match self.next() {
+                Some(Token::Close) => Ok(v),
+                t => Err(format!("{}: expected ], found {:?}", directive, t).into()),
+            }
+        } else {
+            self.nums(directive, n)
+        }
+    }
+
+    fn string(&mut self, directive: &str) -> Result<String, Box<dyn Error>> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s),
+            t => Err(format!("{}: expected a string, found {:?}", directive, t).into()),
+        }
+    }
+
+    fn params(&mut self, directive: &str) -> Result<ParamSet, Box<dyn Error>> {
+        let mut params = Vec::new();
+
+        while let Some(Token::Str(decl)) = self.peek().cloned() {
+            self.next();
+            let mut words = decl.split_whitespace();
+            let (ty, name) = match (words.next(), words.next()) {
+                (Some(ty), Some(name)) => (ty.to_string(), name.to_string()),
+                _ => return Err(format!("{}: bad parameter \"{}\"", directive, decl).into()),
+            };
+
+            let mut values = Vec::new();
+            let mut push = |t: Token| -> Result<(), Box<dyn Error>> {
+                values.push(match t {
+                    Token::Num(n) => Value::Num(n),
+                    Token::Str(s) if ty == "bool" => Value::Bool(s == "true"),
+                    Token::Str(s) => Value::Str(s),
+                    Token::Ident(s) if s == "true" || s == "false" => Value::Bool(s == "true"),
+                    t => {
+                        return Err(
+                            format!("{}: bad value {:?} for \"{}\"", directive, t, decl).into()
+                        )
+                    }
+                });
+                Ok(())
+            };
+
+            match self.next() {
+                Some(Token::Open) => loop {
+                    match self.next() {
+                        Some(Token::Close) => break,
+                        Some(t) => push(t)?,
+                        None => return Err(format!("{}: unterminated [", directive).into()),
+                    }
+                },
+                Some(t) => push(t)?,
+                None => return Err(format!("{}: missing value for \"{}\"", directive, decl).into()),
+            }
+
+            params.push(Param { ty, name, values });
+        }
+
+        Ok(ParamSet { params })
+    }
+}
+
+impl Parser {
+    fn new() -> Parser {
+        Parser {
+            state: GraphicsState {
+                ctm: Transform::identity(),
+                material: Arc::new(Material::Lambertian(Vec3::new(0.5, 0.5, 0.5))),
+                area_light: None,
+            },
+            stack: Vec::new(),
+            named_materials: HashMap::new(),
+            coordinate_systems: HashMap::new(),
+            camera: None,
+            film: ParamSet::default(),
+            sampler: ("halton".to_string(), ParamSet::default()),
+            integrator: ("path".to_string(), ParamSet::default()),
+            primitives: Vec::new(),
+            lights: Vec::new(),
+            light_samples: Vec::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let src = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.parse_source(&src, path, Some(dir))
+    }
+
+    /// Parses `src`, as if read from `path`, with includes relative to
+    /// `dir`, or refused if there's none.
+    fn parse_source(
+        &mut self,
+        src: &str,
+        path: &Path,
+        dir: Option<PathBuf>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut tokens = Tokens {
+            tokens: tokenize(src).map_err(|e| format!("{}: {}", path.display(), e))?,
+            pos: 0,
+            dir,
+        };
+        self.parse_tokens(&mut tokens)
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn parse_tokens(&mut self, t: &mut Tokens) -> Result<(), Box<dyn Error>> {
+        while let Some(token) = t.next() {
+            let directive = match token {
+                Token::Ident(d) => d,
+                other => return Err(format!("expected a directive, found {:?}", other).into()),
+            };
+            let d = directive.as_str();
+
+            match d {
+                "Identity" => self.state.ctm = Transform::identity(),
+                "Translate" => {
+                    let v = t.nums(d, 3)?;
+                    self.apply(&Transform::translate(&Vec3::new(v[0], v[1], v[2])));
+                }
+                "Scale" => {
+                    let v = t.nums(d, 3)?;
+                    self.apply(&Transform::scale(v[0], v[1], v[2]));
+                }
+                "Rotate" => {
+                    let v = t.nums(d, 4)?;
+                    self.apply(&Transform::rotate(v[0], &Vec3::new(v[1], v[2], v[3])));
+                }
+                "LookAt" => {
+                    let v = t.nums(d, 9)?;
+                    let look_at = Transform::look_at(
+                        &Vec3::new(v[0], v[1], v[2]),
+                        &Vec3::new(v[3], v[4], v[5]),
+                        &Vec3::new(v[6], v[7], v[8]),
+                    )
+                    .ok_or("LookAt: up vector is parallel to the view direction")?;
+                    self.apply(&look_at);
+                }
+                "Transform" | "ConcatTransform" => {
+                    let v = t.bracketed_nums(d, 16)?;
+                    // pbrt matrices are given column by column.
+                    let mut m = [[0.0; 4]; 4];
+                    for (i, value) in v.iter().enumerate() {
+                        m[i % 4][i / 4] = *value;
+                    }
+                    let transform =
+                        Transform::new(m).ok_or_else(|| format!("{}: singular matrix", d))?;
+                    if d == "Transform" {
+                        self.state.ctm = transform;
+                    } else {
+                        self.apply(&transform);
+                    }
+                }
+                "CoordinateSystem" => {
+                    let name = t.string(d)?;
+                    self.coordinate_systems.insert(name, self.state.ctm.clone());
+                }
+                "CoordSysTransform" => {
+                    let name = t.string(d)?;
+                    match self.coordinate_systems.get(&name) {
+                        Some(ctm) => self.state.ctm = ctm.clone(),
+                        None => eprintln!("warning: unknown coordinate system \"{}\"", name),
+                    }
+                }
+                "ActiveTransform" => {
+                    t.next();
+                    eprintln!("warning: ActiveTransform is not supported");
+                }
+                "TransformTimes" => {
+                    t.nums(d, 2)?;
+                }
+                "ReverseOrientation" => {
+                    eprintln!("warning: ReverseOrientation is not supported");
+                }
+
+                "Camera" => {
+                    let ty = t.string(d)?;
+                    let params = t.params(d)?;
+                    if ty != "perspective" {
+                        return Err(format!("Camera: \"{}\" is not supported", ty).into());
+                    }
+                    let camera_to_world = self.state.ctm.inverse();
+                    self.coordinate_systems
+                        .insert("camera".to_string(), camera_to_world.clone());
+                    self.camera = Some((params, camera_to_world));
+                }
+                "Film" => {
+                    t.string(d)?;
+                    self.film = t.params(d)?;
+                }
+                "Sampler" => {
+                    let ty = t.string(d)?;
+                    self.sampler = (ty, t.params(d)?);
+                }
+                "Integrator" => {
+                    let ty = t.string(d)?;
+                    self.integrator = (ty, t.params(d)?);
+                }
+                "PixelFilter" | "Accelerator" | "ColorSpace" | "Option" | "MakeNamedMedium"
+                | "MediumInterface" => {
+                    // Accepted and ignored.
+                    while let Some(Token::Str(_)) = t.peek() {
+                        t.next();
+                    }
+                    t.params(d)?;
+                }
+
+                "WorldBegin" => {
+                    self.state.ctm = Transform::identity();
+                    self.coordinate_systems
+                        .insert("world".to_string(), Transform::identity());
+                }
+                "WorldEnd" => (),
+                "AttributeBegin" => self.stack.push(self.state.clone()),
+                "AttributeEnd" => {
+                    self.state = self.stack.pop().ok_or("unmatched AttributeEnd")?;
+                }
+                "TransformBegin" => self.stack.push(self.state.clone()),
+                "TransformEnd" => {
+                    let saved = self.stack.pop().ok_or("unmatched TransformEnd")?;
+                    self.state.ctm = saved.ctm;
+                }
+                "ObjectBegin" | "ObjectEnd" | "ObjectInstance" => {
+                    if d != "ObjectEnd" {
+                        t.string(d)?;
+                    }
+                    eprintln!("warning: {} is not supported", d);
+                }
+
+                "Include" | "Import" => {
+                    let file = t.string(d)?;
+                    let dir = t
+                        .dir
+                        .as_ref()
+                        .ok_or_else(|| format!("{}: this scene can't include other files", d))?;
+                    self.parse_file(&dir.join(file))?;
+                }
+
+                "Texture" => {
+                    let name = t.string(d)?;
+                    t.string(d)?;
+                    t.string(d)?;
+                    t.params(d)?;
+                    eprintln!("warning: texture \"{}\" is not supported", name);
+                }
+                "Material" => {
+                    let ty = t.string(d)?;
+                    let params = t.params(d)?;
+                    self.state.material = Arc::new(make_material(&ty, &params));
+                }
+                "MakeNamedMaterial" => {
+                    let name = t.string(d)?;
+                    let params = t.params(d)?;
+                    let ty = params.string("type", "matte");
+                    self.named_materials
+                        .insert(name, Arc::new(make_material(&ty, &params)));
+                }
+                "NamedMaterial" => {
+                    let name = t.string(d)?;
+                    match self.named_materials.get(&name) {
+                        Some(m) => self.state.material = m.clone(),
+                        None => {
+                            return Err(
+                                format!("NamedMaterial: unknown material \"{}\"", name).into()
+                            )
+                        }
+                    }
+                }
+
+                "LightSource" => {
+                    let ty = t.string(d)?;
+                    let params = t.params(d)?;
+                    self.light_source(&ty, &params);
+                }
+                "AreaLightSource" => {
+                    let ty = t.string(d)?;
+                    let params = t.params(d)?;
+                    if ty == "diffuse" {
+                        let scale = params.rgb("scale", Vec3::new(1.0, 1.0, 1.0));
+                        self.state.area_light = Some((
+                            params.rgb("L", Vec3::new(1.0, 1.0, 1.0)) * scale,
+                            params.bool("twosided", false),
+                            params.int("nsamples", 1).max(1) as u32,
+                        ));
+                    } else {
+                        eprintln!("warning: area light \"{}\" is not supported", ty);
+                    }
+                }
+                "Shape" => {
+                    let ty = t.string(d)?;
+                    let params = t.params(d)?;
+                    self.shape(&ty, &params)?;
+                }
+
+                other => return Err(format!("unknown directive {}", other).into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, t: &Transform) {
+        self.state.ctm = self.state.ctm.compose(t);
+    }
+
+    fn light_source(&mut self, ty: &str, params: &ParamSet) {
+        let ctm = &self.state.ctm;
+        let scale = params.rgb("scale", Vec3::new(1.0, 1.0, 1.0));
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+
+        let light = match ty {
+            "point" => Light::Point {
+                position: ctm.point(&params.point("from", origin)),
+                intensity: params.rgb("I", Vec3::new(1.0, 1.0, 1.0)) * scale,
+            },
+            "spot" => {
+                let from = ctm.point(&params.point("from", origin));
+                let to = ctm.point(&params.point("to", Vec3::new(0.0, 0.0, 1.0)));
+                let cone = params.float("coneangle", 30.0);
+                let delta = params.float("conedeltaangle", 5.0);
+                Light::Spot {
+                    frame: Frame::new(&(to - &from).to_unit()),
+                    position: from,
+                    intensity: params.rgb("I", Vec3::new(1.0, 1.0, 1.0)) * scale,
+                    cos_total: cone.to_radians().cos(),
+                    cos_falloff: (cone - delta).to_radians().cos(),
+                }
+            }
+            "distant" => {
+                let from = params.point("from", origin.clone());
+                let to = params.point("to", Vec3::new(0.0, 0.0, 1.0));
+                Light::Distant {
+                    direction: ctm.vector(&(from - to)).to_unit(),
+                    radiance: params.rgb("L", Vec3::new(1.0, 1.0, 1.0)) * scale,
+                    world_center: origin,
+                    world_radius: 0.0,
+                }
+            }
+            "infinite" => {
+                if params.find("mapname").is_some() {
+                    eprintln!("warning: environment maps are not supported, using a constant");
+                }
+                Light::Infinite {
+                    radiance: params.rgb("L", Vec3::new(1.0, 1.0, 1.0)) * scale,
+                    world_center: origin,
+                    world_radius: 0.0,
+                }
+            }
+            other => {
+                eprintln!("warning: light \"{}\" is not supported", other);
+                return;
+            }
+        };
+
+        self.lights.push(light);
+        self.light_samples
+            .push(params.int("nsamples", 1).max(1) as u32);
+    }
+
+    fn shape(&mut self, ty: &str, params: &ParamSet) -> Result<(), Box<dyn Error>> {
+        let ctm = &self.state.ctm;
+        match ty {
+            "sphere" => {
+                // Non-uniform scales turn spheres into ellipsoids, which we
+                // can't represent; use the average scale factor.
+                let scale = ctm.determinant().abs().cbrt();
+                let sphere = Sphere {
+                    center: ctm.point(&Vec3::new(0.0, 0.0, 0.0)),
+                    radius: params.float("radius", 1.0) * scale,
+                };
+                self.primitive(Shape::Sphere(sphere));
+            }
+            "trianglemesh" => {
+                let mesh = Arc::new(triangle_mesh(params, ctm)?);
+                for index in 0..mesh.indices.len() {
+                    self.primitive(Shape::Triangle(Triangle {
+                        mesh: mesh.clone(),
+                        index,
+                    }));
+                }
+            }
+            other => eprintln!("warning: shape \"{}\" is not supported", other),
+        }
+        Ok(())
+    }
+
+    /// Adds `shape` made of the current material, and an area light for it
+    /// if there's one in effect.
+    fn primitive(&mut self, shape: Shape) {
+        let light = match &self.state.area_light {
+            Some((emission, two_sided, samples)) => {
+                self.lights.push(Light::Area {
+                    shape: shape.clone(),
+                    emission: emission.clone(),
+                    two_sided: *two_sided,
+                });
+                self.light_samples.push(*samples);
+                Some(self.lights.len() - 1)
+            }
+            None => None,
+        };
+
+        self.primitives.push(Primitive {
+            shape,
+            material: self.state.material.clone(),
+            light,
+            id: 0,
+            material_id: 0,
+        });
+    }
+
+    fn finish(self, debug: Option<DebugMode>) -> Result<Parsed, Box<dyn Error>> {
+        let (camera_params, camera_to_world) = self
+            .camera
+            .unwrap_or_else(|| (ParamSet::default(), Transform::identity()));
+
+        let width = self.film.int("xresolution", 640).max(1) as usize;
+        let height = self.film.int("yresolution", 480).max(1) as usize;
+        let camera = Camera::new(
+            camera_to_world,
+            width,
+            height,
+            camera_params.float("fov", 90.0),
+            camera_params.float("lensradius", 0.0),
+            camera_params.float("focaldistance", 1e6),
+        );
+
+        let sampler = make_sampler(&self.sampler.0, &self.sampler.1);
+
+        let (name, params) = &self.integrator;
+        let integrator: Box<dyn Integrator> = if let Some(mode) = debug {
+            Box::new(DebugIntegrator::new(camera, sampler, mode))
+        } else {
+            match name.as_str() {
+                "path" => Box::new(PathIntegrator::from_params(params, camera, sampler)?),
+                "ambientocclusion" => Box::new(AOIntegrator::from_params(params, camera, sampler)?),
+                "bdpt" => Box::new(BDPTIntegrator::from_params(params, camera, sampler)?),
+                "directlighting" => Box::new(DirectLightingIntegrator::from_params(
+                    params,
+                    camera,
+                    sampler,
+                    self.light_samples,
+                )?),
+                "lightpath" => Box::new(LightPathIntegrator::from_params(params, camera, sampler)?),
+                "mlt" => Box::new(MLTIntegrator::from_params(params, camera)?),
+                "sppm" => Box::new(SPPMIntegrator::from_params(params, camera)?),
+                other => return Err(format!("Integrator \"{}\" is not supported", other).into()),
+            }
+        };
+
+        let scene: Box<dyn Scene> = Box::new(World::new(self.primitives, self.lights));
+        Ok((scene, integrator))
+    }
+}
+
+/// A "trianglemesh" shape's mesh, moved to world space by `ctm`.
+fn triangle_mesh(params: &ParamSet, ctm: &Transform) -> Result<TriangleMesh, Box<dyn Error>> {
+    let positions = params.points("P").ok_or("trianglemesh: \"P\" is missing")?;
+    let indices = match params.ints("indices") {
+        Some(indices) => indices,
+        None if positions.len() == 3 => vec![0, 1, 2],
+        None => return Err("trianglemesh: \"indices\" is missing".into()),
+    };
+    if indices.len() % 3 != 0 {
+        return Err(format!(
+            "trianglemesh: {} indices, which isn't a multiple of 3",
+            indices.len()
+        )
+        .into());
+    }
+    if let Some(i) = indices
+        .iter()
+        .find(|&&i| i < 0 || i as usize >= positions.len())
+    {
+        return Err(format!(
+            "trianglemesh: index {} is out of range for {} vertices",
+            i,
+            positions.len()
+        )
+        .into());
+    }
+
+    // Per-vertex data that doesn't match the vertices is ignored, as pbrt
+    // does.
+    let per_vertex = |name: &str, values: Option<Vec<Vec3>>| match values {
+        Some(v) if v.len() != positions.len() => {
+            eprintln!(
+                "warning: trianglemesh: {} \"{}\" values for {} vertices, ignoring them",
+                v.len(),
+                name,
+                positions.len()
+            );
+            None
+        }
+        v => v,
+    };
+    let normals = per_vertex("N", params.points("N"))
+        .map(|n| n.iter().map(|n| ctm.normal(n).to_unit()).collect());
+    let tangents =
+        per_vertex("S", params.points("S")).map(|s| s.iter().map(|s| ctm.vector(s)).collect());
+    let uvs = params
+        .floats("uv")
+        .or_else(|| params.floats("st"))
+        .and_then(|uv| {
+            if uv.len() == 2 * positions.len() {
+                Some(uv.chunks_exact(2).map(|uv| (uv[0], uv[1])).collect())
+            } else {
+                eprintln!(
+                    "warning: trianglemesh: {} \"uv\" values for {} vertices, ignoring them",
+                    uv.len() / 2,
+                    positions.len()
+                );
+                None
+            }
+        });
+
+    Ok(TriangleMesh {
+        indices: indices
+            .chunks_exact(3)
+            .map(|i| [i[0] as usize, i[1] as usize, i[2] as usize])
+            .collect(),
+        positions: positions.iter().map(|p| ctm.point(p)).collect(),
+        normals,
+        tangents,
+        uvs,
+        // A transform that swaps handedness turns the winding around.
+        reverse_orientation: ctm.determinant() < 0.0,
+    })
+}
+
+fn make_sampler(ty: &str, params: &ParamSet) -> Box<dyn Sampler> {
+    let samples = params.int("pixelsamples", 16).max(1) as u32;
+    match ty {
+        "random" | "independent" => Box::new(IndependentSampler::new(samples)),
+        "stratified" => Box::new(StratifiedSampler::from_params(params)),
+        "halton" => Box::new(HaltonSampler::new(samples)),
+        "sobol" | "zsobol" | "paddedsobol" => Box::new(SobolSampler::new(samples)),
+        // Without the blue noise; see `PMJ02Sampler`.
+        "pmj02" | "pmj02bn" => Box::new(PMJ02Sampler::new(samples)),
+        "bluenoise" => Box::new(BlueNoiseSampler::new(samples)),
+        other => {
+            eprintln!(
+                "warning: sampler \"{}\" is not supported, using halton",
+                other
+            );
+            Box::new(HaltonSampler::new(samples))
+        }
+    }
+}
+
+fn make_material(ty: &str, params: &ParamSet) -> Material {
+    let grey = |v: Float| Vec3::new(v, v, v);
+
+    match ty {
+        "matte" => Material::Lambertian(params.rgb("Kd", grey(0.5))),
+        "mirror" => Material::Mirror(params.rgb("Kr", grey(0.9))),
+        "glass" => Material::Dielectric(
+            params.rgb("Kr", grey(1.0)),
+            params.rgb("Kt", grey(1.0)),
+            params.float("eta", params.float("index", 1.5)),
+        ),
+        "metal" => {
+            // pbrt's default is copper.
+            let eta = params.rgb("eta", Vec3::new(0.2004, 0.9240, 1.1022));
+            let k = params.rgb("k", Vec3::new(3.9129, 2.4528, 2.1421));
+            Material::Metal(
+                conductor_reflectance(&eta, &k),
+                roughness_to_exponent(params.float("roughness", 0.01)),
+            )
+        }
+        "plastic" | "substrate" | "uber" => Material::Plastic(
+            params.rgb("Kd", grey(0.25)),
+            params.rgb("Ks", grey(0.25)),
+            roughness_to_exponent(params.float("roughness", 0.1)),
+        ),
+        other => {
+            eprintln!(
+                "warning: material \"{}\" is not supported, using matte",
+                other
+            );
+            Material::Lambertian(grey(0.5))
+        }
+    }
 }
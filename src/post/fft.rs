@@ -0,0 +1,119 @@
+use crate::vec::Float;
+
+use rayon::prelude::*;
+
+use std::f32::consts::PI;
+use std::ops;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Complex {
+    pub re: Float,
+    pub im: Float,
+}
+
+impl Complex {
+    pub const ZERO: Complex = Complex { re: 0.0, im: 0.0 };
+
+    pub const fn new(re: Float, im: Float) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn norm_sqr(self) -> Float {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+impl ops::Add for Complex {
+    type Output = Complex;
+
+    fn add(self, b: Complex) -> Complex {
+        Complex::new(self.re + b.re, self.im + b.im)
+    }
+}
+
+impl ops::Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, b: Complex) -> Complex {
+        Complex::new(self.re - b.re, self.im - b.im)
+    }
+}
+
+impl ops::Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, b: Complex) -> Complex {
+        Complex::new(
+            self.re * b.re - self.im * b.im,
+            self.re * b.im + self.im * b.re,
+        )
+    }
+}
+
+/// In-place iterative radix-2 FFT. `data.len()` must be a power of two.
+/// The inverse transform is scaled by `1 / n`.
+pub fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    assert!(n.is_power_of_two());
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as Float;
+        let step = Complex::new(angle.cos(), angle.sin());
+        for chunk in data.chunks_mut(len) {
+            let mut w = Complex::new(1.0, 0.0);
+            let (lo, hi) = chunk.split_at_mut(len / 2);
+            for (a, b) in lo.iter_mut().zip(hi.iter_mut()) {
+                let t = w * *b;
+                *b = *a - t;
+                *a = *a + t;
+                w = w * step;
+            }
+        }
+        len <<= 1;
+    }
+
+    if inverse {
+        let scale = 1.0 / n as Float;
+        for v in data.iter_mut() {
+            v.re *= scale;
+            v.im *= scale;
+        }
+    }
+}
+
+/// 2D FFT of a row-major `width` x `height` grid; both must be powers of two.
+pub fn fft2d(data: &mut [Complex], width: usize, height: usize, inverse: bool) {
+    data.par_chunks_mut(width).for_each(|row| fft(row, inverse));
+
+    let mut columns = transpose(data, width, height);
+    columns
+        .par_chunks_mut(height)
+        .for_each(|column| fft(column, inverse));
+
+    data.copy_from_slice(&transpose(&columns, height, width));
+}
+
+fn transpose(data: &[Complex], width: usize, height: usize) -> Vec<Complex> {
+    let mut out = vec![Complex::ZERO; data.len()];
+    for y in 0..height {
+        for x in 0..width {
+            out[x * height + y] = data[y * width + x];
+        }
+    }
+    out
+}
@@ -0,0 +1,335 @@
+mod fft;
+
+use crate::image::Image;
+use crate::vec::{Float, Vec3};
+
+use fft::{fft2d, Complex};
+
+use rayon::prelude::*;
+
+use std::error::Error;
+use std::f32::consts::PI;
+
+// Resolution of the aperture grid used to compute the diffraction pattern.
+const APERTURE_SIZE: usize = 256;
+
+// Wavelengths (nm) the red, green and blue channels are treated as. The
+// diffraction pattern scales linearly with wavelength.
+const WAVELENGTHS: [Float; 3] = [650.0, 550.0, 450.0];
+
+/// Shape of the lens aperture, which determines the glare pattern.
+pub enum Aperture {
+    /// A regular polygon with this many blades; fewer than three is a circle.
+    Blades(u32),
+    /// Transmission mask, read from the luminance of an image.
+    Mask(Image),
+}
+
+impl Aperture {
+    /// Parses the `--glare` argument: either a blade count or an image file.
+    pub fn from_arg(arg: &str) -> Result<Aperture, Box<dyn Error>> {
+        match arg.parse::<u32>() {
+            Ok(blades) => Ok(Aperture::Blades(blades)),
+            Err(_) => Ok(Aperture::Mask(Image::read_ppm(arg)?)),
+        }
+    }
+
+    /// Transmission of the aperture on an `n` x `n` grid. The opening
+    /// covers the middle quarter of the grid so the far field is sampled
+    /// finely enough to show the diffraction spikes.
+    fn grid(&self, n: usize) -> Vec<Float> {
+        let radius = n as Float / 8.0;
+        let centre = n as Float / 2.0;
+
+        let mut grid = vec![0.0; n * n];
+        for y in 0..n {
+            for x in 0..n {
+                let dx = (x as Float + 0.5 - centre) / radius;
+                let dy = (y as Float + 0.5 - centre) / radius;
+                grid[y * n + x] = match self {
+                    Aperture::Blades(blades) => {
+                        let r = (dx * dx + dy * dy).sqrt();
+                        if *blades < 3 {
+                            if r <= 1.0 {
+                                1.0
+                            } else {
+                                0.0
+                            }
+                        } else {
+                            // Distance to the nearest edge of the polygon,
+                            // measured along that edge's normal.
+                            let wedge = 2.0 * PI / *blades as Float;
+                            let angle = dy.atan2(dx).rem_euclid(wedge) - wedge / 2.0;
+                            if r * angle.cos() <= (wedge / 2.0).cos() {
+                                1.0
+                            } else {
+                                0.0
+                            }
+                        }
+                    }
+                    Aperture::Mask(mask) => {
+                        if dx.abs() > 1.0 || dy.abs() > 1.0 {
+                            0.0
+                        } else {
+                            let mx = ((dx + 1.0) / 2.0 * mask.width() as Float) as usize;
+                            let my = ((dy + 1.0) / 2.0 * mask.height() as Float) as usize;
+                            let p =
+                                &mask.pixels[my.min(mask.height() - 1)][mx.min(mask.width() - 1)];
+                            0.2126 * p.x + 0.7152 * p.y + 0.0722 * p.z
+                        }
+                    }
+                };
+            }
+        }
+        grid
+    }
+
+    /// Fraunhofer diffraction pattern of the aperture: the squared magnitude
+    /// of its Fourier transform, shifted so the centre is at `(n/2, n/2)`.
+    fn psf(&self, n: usize) -> Vec<Float> {
+        let mut data: Vec<Complex> = self
+            .grid(n)
+            .into_iter()
+            .map(|t| Complex::new(t, 0.0))
+            .collect();
+        fft2d(&mut data, n, n, false);
+
+        let mut psf = vec![0.0; n * n];
+        for y in 0..n {
+            for x in 0..n {
+                psf[((y + n / 2) % n) * n + (x + n / 2) % n] = data[y * n + x].norm_sqr();
+            }
+        }
+        psf
+    }
+}
+
+/// One stage of the post-processing chain. Everything runs on linear
+/// radiance, before the image is clamped and gamma corrected for output.
+pub enum Effect {
+    /// Blurs everything brighter than `threshold` and adds it back.
+    Bloom {
+        threshold: Float,
+        strength: Float,
+        radius: Float,
+    },
+    /// Spreads everything brighter than `threshold` with the diffraction
+    /// pattern of the aperture. Energy is moved, not added.
+    Glare {
+        aperture: Aperture,
+        threshold: Float,
+        strength: Float,
+    },
+    /// Natural cos^4 falloff towards the corners. `strength` is the tangent
+    /// of the angle subtended by the image corner.
+    Vignette { strength: Float },
+}
+
+impl Effect {
+    pub fn apply(&self, image: &Image) -> Image {
+        match self {
+            Effect::Bloom {
+                threshold,
+                strength,
+                radius,
+            } => bloom(image, *threshold, *strength, *radius),
+            Effect::Glare {
+                aperture,
+                threshold,
+                strength,
+            } => glare(image, aperture, *threshold, *strength),
+            Effect::Vignette { strength } => vignette(image, *strength),
+        }
+    }
+}
+
+/// Runs each effect in order.
+pub fn apply_all(effects: &[Effect], image: Image) -> Image {
+    effects
+        .iter()
+        .fold(image, |image, effect| effect.apply(&image))
+}
+
+fn highlights(image: &Image, threshold: Float) -> Vec<Vec<Vec3>> {
+    image
+        .pixels
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|p| {
+                    Vec3::new(
+                        (p.x - threshold).max(0.0),
+                        (p.y - threshold).max(0.0),
+                        (p.z - threshold).max(0.0),
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn bloom(image: &Image, threshold: Float, strength: Float, radius: Float) -> Image {
+    let (width, height) = (image.width(), image.height());
+    let sigma = radius.max(0.5);
+    let extent = (3.0 * sigma).ceil() as isize;
+    let kernel: Vec<Float> = (-extent..=extent)
+        .map(|i| (-((i * i) as Float) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: Float = kernel.iter().sum();
+
+    let blur = |src: &Vec<Vec<Vec3>>, horizontal: bool| -> Vec<Vec<Vec3>> {
+        (0..height)
+            .into_par_iter()
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let mut sum = Vec3::new(0.0, 0.0, 0.0);
+                        for (i, k) in (-extent..=extent).zip(&kernel) {
+                            let (sx, sy) = if horizontal {
+                                ((x as isize + i).clamp(0, width as isize - 1), y as isize)
+                            } else {
+                                (x as isize, (y as isize + i).clamp(0, height as isize - 1))
+                            };
+                            sum += &src[sy as usize][sx as usize] * *k;
+                        }
+                        sum / total
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+
+    let blurred = blur(&blur(&highlights(image, threshold), true), false);
+
+    let pixels = image
+        .pixels
+        .iter()
+        .zip(blurred)
+        .map(|(row, brow)| {
+            row.iter()
+                .zip(brow)
+                .map(|(p, b)| p + b * strength)
+                .collect()
+        })
+        .collect();
+
+    image.with_pixels(pixels)
+}
+
+fn glare(image: &Image, aperture: &Aperture, threshold: Float, strength: Float) -> Image {
+    let (width, height) = (image.width(), image.height());
+    let n = APERTURE_SIZE;
+    let psf = aperture.psf(n);
+
+    let pw = (width + n).next_power_of_two();
+    let ph = (height + n).next_power_of_two();
+    let bright = highlights(image, threshold);
+
+    let mut spread = vec![vec![Vec3::new(0.0, 0.0, 0.0); width]; height];
+
+    for (channel, wavelength) in WAVELENGTHS.iter().enumerate() {
+        let get = |v: &Vec3| match channel {
+            0 => v.x,
+            1 => v.y,
+            _ => v.z,
+        };
+
+        // The kernel is the PSF rescaled for this channel's wavelength,
+        // wrapped around so its centre sits at the origin.
+        let scale = WAVELENGTHS[1] / wavelength;
+        let half = (n / 2) as isize;
+        let mut kernel = vec![Complex::ZERO; pw * ph];
+        let mut total = 0.0;
+        for dy in -half..half {
+            for dx in -half..half {
+                let value = sample_bilinear(
+                    &psf,
+                    n,
+                    dx as Float * scale + half as Float,
+                    dy as Float * scale + half as Float,
+                );
+                let kx = dx.rem_euclid(pw as isize) as usize;
+                let ky = dy.rem_euclid(ph as isize) as usize;
+                kernel[ky * pw + kx] = Complex::new(value, 0.0);
+                total += value;
+            }
+        }
+        if total <= 0.0 {
+            continue;
+        }
+        for k in kernel.iter_mut() {
+            k.re /= total;
+        }
+
+        let mut signal = vec![Complex::ZERO; pw * ph];
+        for y in 0..height {
+            for x in 0..width {
+                signal[y * pw + x] = Complex::new(get(&bright[y][x]), 0.0);
+            }
+        }
+
+        fft2d(&mut kernel, pw, ph, false);
+        fft2d(&mut signal, pw, ph, false);
+        for (s, k) in signal.iter_mut().zip(&kernel) {
+            *s = *s * *k;
+        }
+        fft2d(&mut signal, pw, ph, true);
+
+        for y in 0..height {
+            for x in 0..width {
+                let value = signal[y * pw + x].re.max(0.0);
+                match channel {
+                    0 => spread[y][x].x = value,
+                    1 => spread[y][x].y = value,
+                    _ => spread[y][x].z = value,
+                }
+            }
+        }
+    }
+
+    let pixels = (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| &image.pixels[y][x] + (&spread[y][x] - &bright[y][x]) * strength)
+                .collect()
+        })
+        .collect();
+
+    image.with_pixels(pixels)
+}
+
+fn sample_bilinear(data: &[Float], n: usize, x: Float, y: Float) -> Float {
+    if x < 0.0 || y < 0.0 || x >= (n - 1) as Float || y >= (n - 1) as Float {
+        return 0.0;
+    }
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (fx, fy) = (x - x0 as Float, y - y0 as Float);
+    let at = |x: usize, y: usize| data[y * n + x];
+    (1.0 - fy) * ((1.0 - fx) * at(x0, y0) + fx * at(x0 + 1, y0))
+        + fy * ((1.0 - fx) * at(x0, y0 + 1) + fx * at(x0 + 1, y0 + 1))
+}
+
+fn vignette(image: &Image, strength: Float) -> Image {
+    let (width, height) = (image.width() as Float, image.height() as Float);
+    let half_diagonal = (width * width + height * height).sqrt() / 2.0;
+
+    let pixels = image
+        .pixels
+        .iter()
+        .enumerate()
+        .map(|(y, row)| {
+            row.iter()
+                .enumerate()
+                .map(|(x, p)| {
+                    let dx = x as Float + 0.5 - width / 2.0;
+                    let dy = y as Float + 0.5 - height / 2.0;
+                    let tan = strength * (dx * dx + dy * dy).sqrt() / half_diagonal;
+                    // cos^4(atan(t)) = 1 / (1 + t^2)^2
+                    p / (1.0 + tan * tan).powi(2)
+                })
+                .collect()
+        })
+        .collect();
+
+    image.with_pixels(pixels)
+}
@@ -0,0 +1,57 @@
+use crate::image::Image;
+use crate::vec::{Float, Vec3};
+
+/// Draws `image` `columns` characters wide (fewer, if it's narrower than
+/// that), two pixels to a character: the upper half block in the top
+/// pixel's colour, on the bottom pixel's. Each line ends with a newline,
+/// and the colours are reset before it.
+pub fn half_blocks(image: &Image, columns: usize) -> String {
+    let (width, height) = (image.width(), image.height());
+    if width == 0 || height == 0 {
+        return String::new();
+    }
+    let columns = columns.clamp(1, width);
+    // Characters are about twice as tall as they're wide, so this keeps the
+    // image's proportions.
+    let rows = ((height * columns) as f64 / width as f64).round().max(1.0) as usize;
+
+    let mut out = String::new();
+    for y in (0..rows).step_by(2) {
+        for x in 0..columns {
+            let (r, g, b) = rgb(&average(image, columns, rows, x, y));
+            out += &format!("\x1b[38;2;{};{};{}m", r, g, b);
+            if y + 1 < rows {
+                let (r, g, b) = rgb(&average(image, columns, rows, x, y + 1));
+                out += &format!("\x1b[48;2;{};{};{}m", r, g, b);
+            } else {
+                out += "\x1b[49m";
+            }
+            out.push('▀');
+        }
+        out += "\x1b[0m\x1b[K\n";
+    }
+    out
+}
+
+/// The mean of the pixels that cell `(x, y)` of a `columns` by `rows` grid
+/// covers.
+fn average(image: &Image, columns: usize, rows: usize, x: usize, y: usize) -> Vec3 {
+    // There are no more cells than pixels either way, so none is empty.
+    let (width, height) = (image.width(), image.height());
+    let (x0, x1) = (x * width / columns, (x + 1) * width / columns);
+    let (y0, y1) = (y * height / rows, (y + 1) * height / rows);
+
+    let mut sum = Vec3::new(0.0, 0.0, 0.0);
+    for row in &image.pixels[y0..y1] {
+        for pixel in &row[x0..x1] {
+            sum = &sum + pixel;
+        }
+    }
+    &sum / ((x1 - x0) * (y1 - y0)) as Float
+}
+
+/// With the same gamma as `Image::write_to`.
+fn rgb(colour: &Vec3) -> (u8, u8, u8) {
+    let c = |v: Float| (v.clamp(0.0, 1.0).sqrt() * 255.99).floor() as u8;
+    (c(colour.x), c(colour.y), c(colour.z))
+}
@@ -0,0 +1,144 @@
+use crate::image::Image;
+use crate::scene::take_ray_count;
+use crate::tiles::Tile;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Watches a render, e.g. to draw a progress bar or a preview, and can stop
+/// it. Tiles finish on the render threads, so everything takes `&self`.
+pub trait Observer: Sync {
+    /// A tile of the pass in progress is finished. Integrators that don't
+    /// render in tiles report the other parts of their passes as they
+    /// finish, each as `Tile::whole` of the image.
+    fn tile_done(&self, _tile: &Tile, _status: &Status) {}
+
+    /// The image so far, every `Progressive::snapshot_interval`.
+    fn snapshot(&self, _image: Image) {}
+
+    /// Checked before every tile, or other part of a pass. Once it's true, the render stops as soon
+    /// as it can and returns the image so far.
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Ignores everything, and never cancels.
+impl Observer for () {}
+
+/// How a render is going.
+#[derive(Clone, Debug)]
+pub struct Status {
+    /// The pass in progress, counting from 0.
+    pub pass: u32,
+    /// Tiles of the pass finished so far, out of `tiles`.
+    pub tiles_done: usize,
+    pub tiles: usize,
+    /// Fraction of the render done, and the time left, if there's any way
+    /// to tell.
+    pub fraction: Option<f64>,
+    pub eta: Option<Duration>,
+    pub elapsed: Duration,
+    pub rays_per_second: f64,
+}
+
+/// Works out the `Status` of a progressive render for its observer.
+pub struct Tracker<'a> {
+    observer: &'a dyn Observer,
+    start: Instant,
+    /// Time spent before the render was resumed from a checkpoint.
+    earlier: Duration,
+    first_pass: u32,
+    /// The pass the render will stop at, if it's known.
+    end: Option<u32>,
+    time_budget: Option<Duration>,
+    rays: AtomicU64,
+    /// The pass in progress, its tiles done and its tiles.
+    pass: Mutex<(u32, usize, usize)>,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(
+        observer: &'a dyn Observer,
+        earlier: Duration,
+        first_pass: u32,
+        end: Option<u32>,
+        time_budget: Option<Duration>,
+    ) -> Tracker<'a> {
+        Tracker {
+            observer,
+            start: Instant::now(),
+            earlier,
+            first_pass,
+            end,
+            time_budget,
+            rays: AtomicU64::new(0),
+            pass: Mutex::new((first_pass, 0, 0)),
+        }
+    }
+
+    pub fn observer(&self) -> &dyn Observer {
+        self.observer
+    }
+
+    /// Rendering time, including any before a resume.
+    pub fn elapsed(&self) -> Duration {
+        self.earlier + self.start.elapsed()
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.observer.cancelled()
+    }
+
+    pub fn start_pass(&self, pass: u32, tiles: usize) {
+        *self.pass.lock().unwrap() = (pass, 0, tiles);
+    }
+
+    /// Call on the thread that's about to render a tile, so that only its
+    /// rays are counted when it's done.
+    pub fn start_tile(&self) {
+        take_ray_count();
+    }
+
+    /// Call on the thread that rendered `tile`, as soon as it's done.
+    pub fn tile_done(&self, tile: &Tile) {
+        let traced = take_ray_count();
+        let rays = self.rays.fetch_add(traced, Ordering::Relaxed) + traced;
+        let (pass, tiles_done, tiles) = {
+            let mut state = self.pass.lock().unwrap();
+            state.1 += 1;
+            *state
+        };
+
+        let run_time = self.start.elapsed();
+        let seconds = run_time.as_secs_f64();
+        let passes = (pass - self.first_pass) as f64 + tiles_done as f64 / tiles.max(1) as f64;
+        let by_passes = self.end.map(|end| {
+            let fraction = passes / end.saturating_sub(self.first_pass).max(1) as f64;
+            let left = seconds * (1.0 - fraction) / fraction.max(1e-9);
+            (fraction.min(1.0), Duration::from_secs_f64(left.max(0.0)))
+        });
+        let by_time = self.time_budget.map(|budget| {
+            let elapsed = self.elapsed();
+            let fraction = elapsed.as_secs_f64() / budget.as_secs_f64().max(1e-9);
+            (fraction.min(1.0), budget.saturating_sub(elapsed))
+        });
+        // Whichever finishes the render first.
+        let estimate = match (by_passes, by_time) {
+            (Some(p), Some(t)) => Some(if p.1 < t.1 { p } else { t }),
+            (p, t) => p.or(t),
+        };
+
+        let status = Status {
+            pass,
+            tiles_done,
+            tiles,
+            fraction: estimate.map(|e| e.0),
+            eta: estimate.map(|e| e.1),
+            elapsed: self.elapsed(),
+            rays_per_second: rays as f64 / seconds.max(1e-9),
+        };
+        self.observer.tile_done(tile, &status);
+    }
+}
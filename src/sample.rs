@@ -0,0 +1,510 @@
+use crate::vec::*;
+
+use std::f32::consts::{FRAC_1_PI, FRAC_PI_2, FRAC_PI_4, PI};
+
+// Warps from the unit square to other domains. Each takes `u` uniform on
+// [0,1)^2 and has a `_pdf` function giving the density of what it returns,
+// with respect to area for points and to solid angle for directions. The
+// tests at the bottom check every pair against each other.
+
+/// Maps a uniform sample on [0,1)^2 to the unit disk, keeping strata
+/// contiguous (Shirley and Chiu's concentric mapping).
+pub fn concentric_disk(u: (Float, Float)) -> (Float, Float) {
+    let ox = 2.0 * u.0 - 1.0;
+    let oy = 2.0 * u.1 - 1.0;
+    if ox == 0.0 && oy == 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, FRAC_PI_4 * (oy / ox))
+    } else {
+        (oy, FRAC_PI_2 - FRAC_PI_4 * (ox / oy))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+pub fn concentric_disk_pdf() -> Float {
+    FRAC_1_PI
+}
+
+/// Cosine-weighted direction about +z.
+pub fn cosine_hemisphere(u: (Float, Float)) -> Vec3 {
+    let (x, y) = concentric_disk(u);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    Vec3::new(x, y, z)
+}
+
+/// `cos_theta` is the direction's z in the frame it was sampled in.
+pub fn cosine_hemisphere_pdf(cos_theta: Float) -> Float {
+    cos_theta.max(0.0) * FRAC_1_PI
+}
+
+/// Uniform direction about +z.
+pub fn uniform_hemisphere(u: (Float, Float)) -> Vec3 {
+    let z = u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_hemisphere_pdf() -> Float {
+    1.0 / (2.0 * PI)
+}
+
+/// Uniform direction on the whole sphere.
+pub fn uniform_sphere(u: (Float, Float)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+pub fn uniform_sphere_pdf() -> Float {
+    1.0 / (4.0 * PI)
+}
+
+/// Uniform direction in the cone of directions about +z whose angle to the
+/// axis has cosine at least `cos_max`.
+pub fn uniform_cone(u: (Float, Float), cos_max: Float) -> Vec3 {
+    let cos = (1.0 - u.0) + u.0 * cos_max;
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(phi.cos() * sin, phi.sin() * sin, cos)
+}
+
+pub fn uniform_cone_pdf(cos_max: Float) -> Float {
+    1.0 / (2.0 * PI * (1.0 - cos_max))
+}
+
+/// Barycentric coordinates of a uniformly distributed point in a
+/// triangle, by Heitz's low-distortion mapping, which keeps nearby
+/// samples nearby.
+pub fn uniform_triangle(u: (Float, Float)) -> [Float; 3] {
+    let (b0, b1) = if u.0 < u.1 {
+        let b0 = u.0 / 2.0;
+        (b0, u.1 - b0)
+    } else {
+        let b1 = u.1 / 2.0;
+        (u.0 - b1, b1)
+    };
+    [b0, b1, 1.0 - b0 - b1]
+}
+
+/// For the triangle `p0 p1 p2`.
+pub fn uniform_triangle_pdf(p0: &Vec3, p1: &Vec3, p2: &Vec3) -> Float {
+    2.0 / (p1 - p0).cross(&(p2 - p0)).norm()
+}
+
+/// Uniform direction in the spherical triangle with corners in unit
+/// directions `a`, `b` and `c` (Arvo, "Stratified Sampling of Spherical
+/// Triangles"), or `None` if it's too small to sample.
+pub fn uniform_spherical_triangle(a: &Vec3, b: &Vec3, c: &Vec3, u: (Float, Float)) -> Option<Vec3> {
+    let area = spherical_triangle_area(a, b, c);
+    if area < 1e-6 {
+        return None;
+    }
+
+    // The angle at each corner, between the great circles through it.
+    let n_ab = a.cross(b).to_unit();
+    let n_ca = c.cross(a).to_unit();
+    let angle = |n: &Vec3, m: &Vec3| (-(n % m)).clamp(-1.0, 1.0).acos();
+    let alpha = angle(&n_ab, &n_ca);
+    let cos_c = (a % b).clamp(-1.0, 1.0);
+
+    // Pick the sub-triangle a b c' with the right fraction of the area,
+    // then a point on the arc from b to c'.
+    let sub_area = u.0 * area;
+    let (s, t) = ((sub_area - alpha).sin(), (sub_area - alpha).cos());
+    let (uu, v) = (t - alpha.cos(), s + alpha.sin() * cos_c);
+    let q = ((v * t - uu * s) * alpha.cos() - v) / ((v * s + uu * t) * alpha.sin());
+    let q = q.clamp(-1.0, 1.0);
+    let c_prime = a * q + orthogonal(c, a) * (1.0 - q * q).max(0.0).sqrt();
+
+    let z = 1.0 - u.1 * (1.0 - (&c_prime % b));
+    let z = z.clamp(-1.0, 1.0);
+    Some(b * z + orthogonal(&c_prime, b) * (1.0 - z * z).max(0.0).sqrt())
+}
+
+/// The solid angle of the spherical triangle with corners in unit
+/// directions `a`, `b` and `c` (Van Oosterom and Strackee).
+pub fn spherical_triangle_area(a: &Vec3, b: &Vec3, c: &Vec3) -> Float {
+    let triple = (a % &b.cross(c)).abs();
+    let denominator = 1.0 + a % b + b % c + c % a;
+    2.0 * triple.atan2(denominator)
+}
+
+pub fn uniform_spherical_triangle_pdf(a: &Vec3, b: &Vec3, c: &Vec3) -> Float {
+    let area = spherical_triangle_area(a, b, c);
+    if area > 0.0 {
+        1.0 / area
+    } else {
+        0.0
+    }
+}
+
+/// The unit vector along the part of `v` perpendicular to the unit
+/// vector `w`.
+fn orthogonal(v: &Vec3, w: &Vec3) -> Vec3 {
+    (v - w * (v % w)).to_unit()
+}
+
+/// An orthonormal basis around `n`, used to move sampled directions from
+/// the +z-up local frame into world space.
+pub struct Frame {
+    pub s: Vec3,
+    pub t: Vec3,
+    pub n: Vec3,
+}
+
+impl Frame {
+    pub fn new(n: &Vec3) -> Frame {
+        // Duff et al., "Building an Orthonormal Basis, Revisited".
+        let sign = 1.0_f32.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        Frame {
+            s: Vec3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+            t: Vec3::new(b, sign + n.y * n.y * a, -n.y),
+            n: n.clone(),
+        }
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        &self.s * v.x + &self.t * v.y + &self.n * v.z
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(v % &self.s, v % &self.t, v % &self.n)
+    }
+}
+
+/// Piecewise-constant distribution over `n` buckets, for picking an item
+/// (e.g. a light) in proportion to its weight.
+pub struct Distribution1D {
+    cdf: Vec<Float>,
+}
+
+impl Distribution1D {
+    pub fn new(weights: &[Float]) -> Distribution1D {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        cdf.push(0.0);
+        for w in weights {
+            cdf.push(cdf.last().unwrap() + w.max(0.0));
+        }
+
+        let total = *cdf.last().unwrap();
+        if total > 0.0 {
+            for c in cdf.iter_mut() {
+                *c /= total;
+            }
+        } else {
+            // All weights zero: fall back to uniform.
+            let n = weights.len().max(1) as Float;
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as Float / n;
+            }
+        }
+
+        Distribution1D { cdf }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cdf.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Picks a bucket; returns its index and probability.
+    pub fn sample(&self, u: Float) -> (usize, Float) {
+        let index = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(self.len() - 1);
+        (index, self.pdf(index))
+    }
+
+    pub fn pdf(&self, index: usize) -> Float {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const SAMPLES: usize = 200_000;
+    // Bins over (cos theta, phi), in which solid angle is uniform.
+    const THETA_BINS: usize = 20;
+    const PHI_BINS: usize = 40;
+
+    fn uniforms(seed: u64) -> impl Iterator<Item = (Float, Float)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        std::iter::repeat_with(move || (rng.gen(), rng.gen()))
+    }
+
+    /// Pearson's chi-square statistic for `observed` counts against
+    /// `expected` ones, with bins expecting fewer than five pooled, turned
+    /// into a roughly standard normal score by Wilson and Hilferty's cube
+    /// root. Large values mean the counts don't follow the expectation.
+    fn chi_square(observed: &[u64], expected: &[f64]) -> f64 {
+        let (mut statistic, mut bins) = (0.0, 0);
+        let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+        for (&o, &e) in observed.iter().zip(expected) {
+            if e < 5.0 {
+                pooled_observed += o as f64;
+                pooled_expected += e;
+            } else {
+                statistic += (o as f64 - e).powi(2) / e;
+                bins += 1;
+            }
+        }
+        if pooled_expected > 0.0 {
+            statistic += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+            bins += 1;
+        } else {
+            assert_eq!(pooled_observed, 0.0, "samples where the pdf is zero");
+        }
+
+        let dof = (bins - 1) as f64;
+        let scale = 2.0 / (9.0 * dof);
+        ((statistic / dof).cbrt() - (1.0 - scale)) / scale.sqrt()
+    }
+
+    fn direction_bin(w: &Vec3) -> usize {
+        let theta = ((w.z as f64 + 1.0) / 2.0 * THETA_BINS as f64) as usize;
+        let phi = (w.y as f64)
+            .atan2(w.x as f64)
+            .rem_euclid(2.0 * std::f64::consts::PI);
+        let phi = (phi / (2.0 * std::f64::consts::PI) * PHI_BINS as f64) as usize;
+        theta.min(THETA_BINS - 1) * PHI_BINS + phi.min(PHI_BINS - 1)
+    }
+
+    /// Checks that `warp` spreads directions with density `pdf`, which must
+    /// integrate to one.
+    fn check_directions(warp: impl Fn((Float, Float)) -> Vec3, pdf: impl Fn(&Vec3) -> Float) {
+        let mut observed = vec![0; THETA_BINS * PHI_BINS];
+        for u in uniforms(1).take(SAMPLES) {
+            let w = warp(u);
+            assert!((w.norm() - 1.0).abs() < 1e-4, "not a unit vector");
+            observed[direction_bin(&w)] += 1;
+        }
+
+        // Integrate the pdf over each bin by the midpoint rule.
+        const SUB: usize = 8;
+        let (d_cos, d_phi) = (
+            2.0 / (THETA_BINS * SUB) as f64,
+            2.0 * std::f64::consts::PI / (PHI_BINS * SUB) as f64,
+        );
+        let mut expected = vec![0.0; THETA_BINS * PHI_BINS];
+        for i in 0..THETA_BINS * SUB {
+            let cos = -1.0 + (i as f64 + 0.5) * d_cos;
+            let sin = (1.0 - cos * cos).sqrt();
+            for j in 0..PHI_BINS * SUB {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let w = Vec3::new(
+                    (sin * phi.cos()) as Float,
+                    (sin * phi.sin()) as Float,
+                    cos as Float,
+                );
+                expected[(i / SUB) * PHI_BINS + j / SUB] += pdf(&w) as f64 * d_cos * d_phi;
+            }
+        }
+        let total: f64 = expected.iter().sum();
+        assert!((total - 1.0).abs() < 1e-3, "pdf integrates to {}", total);
+
+        let expected: Vec<_> = expected.iter().map(|p| p * SAMPLES as f64).collect();
+        let z = chi_square(&observed, &expected);
+        assert!(z < 4.0, "chi-square z-score {}", z);
+    }
+
+    #[test]
+    fn uniform_sphere_matches_pdf() {
+        check_directions(uniform_sphere, |_| uniform_sphere_pdf());
+    }
+
+    #[test]
+    fn uniform_hemisphere_matches_pdf() {
+        check_directions(uniform_hemisphere, |w| {
+            if w.z > 0.0 {
+                uniform_hemisphere_pdf()
+            } else {
+                0.0
+            }
+        });
+    }
+
+    #[test]
+    fn cosine_hemisphere_matches_pdf() {
+        check_directions(cosine_hemisphere, |w| cosine_hemisphere_pdf(w.z));
+    }
+
+    #[test]
+    fn uniform_cone_matches_pdf() {
+        // On a bin boundary, so the midpoint rule is exact.
+        let cos_max = 0.5;
+        check_directions(
+            |u| uniform_cone(u, cos_max),
+            |w| {
+                if w.z > cos_max {
+                    uniform_cone_pdf(cos_max)
+                } else {
+                    0.0
+                }
+            },
+        );
+    }
+
+    #[test]
+    fn concentric_disk_matches_pdf() {
+        // Bins over (r^2, phi), in which area is uniform.
+        const R_BINS: usize = 20;
+        let mut observed = vec![0; R_BINS * PHI_BINS];
+        for u in uniforms(2).take(SAMPLES) {
+            let (x, y) = concentric_disk(u);
+            let r2 = (x * x + y * y) as f64;
+            assert!(r2 <= 1.0 + 1e-6, "outside the disk");
+            let phi = (y as f64)
+                .atan2(x as f64)
+                .rem_euclid(2.0 * std::f64::consts::PI);
+            let i = ((r2 * R_BINS as f64) as usize).min(R_BINS - 1);
+            let j =
+                ((phi / (2.0 * std::f64::consts::PI) * PHI_BINS as f64) as usize).min(PHI_BINS - 1);
+            observed[i * PHI_BINS + j] += 1;
+        }
+
+        // dA = r dr dphi = d(r^2) dphi / 2, and the pdf is constant.
+        let area = 0.5 / R_BINS as f64 * 2.0 * std::f64::consts::PI / PHI_BINS as f64;
+        let p = concentric_disk_pdf() as f64 * area;
+        assert!((p * (R_BINS * PHI_BINS) as f64 - 1.0).abs() < 1e-4);
+
+        let expected = vec![p * SAMPLES as f64; R_BINS * PHI_BINS];
+        let z = chi_square(&observed, &expected);
+        assert!(z < 4.0, "chi-square z-score {}", z);
+    }
+
+    #[test]
+    fn uniform_triangle_matches_pdf() {
+        // A grid over (b0, b1); the triangle b0 + b1 <= 1 covers the cells
+        // below the diagonal and half of each one on it.
+        const BINS: usize = 24;
+        let mut observed = vec![0; BINS * BINS];
+        for u in uniforms(3).take(SAMPLES) {
+            let b = uniform_triangle(u);
+            assert!(b.iter().all(|&b| b >= -1e-6), "outside the triangle");
+            assert!((b[0] + b[1] + b[2] - 1.0).abs() < 1e-5);
+            let i = ((b[0] as f64 * BINS as f64) as usize).min(BINS - 1);
+            let j = ((b[1] as f64 * BINS as f64) as usize).min(BINS - 1);
+            observed[i * BINS + j] += 1;
+        }
+
+        // Any triangle will do for the pdf: in barycentrics it's the pdf
+        // times the triangle's area, over the half-unit square's.
+        let (p0, p1, p2) = (
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(3.0, 1.0, 0.0),
+            Vec3::new(0.0, 2.0, 2.0),
+        );
+        let area = 0.5 * (&p1 - &p0).cross(&(&p2 - &p0)).norm();
+        let density = (uniform_triangle_pdf(&p0, &p1, &p2) * area) as f64 * 2.0;
+        assert!(
+            (density - 2.0).abs() < 1e-4,
+            "pdf is off by {}",
+            density / 2.0
+        );
+
+        let cell = density / (BINS * BINS) as f64 * SAMPLES as f64;
+        let mut expected = vec![0.0; BINS * BINS];
+        for i in 0..BINS {
+            for j in 0..BINS {
+                expected[i * BINS + j] = match (i + j + 1).cmp(&BINS) {
+                    std::cmp::Ordering::Less => cell,
+                    std::cmp::Ordering::Equal => cell / 2.0,
+                    std::cmp::Ordering::Greater => 0.0,
+                };
+            }
+        }
+        let z = chi_square(&observed, &expected);
+        assert!(z < 4.0, "chi-square z-score {}", z);
+    }
+
+    #[test]
+    fn spherical_triangle_area_of_octant() {
+        let (a, b, c) = (
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        assert!((spherical_triangle_area(&a, &b, &c) - FRAC_PI_2).abs() < 1e-5);
+        assert!((uniform_spherical_triangle_pdf(&a, &b, &c) - 2.0 / PI).abs() < 1e-5);
+    }
+
+    #[test]
+    fn uniform_spherical_triangle_matches_pdf() {
+        let (a, b, c) = (
+            Vec3::new(1.0, 0.2, 0.3).to_unit(),
+            Vec3::new(-0.1, 1.0, 0.4).to_unit(),
+            Vec3::new(0.3, 0.1, 1.0).to_unit(),
+        );
+        let orientation = a.cross(&b) % &c;
+        let inside = |w: &Vec3| {
+            [a.cross(&b) % w, b.cross(&c) % w, c.cross(&a) % w]
+                .iter()
+                .all(|&s| s * orientation >= 0.0)
+        };
+
+        let mut observed = vec![0; THETA_BINS * PHI_BINS];
+        for u in uniforms(4).take(SAMPLES) {
+            let w = uniform_spherical_triangle(&a, &b, &c, u).unwrap();
+            assert!((w.norm() - 1.0).abs() < 1e-4, "not a unit vector");
+            let slack = 1e-4 * orientation.signum();
+            assert!(
+                inside(&(&w + &(&(&a + &b) + &c) * slack)),
+                "outside the triangle"
+            );
+            observed[direction_bin(&w)] += 1;
+        }
+
+        // The bins cut the triangle's edges, so rather than integrate the
+        // pdf over them, compare against uniform sphere samples that land in
+        // the triangle (which the test above checks), and check the pdf by
+        // how many of those do.
+        let mut reference = vec![0; THETA_BINS * PHI_BINS];
+        let mut total = 0;
+        for u in uniforms(5).take(SAMPLES * 8) {
+            let w = uniform_sphere(u);
+            if inside(&w) {
+                reference[direction_bin(&w)] += 1;
+                total += 1;
+            }
+        }
+        let fraction = total as f64 / (SAMPLES * 8) as f64;
+        let estimate = fraction / uniform_sphere_pdf() as f64;
+        let exact = 1.0 / uniform_spherical_triangle_pdf(&a, &b, &c) as f64;
+        let error = (fraction * (1.0 - fraction) / (SAMPLES * 8) as f64).sqrt()
+            / uniform_sphere_pdf() as f64;
+        assert!(
+            (estimate - exact).abs() < 4.0 * error,
+            "solid angle {} against {}",
+            exact,
+            estimate
+        );
+
+        // The two-sample chi-square test.
+        let (n, m) = (SAMPLES as f64, total as f64);
+        let (mut statistic, mut bins) = (0.0, 0);
+        for (&o, &r) in observed.iter().zip(&reference) {
+            if o + r >= 10 {
+                let d = (m / n).sqrt() * o as f64 - (n / m).sqrt() * r as f64;
+                statistic += d * d / (o + r) as f64;
+                bins += 1;
+            }
+        }
+        let dof = (bins - 1) as f64;
+        let scale = 2.0 / (9.0 * dof);
+        let z = ((statistic / dof).cbrt() - (1.0 - scale)) / scale.sqrt();
+        assert!(z < 4.0, "chi-square z-score {}", z);
+    }
+}
@@ -0,0 +1,879 @@
+use crate::parse::ParamSet;
+use crate::vec::Float;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore, SeedableRng};
+use rayon::prelude::*;
+
+/// The largest `Float` below one.
+const ONE_MINUS_EPSILON: Float = 1.0 - Float::EPSILON / 2.0;
+
+/// Where the random numbers behind each sample come from; pbrt's `Sampler`
+/// directive. Samples are laid out per pixel, and each coordinate, or
+/// dimension, of a sample is asked for separately, so that any sample can
+/// be made on any thread in any order and come out the same.
+pub trait Sampler: Sync {
+    /// pbrt's name for the sampler.
+    fn name(&self) -> &'static str;
+
+    fn samples_per_pixel(&self) -> u32;
+
+    /// Re-randomizes the samples. The same seed gives the same samples.
+    fn seed(&mut self, seed: u64);
+
+    /// Coordinate `dimension` of sample `index` of pixel `(x, y)`, in
+    /// [0, 1). Indices past `samples_per_pixel` are fine, for renders that
+    /// keep adding samples.
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float;
+}
+
+/// One sample of one pixel, handing out a dimension of it to every call
+/// through the `RngCore` interface, so integrators draw from it just like
+/// from any other `Rng`. The first two dimensions are the position in the
+/// pixel.
+pub struct SampleStream<'a> {
+    sampler: &'a dyn Sampler,
+    x: u32,
+    y: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl<'a> SampleStream<'a> {
+    pub fn new(sampler: &'a dyn Sampler, x: usize, y: usize, index: u32) -> SampleStream<'a> {
+        SampleStream {
+            sampler,
+            x: x as u32,
+            y: y as u32,
+            index,
+            dimension: 0,
+        }
+    }
+
+    pub fn next(&mut self) -> Float {
+        let u = self
+            .sampler
+            .sample(self.x, self.y, self.index, self.dimension);
+        self.dimension += 1;
+        u
+    }
+}
+
+impl RngCore for SampleStream<'_> {
+    fn next_u32(&mut self) -> u32 {
+        (self.next() as f64 * 4_294_967_296.0) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next() as f64 * 18_446_744_073_709_551_616.0) as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Uniform random numbers, independent of each other; pbrt's "random".
+pub struct IndependentSampler {
+    samples: u32,
+    seed: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(samples: u32) -> IndependentSampler {
+        IndependentSampler { samples, seed: 0 }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn name(&self) -> &'static str {
+        "independent"
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        uniform(hash(&[
+            self.seed,
+            x as u64,
+            y as u64,
+            index as u64,
+            dimension as u64,
+        ]))
+    }
+}
+
+/// Jittered samples on an `x_samples` by `y_samples` grid. Consecutive
+/// dimensions are stratified in pairs, since most of what the integrators
+/// draw is two-dimensional: positions on the film, the lens and lights,
+/// and directions. Each pair visits the strata in its own random order.
+pub struct StratifiedSampler {
+    x_samples: u32,
+    y_samples: u32,
+    jitter: bool,
+    seed: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(x_samples: u32, y_samples: u32, jitter: bool) -> StratifiedSampler {
+        StratifiedSampler {
+            x_samples,
+            y_samples,
+            jitter,
+            seed: 0,
+        }
+    }
+
+    pub fn from_params(params: &ParamSet) -> StratifiedSampler {
+        StratifiedSampler::new(
+            params.int("xsamples", 4).max(1) as u32,
+            params.int("ysamples", 4).max(1) as u32,
+            params.bool("jitter", true),
+        )
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn name(&self) -> &'static str {
+        "stratified"
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.x_samples * self.y_samples
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let n = self.samples_per_pixel();
+        // Past the last sample, start over with a new order.
+        let (round, i) = (index / n, index % n);
+        let order = hash(&[
+            self.seed,
+            x as u64,
+            y as u64,
+            (dimension / 2) as u64,
+            round as u64,
+        ]);
+        let stratum = permutation_element(i, n, order as u32);
+
+        let jitter = if self.jitter {
+            uniform(hash(&[
+                self.seed,
+                x as u64,
+                y as u64,
+                index as u64,
+                dimension as u64,
+            ]))
+        } else {
+            0.5
+        };
+        let u = if dimension.is_multiple_of(2) {
+            ((stratum % self.x_samples) as Float + jitter) / self.x_samples as Float
+        } else {
+            ((stratum / self.x_samples) as Float + jitter) / self.y_samples as Float
+        };
+        u.min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// Dimensions past this many have no prime of their own, and get
+/// independent random numbers instead.
+const HALTON_DIMENSIONS: usize = 128;
+
+/// Pixels repeat the same pattern of sample positions every this many.
+const HALTON_RESOLUTION: u64 = 128;
+
+/// The Halton sequence, laid over the image so that the first two
+/// dimensions land in the right pixel, as in pbrt; the rest have their
+/// digits scrambled by a random permutation for each digit.
+pub struct HaltonSampler {
+    samples: u32,
+    seed: u64,
+    primes: Vec<u64>,
+    /// What to multiply the digits that pick the column and the row by, to
+    /// find where in the sequence both are.
+    offsets: [u64; 2],
+    /// For each dimension past the second, a permutation of the digits
+    /// `0..base` for each digit a `Float` can hold.
+    permutations: Vec<Vec<u16>>,
+}
+
+/// Base 2 digits and base 3 digits of the index that pick the pixel,
+/// enough for `HALTON_RESOLUTION` pixels in each direction.
+const HALTON_SCALES: [u64; 2] = [128, 243];
+const HALTON_EXPONENTS: [u32; 2] = [7, 5];
+
+impl HaltonSampler {
+    pub fn new(samples: u32) -> HaltonSampler {
+        let mut primes = Vec::new();
+        let mut n = 2;
+        while primes.len() < HALTON_DIMENSIONS {
+            if primes.iter().all(|p| n % p != 0) {
+                primes.push(n);
+            }
+            n += 1;
+        }
+        // By the Chinese remainder theorem.
+        let offsets = [0, 1].map(|i| {
+            let other = HALTON_SCALES[1 - i];
+            other * multiplicative_inverse(other, HALTON_SCALES[i])
+        });
+        let mut sampler = HaltonSampler {
+            samples,
+            seed: 0,
+            primes,
+            offsets,
+            permutations: Vec::new(),
+        };
+        sampler.seed(0);
+        sampler
+    }
+
+    /// Where in the sequence sample `index` of pixel `(x, y)` is. The
+    /// sequence's first dimension picks the column from its lowest base 2
+    /// digits, and the second the row from its lowest base 3 digits, so
+    /// this solves for those digits and leaves the rest to the index.
+    fn sequence_index(&self, x: u32, y: u32, index: u32) -> u64 {
+        let stride = HALTON_SCALES[0] * HALTON_SCALES[1];
+        let pixel = [x as u64 % HALTON_RESOLUTION, y as u64 % HALTON_RESOLUTION];
+        let offset = inverse_radical_inverse(pixel[0], 2, HALTON_EXPONENTS[0]) * self.offsets[0]
+            + inverse_radical_inverse(pixel[1], 3, HALTON_EXPONENTS[1]) * self.offsets[1];
+        offset % stride + index as u64 * stride
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn name(&self) -> &'static str {
+        "halton"
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.permutations = self
+            .primes
+            .iter()
+            .enumerate()
+            .map(|(dimension, &base)| {
+                if dimension < 2 {
+                    return Vec::new();
+                }
+                let mut rng = StdRng::seed_from_u64(hash(&[seed, dimension as u64]));
+                let mut permutations = Vec::new();
+                for _ in 0..digit_count(base) {
+                    let mut digits: Vec<u16> = (0..base as u16).collect();
+                    digits.shuffle(&mut rng);
+                    permutations.extend(digits);
+                }
+                permutations
+            })
+            .collect();
+    }
+
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let i = self.sequence_index(x, y, index);
+        let dimension = dimension as usize;
+        match dimension {
+            // The position in the pixel; the lower digits picked the pixel.
+            0 => radical_inverse(2, i >> HALTON_EXPONENTS[0]),
+            1 => radical_inverse(3, i / HALTON_SCALES[1]),
+            d if d < HALTON_DIMENSIONS => {
+                scrambled_radical_inverse(self.primes[d], i, &self.permutations[d])
+            }
+            d => uniform(hash(&[self.seed, i, d as u64])),
+        }
+    }
+}
+
+/// Direction numbers of the first four dimensions of the Sobol sequence,
+/// from its primitive polynomials' degrees, their coefficients and the
+/// first direction numbers, as in Joe and Kuo's tables.
+const SOBOL_POLYNOMIALS: [(u32, u32, &[u32]); 3] =
+    [(1, 0, &[1]), (2, 1, &[1, 3]), (3, 1, &[1, 3, 1])];
+
+/// Owen-scrambled Sobol points, four dimensions at a time, as Burley
+/// describes in "Practical Hash-based Owen Scrambling". Every four
+/// dimensions shuffle the order of the points with their own nested
+/// uniform scramble, which keeps each power of two of them well spread
+/// out.
+pub struct SobolSampler {
+    samples: u32,
+    seed: u64,
+    directions: [[u32; 32]; 4],
+}
+
+impl SobolSampler {
+    pub fn new(samples: u32) -> SobolSampler {
+        let mut directions = [[0; 32]; 4];
+        for (bit, v) in directions[0].iter_mut().enumerate() {
+            *v = 1 << (31 - bit);
+        }
+        for (d, &(degree, coefficients, initial)) in SOBOL_POLYNOMIALS.iter().enumerate() {
+            let s = degree as usize;
+            let mut m = initial.to_vec();
+            for i in s..32 {
+                let mut next = m[i - s] ^ (m[i - s] << s);
+                for k in 1..s {
+                    if (coefficients >> (s - 1 - k)) & 1 == 1 {
+                        next ^= m[i - k] << k;
+                    }
+                }
+                m.push(next);
+            }
+            for (bit, v) in directions[d + 1].iter_mut().enumerate() {
+                *v = m[bit] << (31 - bit);
+            }
+        }
+        SobolSampler {
+            samples,
+            seed: 0,
+            directions,
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn name(&self) -> &'static str {
+        "sobol"
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let set = hash(&[self.seed, x as u64, y as u64, (dimension / 4) as u64]);
+        let index = owen_scramble(index, set as u32);
+
+        let directions = &self.directions[(dimension % 4) as usize];
+        let (mut v, mut bits) = (0, index);
+        while bits != 0 {
+            v ^= directions[bits.trailing_zeros() as usize];
+            bits &= bits - 1;
+        }
+        let v = owen_scramble(v, hash(&[set, dimension as u64]) as u32);
+        (v as Float * (1.0 / 4_294_967_296.0)).min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// How many PMJ02 sequences to draw from, and the longest they get.
+const PMJ02_SETS: usize = 16;
+const PMJ02_MAX_SAMPLES: usize = 4096;
+
+/// Progressive multi-jittered (0,2) sequences (Christensen, Kensler and
+/// Kilpatrick), for consecutive pairs of dimensions. Every power of two
+/// of the first points is stratified in every way a (0,2) sequence is: in
+/// each row and column of the finest grid, and in every grid of as many
+/// equal rectangles. Each pair of dimensions of each pixel picks one of
+/// a few precomputed sequences, and scrambles its binary digits. Unlike
+/// pbrt-v4's "pmj02bn", the sequences aren't blue noise, but scenes that
+/// ask for that get this.
+pub struct PMJ02Sampler {
+    samples: u32,
+    seed: u64,
+    /// `PMJ02_SETS` sequences of `length` points, in 0.32 fixed point.
+    points: Vec<Vec<(u32, u32)>>,
+    length: usize,
+}
+
+impl PMJ02Sampler {
+    pub fn new(samples: u32) -> PMJ02Sampler {
+        let length = (samples as usize)
+            .next_power_of_two()
+            .min(PMJ02_MAX_SAMPLES);
+        let points = (0..PMJ02_SETS)
+            .into_par_iter()
+            .map(|set| {
+                Pmj02::generate(length, set as u64)
+                    .into_iter()
+                    .map(|(x, y)| {
+                        let fixed = |u: f64| (u * 4_294_967_296.0) as u32;
+                        (fixed(x), fixed(y))
+                    })
+                    .collect()
+            })
+            .collect();
+        PMJ02Sampler {
+            samples,
+            seed: 0,
+            points,
+            length,
+        }
+    }
+}
+
+impl Sampler for PMJ02Sampler {
+    fn name(&self) -> &'static str {
+        "pmj02"
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let index = index as usize;
+        // Past the end of a sequence, carry on with another.
+        let h = hash(&[
+            self.seed,
+            x as u64,
+            y as u64,
+            (dimension / 2) as u64,
+            (index / self.length) as u64,
+        ]);
+        let point = self.points[h as usize % PMJ02_SETS][index % self.length];
+        let v = if dimension.is_multiple_of(2) {
+            point.0 ^ (h >> 32) as u32
+        } else {
+            point.1 ^ mix(h) as u32
+        };
+        (v as Float * (1.0 / 4_294_967_296.0)).min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// Builds one PMJ02 sequence, four times as long at each step: first the
+/// point diagonally opposite each existing one in its square of the grid,
+/// then the points in the other two quarters. Every new point goes in a
+/// random spot that keeps the (0,2) stratification, found by trying rows
+/// and columns of the finest grid that are still free.
+struct Pmj02 {
+    points: Vec<(f64, f64)>,
+    /// For each way of splitting the square into as many rectangles as
+    /// there will be points, `2^k` columns by `n / 2^k` rows, which are
+    /// taken.
+    occupied: Vec<Vec<bool>>,
+    rng: StdRng,
+}
+
+impl Pmj02 {
+    fn generate(length: usize, seed: u64) -> Vec<(f64, f64)> {
+        let mut pmj = Pmj02 {
+            points: Vec::with_capacity(length.max(4)),
+            occupied: Vec::new(),
+            rng: StdRng::seed_from_u64(seed),
+        };
+        let first = (pmj.rng.gen(), pmj.rng.gen());
+        pmj.points.push(first);
+
+        let mut n = 1;
+        while n < length {
+            pmj.extend_diagonal(n);
+            pmj.extend_others(2 * n);
+            n *= 4;
+        }
+        pmj.points.truncate(length);
+        pmj.points
+    }
+
+    /// Doubles `n`, a power of four, points.
+    fn extend_diagonal(&mut self, n: usize) {
+        let cells = (n as f64).sqrt() as usize;
+        self.mark_occupied(2 * n);
+        for i in 0..n {
+            let (cell, half) = cell_of(self.points[i], cells);
+            let point = self.point_in(cell, (1 - half.0, 1 - half.1), cells, 2 * n);
+            self.points.push(point);
+        }
+    }
+
+    /// Doubles `n`, twice a power of four, points.
+    fn extend_others(&mut self, n: usize) {
+        let cells = ((n / 2) as f64).sqrt() as usize;
+        self.mark_occupied(2 * n);
+        let mut halves = Vec::with_capacity(n / 2);
+        for i in 0..n / 2 {
+            let (cell, mut half) = cell_of(self.points[i], cells);
+            if self.rng.gen() {
+                half.0 = 1 - half.0;
+            } else {
+                half.1 = 1 - half.1;
+            }
+            halves.push((cell, half));
+            let point = self.point_in(cell, half, cells, 2 * n);
+            self.points.push(point);
+        }
+        for (cell, half) in halves {
+            let point = self.point_in(cell, (1 - half.0, 1 - half.1), cells, 2 * n);
+            self.points.push(point);
+        }
+    }
+
+    fn mark_occupied(&mut self, total: usize) {
+        let levels = total.trailing_zeros() as usize;
+        self.occupied = vec![vec![false; total]; levels + 1];
+        for i in 0..self.points.len() {
+            self.mark(self.points[i], total);
+        }
+    }
+
+    fn strata(point: (f64, f64), total: usize) -> impl Iterator<Item = (usize, usize)> {
+        (0..=total.trailing_zeros() as usize).map(move |k| {
+            let (columns, rows) = (1 << k, total >> k);
+            let x = (point.0 * columns as f64) as usize;
+            let y = (point.1 * rows as f64) as usize;
+            (k, y * columns + x)
+        })
+    }
+
+    fn mark(&mut self, point: (f64, f64), total: usize) {
+        for (k, i) in Pmj02::strata(point, total) {
+            self.occupied[k][i] = true;
+        }
+    }
+
+    /// A free point in quarter `half` of square `cell` of a `cells` by
+    /// `cells` grid, given there will be `total` points.
+    fn point_in(
+        &mut self,
+        cell: (usize, usize),
+        half: (usize, usize),
+        cells: usize,
+        total: usize,
+    ) -> (f64, f64) {
+        let levels = total.trailing_zeros() as usize;
+        let per_half = total / (2 * cells);
+        let x0 = (2 * cell.0 + half.0) * per_half;
+        let y0 = (2 * cell.1 + half.1) * per_half;
+        let columns: Vec<usize> = (x0..x0 + per_half)
+            .filter(|&x| !self.occupied[levels][x])
+            .collect();
+        let rows: Vec<usize> = (y0..y0 + per_half)
+            .filter(|&y| !self.occupied[0][y])
+            .collect();
+
+        loop {
+            let x = columns[self.rng.gen_range(0, columns.len())];
+            let y = rows[self.rng.gen_range(0, rows.len())];
+            let point = (
+                (x as f64 + self.rng.gen::<f64>()) / total as f64,
+                (y as f64 + self.rng.gen::<f64>()) / total as f64,
+            );
+            if Pmj02::strata(point, total).all(|(k, i)| !self.occupied[k][i]) {
+                self.mark(point, total);
+                return point;
+            }
+        }
+    }
+}
+
+/// The side of the blue-noise mask, in pixels; it's tiled over the image.
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// The plastic number, whose powers' reciprocals make the two-dimensional
+/// additive recurrence with the most even gaps (Roberts' R2 sequence).
+const PLASTIC: f64 = 1.324_717_957_244_746;
+
+/// Samples that are spread out within each pixel and whose error is spread
+/// out across pixels, so that at low sample counts noise comes out fine
+/// grained rather than in clumps (Georgiev and Fajardo, "Blue-noise
+/// Dithered Sampling"). Within a pixel, each pair of dimensions follows a
+/// rank-1 lattice, extended as an additive recurrence so that any number
+/// of samples will do. Each pixel shifts the lattice by the value of a
+/// blue-noise mask there, so that neighbouring pixels take values far
+/// apart. Each dimension reads the mask at its own offset, and pairs
+/// past the first visit the points in their own order, so that they
+/// don't correlate with each other. The effect is strongest where a few
+/// dimensions account for most of the noise, as in direct lighting.
+pub struct BlueNoiseSampler {
+    samples: u32,
+    seed: u64,
+    /// Each pixel's rank in the mask, from 0 to `BLUE_NOISE_SIZE^2 - 1`.
+    mask: Vec<u16>,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(samples: u32) -> BlueNoiseSampler {
+        BlueNoiseSampler {
+            samples,
+            seed: 0,
+            mask: void_and_cluster(BLUE_NOISE_SIZE, 0),
+        }
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn name(&self) -> &'static str {
+        "bluenoise"
+    }
+
+    fn samples_per_pixel(&self) -> u32 {
+        self.samples
+    }
+
+    fn seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    fn sample(&self, x: u32, y: u32, index: u32, dimension: u32) -> Float {
+        let pair = (dimension / 2) as u64;
+        let index = if pair == 0 {
+            index
+        } else {
+            let n = self.samples;
+            let order = hash(&[self.seed, pair, (index / n) as u64]);
+            index / n * n + permutation_element(index % n, n, order as u32)
+        };
+
+        let h = hash(&[self.seed, dimension as u64]);
+        let size = BLUE_NOISE_SIZE as u64;
+        let mx = (x as u64 + h % size) % size;
+        let my = (y as u64 + (h >> 32) % size) % size;
+        let rank = self.mask[(my * size + mx) as usize];
+        let shift = (rank as f64 + 0.5) / (size * size) as f64;
+
+        let step = if dimension.is_multiple_of(2) {
+            1.0 / PLASTIC
+        } else {
+            1.0 / (PLASTIC * PLASTIC)
+        };
+        ((shift + index as f64 * step).fract() as Float).min(ONE_MINUS_EPSILON)
+    }
+}
+
+/// A `size` by `size` blue-noise mask, by Ulichney's void-and-cluster
+/// method: the rank of each pixel in an order where every prefix is
+/// spread out evenly, tiling seamlessly. Clusters and voids are where a
+/// Gaussian blur of the pixels ranked so far is highest and lowest.
+fn void_and_cluster(size: usize, seed: u64) -> Vec<u16> {
+    let n = size * size;
+    let mut kernel = vec![0.0; n];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wrap = |d: usize| d.min(size - d) as f64;
+            let r2 = wrap(dx).powi(2) + wrap(dy).powi(2);
+            kernel[dy * size + dx] = (-r2 / (2.0 * 1.5 * 1.5)).exp();
+        }
+    }
+    let splat = |energy: &mut [f64], p: usize, sign: f64| {
+        let (px, py) = (p % size, p / size);
+        for y in 0..size {
+            let dy = (y + size - py) % size;
+            for x in 0..size {
+                energy[y * size + x] += sign * kernel[dy * size + (x + size - px) % size];
+            }
+        }
+    };
+    let extreme = |energy: &[f64], set: &[bool], want: bool, tightest: bool| {
+        let candidates = (0..n).filter(|&p| set[p] == want);
+        if tightest {
+            candidates.max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        } else {
+            candidates.min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+        }
+        .unwrap()
+    };
+
+    // A random tenth of the pixels, evened out by moving the pixel in the
+    // tightest cluster to the largest void until it's already there.
+    let mut rng = StdRng::seed_from_u64(seed);
+    let ones = n / 10;
+    let mut pattern = vec![false; n];
+    let mut energy = vec![0.0; n];
+    let mut order: Vec<usize> = (0..n).collect();
+    order.shuffle(&mut rng);
+    for &p in &order[..ones] {
+        pattern[p] = true;
+        splat(&mut energy, p, 1.0);
+    }
+    loop {
+        let cluster = extreme(&energy, &pattern, true, true);
+        pattern[cluster] = false;
+        splat(&mut energy, cluster, -1.0);
+        let void = extreme(&energy, &pattern, false, false);
+        pattern[void] = true;
+        splat(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Rank that pattern's pixels by taking away the tightest cluster each
+    // time, which gets the highest rank left; then the other pixels by
+    // filling the largest void each time.
+    let mut rank = vec![0; n];
+    let (mut taken, mut taken_energy) = (pattern.clone(), energy.clone());
+    for r in (0..ones).rev() {
+        let cluster = extreme(&taken_energy, &taken, true, true);
+        taken[cluster] = false;
+        splat(&mut taken_energy, cluster, -1.0);
+        rank[cluster] = r as u16;
+    }
+    for r in ones..n {
+        let void = extreme(&energy, &pattern, false, false);
+        pattern[void] = true;
+        splat(&mut energy, void, 1.0);
+        rank[void] = r as u16;
+    }
+    rank
+}
+
+/// The square of a `cells` by `cells` grid that `point` is in, and which
+/// quarter of it.
+fn cell_of(point: (f64, f64), cells: usize) -> ((usize, usize), (usize, usize)) {
+    let (x, y) = (point.0 * cells as f64, point.1 * cells as f64);
+    let cell = (x as usize, y as usize);
+    let half = (
+        (2.0 * (x - cell.0 as f64)) as usize,
+        (2.0 * (y - cell.1 as f64)) as usize,
+    );
+    (cell, half)
+}
+
+/// The digits of `a` in `base`, mirrored about the point.
+fn radical_inverse(base: u64, mut a: u64) -> Float {
+    let inverse_base = 1.0 / base as f64;
+    let (mut reversed, mut scale) = (0, 1.0);
+    while a > 0 {
+        reversed = reversed * base + a % base;
+        scale *= inverse_base;
+        a /= base;
+    }
+    ((reversed as f64 * scale) as Float).min(ONE_MINUS_EPSILON)
+}
+
+/// The index whose lowest `digits` digits in `base` `radical_inverse`
+/// would turn into `inverse`'s.
+fn inverse_radical_inverse(mut inverse: u64, base: u64, digits: u32) -> u64 {
+    let mut index = 0;
+    for _ in 0..digits {
+        index = index * base + inverse % base;
+        inverse /= base;
+    }
+    index
+}
+
+/// As `radical_inverse`, but with digit `i` replaced by
+/// `permutations[i * base + digit]`. That includes the zeros past the
+/// last digit, so it goes on for as many digits as a `Float` can hold.
+fn scrambled_radical_inverse(base: u64, mut a: u64, permutations: &[u16]) -> Float {
+    let inverse_base = 1.0 / base as f64;
+    let (mut reversed, mut scale) = (0, 1.0);
+    for digits in permutations.chunks(base as usize) {
+        reversed = reversed * base + digits[(a % base) as usize] as u64;
+        scale *= inverse_base;
+        a /= base;
+    }
+    ((reversed as f64 * scale) as Float).min(ONE_MINUS_EPSILON)
+}
+
+/// How many digits in `base` make a difference to a `Float` in [0, 1).
+fn digit_count(base: u64) -> usize {
+    let mut count = 0;
+    let mut scale: Float = 1.0;
+    while 1.0 - (base - 1) as Float * scale < 1.0 {
+        count += 1;
+        scale /= base as Float;
+    }
+    count
+}
+
+/// `b` such that `a * b % n == 1`, for coprime `a` and `n`.
+fn multiplicative_inverse(a: u64, n: u64) -> u64 {
+    let (mut r, mut new_r) = (n as i64, a as i64);
+    let (mut t, mut new_t) = (0i64, 1i64);
+    while new_r != 0 {
+        let q = r / new_r;
+        (r, new_r) = (new_r, r - q * new_r);
+        (t, new_t) = (new_t, t - q * new_t);
+    }
+    t.rem_euclid(n as i64) as u64
+}
+
+/// Element `i` of a random permutation of `0..n`, picked by `seed`
+/// (Kensler, "Correlated Multi-Jittered Sampling").
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let p = seed;
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            return (i + p % n) % n;
+        }
+    }
+}
+
+/// Owen scrambling of a 0.32 fixed point number: each bit is flipped or
+/// not depending on the bits above it. Works on the bits reversed, where
+/// a hash that only lets bits affect those above them does the job
+/// (Laine and Karras; Vegdahl's constants).
+fn owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut x = v.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+    x.reverse_bits()
+}
+
+/// pbrt's `MixBits`.
+fn mix(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+    v
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0, |h, &v| mix(h ^ v.wrapping_add(0x9e37_79b9_7f4a_7c15)))
+}
+
+/// A `Float` in [0, 1) from the top bits of `bits`.
+fn uniform(bits: u64) -> Float {
+    (bits >> 40) as Float * (1.0 / 16_777_216.0)
+}
@@ -0,0 +1,690 @@
+use crate::scene::shape::{Boxable, HitRecord, Hitable, AABB};
+use crate::vec::*;
+
+use rayon::prelude::*;
+
+/// How many buckets the centroids are sorted into when looking for the
+/// cheapest split.
+const BINS: usize = 12;
+
+/// Leaves hold at most this many items, unless they're as deep as the
+/// hierarchy goes.
+const MAX_LEAF: usize = 4;
+
+/// How deep the hierarchy goes, which bounds the traversal stack. Badly
+/// clustered items can take a split per item otherwise.
+const MAX_DEPTH: usize = 64;
+
+/// Cost of visiting a node, relative to testing an item.
+const TRAVERSAL_COST: Float = 0.125;
+
+/// Nodes with at least this many items are binned by several threads, and
+/// build their children at the same time.
+const PARALLEL_BUILD: usize = 4096;
+
+/// A bounding volume hierarchy, split by the surface area heuristic: each
+/// node divides its items where the chance of a ray hitting either side,
+/// which goes with its surface area, times what's in it is least. The
+/// nodes are stored depth first, each node's first child right after it,
+/// and rays visit the nearer child first, skipping whatever is behind the
+/// closest hit so far. Big hierarchies build in parallel, and things that
+/// move a little can be refitted without building again.
+pub struct Bvh<T> {
+    /// In the order the leaves refer to them.
+    items: Vec<T>,
+    nodes: Vec<Node>,
+}
+
+struct Node {
+    bbox: AABB,
+    /// The first item of a leaf, or the second child of an interior node.
+    offset: u32,
+    /// Items in a leaf; zero for interior nodes.
+    count: u32,
+    /// Which axis an interior node split its items along.
+    axis: u8,
+}
+
+/// An item's bounds while building.
+struct Info {
+    index: usize,
+    bbox: AABB,
+    centroid: Vec3,
+}
+
+enum BuildNode {
+    /// `count` items starting at `first`.
+    Leaf {
+        bbox: AABB,
+        first: usize,
+        count: usize,
+    },
+    Interior {
+        bbox: AABB,
+        axis: usize,
+        children: Box<[BuildNode; 2]>,
+    },
+}
+
+impl<T: Boxable + Send + Sync> Bvh<T> {
+    pub fn new(items: Vec<T>) -> Bvh<T> {
+        if items.is_empty() {
+            return Bvh {
+                items,
+                nodes: Vec::new(),
+            };
+        }
+
+        let mut infos: Vec<Info> = items
+            .par_iter()
+            .enumerate()
+            .map(|(index, item)| {
+                let bbox = item.get_bbox();
+                Info {
+                    index,
+                    centroid: bbox.centroid(),
+                    bbox,
+                }
+            })
+            .collect();
+        let root = build(&mut infos, 0, 0);
+
+        let mut nodes = Vec::with_capacity(2 * items.len());
+        flatten(root, &mut nodes);
+
+        // Put the items in the order the leaves expect.
+        let mut slots: Vec<Option<T>> = items.into_iter().map(Some).collect();
+        let items = infos
+            .iter()
+            .map(|info| slots[info.index].take().unwrap())
+            .collect();
+        Bvh { items, nodes }
+    }
+
+    /// Calls `update` on every item, which may move it, then fits the
+    /// boxes to where the items are now, for animations where only
+    /// transforms or vertex positions change. The hierarchy keeps its
+    /// shape, so the further things move from where they were when it was
+    /// built, the slower it gets; build a new one then.
+    pub fn refit(&mut self, update: impl Fn(&mut T) + Send + Sync) {
+        self.items.par_iter_mut().for_each(update);
+
+        let items = &self.items;
+        self.nodes
+            .par_iter_mut()
+            .filter(|node| node.count > 0)
+            .for_each(|node| {
+                let first = node.offset as usize;
+                node.bbox = items[first..first + node.count as usize]
+                    .iter()
+                    .map(T::get_bbox)
+                    .reduce(|a, b| a.absorb(&b))
+                    .unwrap();
+            });
+        // Children come after their parents.
+        for i in (0..self.nodes.len()).rev() {
+            if self.nodes[i].count == 0 {
+                let second = self.nodes[i].offset as usize;
+                self.nodes[i].bbox = self.nodes[i + 1].bbox.absorb(&self.nodes[second].bbox);
+            }
+        }
+    }
+}
+
+impl<T> Bvh<T> {
+    /// In no particular order.
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Bounds of everything, unless there's nothing.
+    pub fn bounds(&self) -> Option<AABB> {
+        self.nodes.first().map(|root| root.bbox.clone())
+    }
+}
+
+/// Builds the hierarchy over `infos`, reordering them so that each leaf's
+/// items are together; `start` is where `infos` begins among all items,
+/// and `depth` how many nodes are above.
+fn build(infos: &mut [Info], start: usize, depth: usize) -> BuildNode {
+    let (bbox, centroids) = reduce(
+        infos,
+        || None,
+        |bounds: Option<(AABB, AABB)>, info| {
+            let centroid = AABB {
+                min: info.centroid.clone(),
+                max: info.centroid.clone(),
+            };
+            Some(match bounds {
+                Some((bbox, centroids)) => (bbox.absorb(&info.bbox), centroids.absorb(&centroid)),
+                None => (info.bbox.clone(), centroid),
+            })
+        },
+        |a, b| match (a, b) {
+            (Some(a), Some(b)) => Some((a.0.absorb(&b.0), a.1.absorb(&b.1))),
+            (a, b) => a.or(b),
+        },
+    )
+    .unwrap();
+    let leaf = BuildNode::Leaf {
+        bbox: bbox.clone(),
+        first: start,
+        count: infos.len(),
+    };
+    if infos.len() == 1 || depth == MAX_DEPTH {
+        return leaf;
+    }
+
+    let axis = (&centroids.max - &centroids.min).longest_dimension();
+    let (lo, hi) = (centroids.min.get(axis), centroids.max.get(axis));
+    if hi <= lo {
+        // All in the same place; nothing to split.
+        return leaf;
+    }
+    let bin_of = |info: &Info| {
+        let b = ((info.centroid.get(axis) - lo) / (hi - lo) * BINS as Float) as usize;
+        b.min(BINS - 1)
+    };
+
+    let bins = reduce(
+        infos,
+        || vec![(0, None); BINS],
+        |mut bins: Vec<(usize, Option<AABB>)>, info| {
+            let bin = &mut bins[bin_of(info)];
+            bin.0 += 1;
+            bin.1 = Some(grow(bin.1.take(), &info.bbox));
+            bins
+        },
+        |mut a, b| {
+            for (a, (n, bbox)) in a.iter_mut().zip(b) {
+                a.0 += n;
+                if let Some(bbox) = bbox {
+                    a.1 = Some(grow(a.1.take(), &bbox));
+                }
+            }
+            a
+        },
+    );
+
+    // The cost of splitting after each bin, sweeping from both ends.
+    let sweep = |bins: &mut dyn Iterator<Item = &(usize, Option<AABB>)>| {
+        let (mut count, mut bbox): (usize, Option<AABB>) = (0, None);
+        bins.map(|(n, b)| {
+            count += n;
+            if let Some(b) = b {
+                bbox = Some(grow(bbox.take(), b));
+            }
+            count as Float * bbox.as_ref().map_or(0.0, AABB::surface_area)
+        })
+        .collect::<Vec<_>>()
+    };
+    let below = sweep(&mut bins.iter());
+    let mut above = sweep(&mut bins.iter().rev());
+    above.reverse();
+    let (split, cost) = (0..BINS - 1)
+        .map(|i| {
+            (
+                i,
+                TRAVERSAL_COST + (below[i] + above[i + 1]) / bbox.surface_area(),
+            )
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+
+    if infos.len() <= MAX_LEAF && cost >= infos.len() as Float {
+        return leaf;
+    }
+
+    let parallel = infos.len() >= PARALLEL_BUILD;
+    let mid = partition(infos, |info| bin_of(info) <= split);
+    let (left, right) = infos.split_at_mut(mid);
+    BuildNode::Interior {
+        bbox,
+        axis: axis as usize,
+        children: Box::new(if parallel {
+            let (left, right) = rayon::join(
+                || build(left, start, depth + 1),
+                || build(right, start + mid, depth + 1),
+            );
+            [left, right]
+        } else {
+            [
+                build(left, start, depth + 1),
+                build(right, start + mid, depth + 1),
+            ]
+        }),
+    }
+}
+
+/// Moves the items that satisfy `pred` to the front, and says how many
+/// there are.
+fn partition(infos: &mut [Info], pred: impl Fn(&Info) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..infos.len() {
+        if pred(&infos[i]) {
+            infos.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+/// Folds `infos` into one value, in parallel if there are enough of them
+/// to be worth it.
+fn reduce<R: Send>(
+    infos: &[Info],
+    identity: impl Fn() -> R + Send + Sync,
+    fold: impl Fn(R, &Info) -> R + Send + Sync,
+    merge: impl Fn(R, R) -> R + Send + Sync,
+) -> R {
+    if infos.len() >= PARALLEL_BUILD {
+        infos
+            .par_iter()
+            .fold(&identity, &fold)
+            .reduce(&identity, &merge)
+    } else {
+        infos.iter().fold(identity(), fold)
+    }
+}
+
+fn grow(bbox: Option<AABB>, other: &AABB) -> AABB {
+    match bbox {
+        Some(bbox) => bbox.absorb(other),
+        None => other.clone(),
+    }
+}
+
+fn flatten(node: BuildNode, nodes: &mut Vec<Node>) {
+    match node {
+        BuildNode::Leaf { bbox, first, count } => nodes.push(Node {
+            bbox,
+            offset: first as u32,
+            count: count as u32,
+            axis: 0,
+        }),
+        BuildNode::Interior {
+            bbox,
+            axis,
+            children,
+        } => {
+            let index = nodes.len();
+            nodes.push(Node {
+                bbox,
+                offset: 0,
+                count: 0,
+                axis: axis as u8,
+            });
+            let [first, second] = *children;
+            flatten(first, nodes);
+            nodes[index].offset = nodes.len() as u32;
+            flatten(second, nodes);
+        }
+    }
+}
+
+impl<T: Hitable> Bvh<T> {
+    /// Walks the hierarchy front to back, calling `visit` on the items of
+    /// each leaf the ray reaches before `t_max`. `visit` returns the new
+    /// `t_max`, or `None` to stop. Returns how many nodes and items it
+    /// looked at.
+    fn traverse<'a>(
+        &'a self,
+        ray: &Ray,
+        t_min: Float,
+        mut t_max: Float,
+        mut visit: impl FnMut(&'a [T], Float) -> Option<Float>,
+    ) -> u32 {
+        if self.nodes.is_empty() {
+            return 0;
+        }
+        let negative = [
+            ray.direction.x < 0.0,
+            ray.direction.y < 0.0,
+            ray.direction.z < 0.0,
+        ];
+        let mut stack = [0; MAX_DEPTH];
+        let (mut top, mut current) = (0, 0);
+        let mut steps = 0;
+        loop {
+            let node = &self.nodes[current];
+            steps += 1;
+            if node.bbox.hit(ray, t_min, t_max) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    let items = &self.items[first..first + node.count as usize];
+                    steps += items.len() as u32;
+                    match visit(items, t_max) {
+                        Some(t) => t_max = t,
+                        None => return steps,
+                    }
+                } else {
+                    // Visit the nearer child first.
+                    let second = node.offset as usize;
+                    let (near, far) = if negative[node.axis as usize] {
+                        (second, current + 1)
+                    } else {
+                        (current + 1, second)
+                    };
+                    stack[top] = far;
+                    top += 1;
+                    current = near;
+                    continue;
+                }
+            }
+            if top == 0 {
+                return steps;
+            }
+            top -= 1;
+            current = stack[top];
+        }
+    }
+
+    /// Whether anything is hit in `(t_min, t_max)`, stopping at the first.
+    pub fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let mut occluded = false;
+        self.traverse(ray, t_min, t_max, |items, t_max| {
+            occluded = items
+                .iter()
+                .any(|item| item.hit(ray, t_min, t_max).is_some());
+            if occluded {
+                None
+            } else {
+                Some(t_max)
+            }
+        });
+        occluded
+    }
+
+    /// Number of bounding boxes and items `hit` tests along the ray.
+    pub fn traversal_steps(&self, ray: &Ray, t_min: Float, t_max: Float) -> u32 {
+        self.closest(ray, t_min, t_max).1
+    }
+
+    fn closest(&self, ray: &Ray, t_min: Float, t_max: Float) -> (Option<HitRecord<'_>>, u32) {
+        let mut record = None;
+        let steps = self.traverse(ray, t_min, t_max, |items, mut closest| {
+            for item in items {
+                if let Some(hit) = item.hit(ray, t_min, closest) {
+                    closest = hit.pos;
+                    record = Some(hit);
+                }
+            }
+            Some(closest)
+        });
+        (record, steps)
+    }
+}
+
+impl<T: Hitable> Hitable for Bvh<T> {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.closest(ray, t_min, t_max).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::EPSILON;
+    use crate::scene::material::Material;
+    use crate::scene::shape::{Primitive, Shape, Sphere, Triangle, TriangleMesh};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use std::sync::Arc;
+
+    fn point<R: Rng>(rng: &mut R, extent: Float) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(-extent, extent),
+            rng.gen_range(-extent, extent),
+            rng.gen_range(-extent, extent),
+        )
+    }
+
+    fn primitive(shape: Shape, id: usize) -> Primitive {
+        Primitive {
+            shape,
+            material: Arc::new(Material::Lambertian(Vec3::new(0.5, 0.5, 0.5))),
+            light: None,
+            id,
+            material_id: 0,
+        }
+    }
+
+    fn sphere(center: Vec3, radius: Float, id: usize) -> Primitive {
+        primitive(Shape::Sphere(Sphere { center, radius }), id)
+    }
+
+    /// `spheres` spheres and `triangles` small triangles, scattered through
+    /// a box ten units either side of the origin.
+    fn scatter<R: Rng>(rng: &mut R, spheres: usize, triangles: usize) -> Vec<Primitive> {
+        let mut items: Vec<Primitive> = (0..spheres)
+            .map(|id| {
+                let center = point(rng, 10.0);
+                sphere(center, rng.gen_range(0.05, 1.0), id)
+            })
+            .collect();
+        let mut positions = Vec::new();
+        for _ in 0..triangles {
+            let corner = point(rng, 10.0);
+            for _ in 0..3 {
+                positions.push(&corner + &point(rng, 1.0));
+            }
+        }
+        let mesh = Arc::new(TriangleMesh {
+            indices: (0..triangles)
+                .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+                .collect(),
+            positions,
+            normals: None,
+            tangents: None,
+            uvs: None,
+            reverse_orientation: false,
+        });
+        items.extend((0..triangles).map(|index| {
+            let triangle = Triangle {
+                mesh: Arc::clone(&mesh),
+                index,
+            };
+            primitive(Shape::Triangle(triangle), spheres + index)
+        }));
+        items
+    }
+
+    /// Random rays from around the items, aimed near them, and a random
+    /// end for the ones asking about occlusion.
+    fn rays<R: Rng>(rng: &mut R, count: usize) -> Vec<(Ray, Float)> {
+        (0..count)
+            .map(|_| {
+                let origin = point(rng, 15.0);
+                let target = point(rng, 10.0);
+                let t_max = if rng.gen() {
+                    Float::INFINITY
+                } else {
+                    rng.gen_range(0.0, 1.0)
+                };
+                (Ray::new(origin.clone(), &target - &origin, 0.0), t_max)
+            })
+            .collect()
+    }
+
+    /// Checks `bvh` against testing every item in turn.
+    fn check(bvh: &Bvh<Primitive>, items: &[Primitive], rays: &[(Ray, Float)]) {
+        for (ray, t_max) in rays {
+            let mut closest = None;
+            let mut t = Float::INFINITY;
+            for item in items {
+                if let Some(hit) = item.hit(ray, EPSILON, t) {
+                    t = hit.pos;
+                    closest = Some(hit.pos);
+                }
+            }
+            let hit = bvh.hit(ray, EPSILON, Float::INFINITY);
+            assert_eq!(hit.as_ref().map(|hit| hit.pos), closest);
+            if let Some(hit) = hit {
+                // Coincident items may tie; whichever it was must be there.
+                let item = items.iter().find(|item| item.id == hit.primitive).unwrap();
+                assert_eq!(
+                    item.hit(ray, EPSILON, Float::INFINITY).map(|h| h.pos),
+                    Some(hit.pos)
+                );
+            }
+
+            let occluded = items
+                .iter()
+                .any(|item| item.hit(ray, EPSILON, *t_max).is_some());
+            assert_eq!(bvh.occluded(ray, EPSILON, *t_max), occluded);
+        }
+
+        let bounds = items
+            .iter()
+            .map(Boxable::get_bbox)
+            .reduce(|a, b| a.absorb(&b));
+        assert_eq!(bvh.bounds().map(corners), bounds.map(corners));
+    }
+
+    fn corners(bbox: AABB) -> [Float; 6] {
+        let (min, max) = (bbox.min, bbox.max);
+        [min.x, min.y, min.z, max.x, max.y, max.z]
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for &(spheres, triangles) in &[(2, 3), (40, 0), (0, 40), (300, 300)] {
+            let items = scatter(&mut rng, spheres, triangles);
+            let bvh = Bvh::new(items.clone());
+            check(&bvh, &items, &rays(&mut rng, 2000));
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let bvh = Bvh::new(Vec::new());
+        check(&bvh, &[], &rays(&mut rng, 100));
+    }
+
+    #[test]
+    fn single_item() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let items = vec![sphere(Vec3::new(1.0, -2.0, 3.0), 4.0, 0)];
+        let bvh = Bvh::new(items.clone());
+        check(&bvh, &items, &rays(&mut rng, 1000));
+    }
+
+    #[test]
+    fn coincident_centroids() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let center = Vec3::new(0.5, 0.5, 0.5);
+        // Nested spheres, some of them twice.
+        let items: Vec<Primitive> = (0..40)
+            .map(|id| sphere(center.clone(), 1.0 + (id % 25) as Float * 0.3, id))
+            .collect();
+        let bvh = Bvh::new(items.clone());
+        check(&bvh, &items, &rays(&mut rng, 2000));
+
+        // The same, among other things.
+        let mut items = items;
+        items.extend(scatter(&mut rng, 100, 100).into_iter().map(|mut item| {
+            item.id += 40;
+            item
+        }));
+        let bvh = Bvh::new(items.clone());
+        check(&bvh, &items, &rays(&mut rng, 2000));
+    }
+
+    #[test]
+    fn refit_matches_a_new_build() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let items = scatter(&mut rng, 200, 200);
+        let mesh = items
+            .iter()
+            .find_map(|item| match &item.shape {
+                Shape::Triangle(triangle) => Some(Arc::clone(&triangle.mesh)),
+                _ => None,
+            })
+            .unwrap();
+        let mut moved = (*mesh).clone();
+        for p in moved.positions.iter_mut() {
+            *p = &*p + &point(&mut rng, 2.0);
+        }
+        let moved = Arc::new(moved);
+
+        // Spheres move and grow by amounts that depend on which they are.
+        let update = |item: &mut Primitive| match &mut item.shape {
+            Shape::Sphere(sphere) => {
+                let k = item.id as Float;
+                let offset = Vec3::new((k * 0.37).sin(), (k * 0.71).cos(), (k * 0.13).sin());
+                sphere.center = &sphere.center + &(offset * 3.0);
+                sphere.radius *= 1.0 + (item.id % 3) as Float * 0.5;
+            }
+            Shape::Triangle(triangle) => triangle.mesh = Arc::clone(&moved),
+        };
+
+        let mut refitted = Bvh::new(items.clone());
+        refitted.refit(update);
+        let mut items = items;
+        items.iter_mut().for_each(update);
+        let fresh = Bvh::new(items.clone());
+
+        let rays = rays(&mut rng, 2000);
+        check(&refitted, &items, &rays);
+        for (ray, _) in &rays {
+            assert_eq!(
+                refitted
+                    .hit(ray, EPSILON, Float::INFINITY)
+                    .map(|hit| hit.pos),
+                fresh.hit(ray, EPSILON, Float::INFINITY).map(|hit| hit.pos)
+            );
+        }
+        assert_eq!(refitted.bounds().map(corners), fresh.bounds().map(corners));
+    }
+
+    #[test]
+    fn exponentially_spaced_items() {
+        let x = |base: Float, i: usize| base.powi(i as i32);
+        // Along one axis, and spread by more than there are bins along
+        // each in turn, so that each split can only peel off the furthest
+        // item or few.
+        let layouts: [&dyn Fn(usize) -> Vec3; 2] = [&|i| Vec3::new(x(2.0, i), 0.0, 0.0), &|i| {
+            let x = x(13.0, i / 3);
+            let mut v = [0.0; 3];
+            v[i % 3] = x;
+            Vec3::new(v[0], v[1], v[2])
+        }];
+        for center in &layouts {
+            let items: Vec<Primitive> = (0..100)
+                .map(|i| {
+                    let center = center(i);
+                    let radius = center.max_component() / 4.0;
+                    sphere(center, radius, i)
+                })
+                .collect();
+            let bvh = Bvh::new(items.clone());
+
+            // Straight down onto each item, and along each axis.
+            let mut rays: Vec<(Ray, Float)> = items
+                .iter()
+                .map(|item| {
+                    let bbox = item.get_bbox();
+                    let (center, height) = (bbox.centroid(), bbox.max.z - bbox.min.z);
+                    let above = Vec3::new(center.x, center.y, bbox.max.z + height);
+                    let ray = Ray::new(above, Vec3::new(0.0, 0.0, -1.0), 0.0);
+                    (ray, Float::INFINITY)
+                })
+                .collect();
+            for axis in &[
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+            ] {
+                rays.push((
+                    Ray::new(Vec3::new(-1.0, -1.0, -1.0), axis.clone(), 0.0),
+                    Float::INFINITY,
+                ));
+            }
+            check(&bvh, &items, &rays);
+        }
+    }
+}
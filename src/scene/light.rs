@@ -0,0 +1,421 @@
+use crate::sample::*;
+use crate::scene::shape::{Shape, AABB};
+use crate::vec::*;
+
+use std::f32::consts::PI;
+
+pub enum Light {
+    /// pbrt "point": radiant intensity `intensity` from a single point.
+    Point { position: Vec3, intensity: Vec3 },
+    /// pbrt "spot": a point light restricted to a cone, with a smooth
+    /// falloff between `cos_falloff` and `cos_total`.
+    Spot {
+        position: Vec3,
+        frame: Frame,
+        intensity: Vec3,
+        cos_total: Float,
+        cos_falloff: Float,
+    },
+    /// pbrt "distant": parallel light arriving from `direction`.
+    Distant {
+        direction: Vec3,
+        radiance: Vec3,
+        world_center: Vec3,
+        world_radius: Float,
+    },
+    /// pbrt "infinite" without an environment map: constant radiance from
+    /// every direction.
+    Infinite {
+        radiance: Vec3,
+        world_center: Vec3,
+        world_radius: Float,
+    },
+    /// pbrt "diffuse" area light attached to a shape.
+    Area {
+        shape: Shape,
+        emission: Vec3,
+        two_sided: bool,
+    },
+}
+
+/// Incident illumination at a point from a sampled point on a light.
+pub struct LightSample {
+    /// Unit direction from the reference point towards the light.
+    pub wi: Vec3,
+    pub radiance: Vec3,
+    /// With respect to solid angle; 1 for delta lights.
+    pub pdf: Float,
+    /// Distance to the sampled point, for the shadow ray.
+    pub distance: Float,
+    /// Surface normal at the sampled point; zero for lights that aren't
+    /// surfaces.
+    pub normal: Vec3,
+}
+
+/// A ray of light leaving a light source.
+pub struct EmissionSample {
+    pub ray: Ray,
+    /// Normal at the ray's origin; the ray direction for point lights.
+    pub normal: Vec3,
+    pub radiance: Vec3,
+    /// With respect to area; 1 for lights at a single point.
+    pub pdf_pos: Float,
+    /// With respect to solid angle; 1 for directional lights.
+    pub pdf_dir: Float,
+}
+
+impl Light {
+    /// Lets lights that depend on the extent of the scene find out about it.
+    pub fn preprocess(&mut self, bounds: &AABB) {
+        let (center, radius) = bounds.bounding_sphere();
+        match self {
+            Light::Distant {
+                world_center,
+                world_radius,
+                ..
+            }
+            | Light::Infinite {
+                world_center,
+                world_radius,
+                ..
+            } => {
+                *world_center = center;
+                *world_radius = radius;
+            }
+            _ => (),
+        }
+    }
+
+    /// Point and directional lights can't be hit by rays, so they're only
+    /// ever reached by sampling them.
+    pub fn is_delta(&self) -> bool {
+        matches!(
+            self,
+            Light::Point { .. } | Light::Spot { .. } | Light::Distant { .. }
+        )
+    }
+
+    pub fn is_infinite(&self) -> bool {
+        matches!(self, Light::Infinite { .. } | Light::Distant { .. })
+    }
+
+    pub fn sample_li(&self, point: &Vec3, u: (Float, Float)) -> Option<LightSample> {
+        match self {
+            Light::Point {
+                position,
+                intensity,
+            } => {
+                let to_light = position - point;
+                let distance = to_light.norm();
+                Some(LightSample {
+                    wi: &to_light / distance,
+                    radiance: intensity / (distance * distance),
+                    pdf: 1.0,
+                    distance,
+                    normal: Vec3::new(0.0, 0.0, 0.0),
+                })
+            }
+            Light::Spot {
+                position,
+                frame,
+                intensity,
+                cos_total,
+                cos_falloff,
+            } => {
+                let to_light = position - point;
+                let distance = to_light.norm();
+                let wi = &to_light / distance;
+                let falloff = spot_falloff(&frame.to_local(&wi.negate()), *cos_total, *cos_falloff);
+                Some(LightSample {
+                    wi,
+                    radiance: intensity * (falloff / (distance * distance)),
+                    pdf: 1.0,
+                    distance,
+                    normal: Vec3::new(0.0, 0.0, 0.0),
+                })
+            }
+            Light::Distant {
+                direction,
+                radiance,
+                world_radius,
+                ..
+            } => Some(LightSample {
+                wi: direction.clone(),
+                radiance: radiance.clone(),
+                pdf: 1.0,
+                distance: 2.0 * world_radius,
+                normal: Vec3::new(0.0, 0.0, 0.0),
+            }),
+            Light::Infinite {
+                radiance,
+                world_radius,
+                ..
+            } => Some(LightSample {
+                wi: uniform_sphere(u),
+                radiance: radiance.clone(),
+                pdf: uniform_sphere_pdf(),
+                distance: 2.0 * world_radius,
+                normal: Vec3::new(0.0, 0.0, 0.0),
+            }),
+            Light::Area {
+                shape,
+                emission,
+                two_sided,
+            } => {
+                let sample = shape.sample(point, u)?;
+                let to_light = &sample.point - point;
+                let distance = to_light.norm();
+                if distance == 0.0 || sample.pdf <= 0.0 {
+                    return None;
+                }
+                let wi = &to_light / distance;
+                Some(LightSample {
+                    radiance: area_radiance(emission, *two_sided, &sample.normal, &wi.negate()),
+                    wi,
+                    pdf: sample.pdf,
+                    distance,
+                    normal: sample.normal,
+                })
+            }
+        }
+    }
+
+    /// Solid-angle density of `sample_li` choosing `wi` from `point`.
+    pub fn pdf_li(&self, point: &Vec3, wi: &Vec3) -> Float {
+        match self {
+            Light::Infinite { .. } => uniform_sphere_pdf(),
+            Light::Area { shape, .. } => shape.pdf(point, wi),
+            _ => 0.0,
+        }
+    }
+
+    /// Samples a ray leaving the light, for tracing paths from the lights.
+    pub fn sample_le(&self, u1: (Float, Float), u2: (Float, Float)) -> Option<EmissionSample> {
+        match self {
+            Light::Point {
+                position,
+                intensity,
+            } => {
+                let direction = uniform_sphere(u1);
+                Some(EmissionSample {
+                    ray: Ray::new(position.clone(), direction.clone(), 0.0),
+                    normal: direction,
+                    radiance: intensity.clone(),
+                    pdf_pos: 1.0,
+                    pdf_dir: uniform_sphere_pdf(),
+                })
+            }
+            Light::Spot {
+                position,
+                frame,
+                intensity,
+                cos_total,
+                cos_falloff,
+            } => {
+                let local = uniform_cone(u1, *cos_total);
+                let direction = frame.to_world(&local);
+                Some(EmissionSample {
+                    ray: Ray::new(position.clone(), direction.clone(), 0.0),
+                    normal: direction,
+                    radiance: intensity * spot_falloff(&local, *cos_total, *cos_falloff),
+                    pdf_pos: 1.0,
+                    pdf_dir: uniform_cone_pdf(*cos_total),
+                })
+            }
+            Light::Distant {
+                direction,
+                radiance,
+                world_center,
+                world_radius,
+            } => {
+                // Start on a disk covering the scene, just outside it.
+                let origin = disk_point(world_center, *world_radius, direction, u1)
+                    + direction * *world_radius;
+                let w = direction.negate();
+                Some(EmissionSample {
+                    ray: Ray::new(origin, w.clone(), 0.0),
+                    normal: w,
+                    radiance: radiance.clone(),
+                    pdf_pos: 1.0 / (PI * world_radius * world_radius),
+                    pdf_dir: 1.0,
+                })
+            }
+            Light::Infinite {
+                radiance,
+                world_center,
+                world_radius,
+            } => {
+                let w = uniform_sphere(u1).negate();
+                let origin = disk_point(world_center, *world_radius, &w, u2) - &w * *world_radius;
+                Some(EmissionSample {
+                    ray: Ray::new(origin, w.clone(), 0.0),
+                    normal: w,
+                    radiance: radiance.clone(),
+                    pdf_pos: 1.0 / (PI * world_radius * world_radius),
+                    pdf_dir: uniform_sphere_pdf(),
+                })
+            }
+            Light::Area {
+                shape,
+                emission,
+                two_sided,
+            } => {
+                let (point, normal) = shape.sample_area(u1);
+                let (local, pdf_dir) = if *two_sided {
+                    // Use the first half of u2.0 for one side, the other
+                    // half for the other.
+                    let side = u2.0 < 0.5;
+                    let u0 = if side { u2.0 * 2.0 } else { (u2.0 - 0.5) * 2.0 };
+                    let mut local = cosine_hemisphere((u0.min(1.0 - Float::EPSILON), u2.1));
+                    if !side {
+                        local.z = -local.z;
+                    }
+                    let pdf = 0.5 * cosine_hemisphere_pdf(local.z.abs());
+                    (local, pdf)
+                } else {
+                    let local = cosine_hemisphere(u2);
+                    let pdf = cosine_hemisphere_pdf(local.z);
+                    (local, pdf)
+                };
+                if pdf_dir <= 0.0 {
+                    return None;
+                }
+                let direction = Frame::new(&normal).to_world(&local);
+                Some(EmissionSample {
+                    radiance: area_radiance(emission, *two_sided, &normal, &direction),
+                    ray: Ray::new(point, direction, 0.0),
+                    normal,
+                    pdf_pos: 1.0 / shape.area(),
+                    pdf_dir,
+                })
+            }
+        }
+    }
+
+    /// Densities of `sample_le` choosing a ray in direction `w` from a
+    /// point with normal `n`: `(pdf_pos, pdf_dir)`.
+    pub fn pdf_le(&self, w: &Vec3, n: &Vec3) -> (Float, Float) {
+        match self {
+            Light::Point { .. } => (0.0, uniform_sphere_pdf()),
+            Light::Spot {
+                frame, cos_total, ..
+            } => {
+                if frame.to_local(w).z >= *cos_total {
+                    (0.0, uniform_cone_pdf(*cos_total))
+                } else {
+                    (0.0, 0.0)
+                }
+            }
+            Light::Distant { world_radius, .. } => (1.0 / (PI * world_radius * world_radius), 0.0),
+            Light::Infinite { world_radius, .. } => (
+                1.0 / (PI * world_radius * world_radius),
+                uniform_sphere_pdf(),
+            ),
+            Light::Area {
+                shape, two_sided, ..
+            } => {
+                let cos = n % w;
+                let pdf_dir = if *two_sided {
+                    0.5 * cosine_hemisphere_pdf(cos.abs())
+                } else {
+                    cosine_hemisphere_pdf(cos)
+                };
+                (1.0 / shape.area(), pdf_dir)
+            }
+        }
+    }
+
+    /// Radiance leaving a point on an area light with normal `n` in
+    /// direction `w`.
+    pub fn l(&self, n: &Vec3, w: &Vec3) -> Vec3 {
+        match self {
+            Light::Area {
+                emission,
+                two_sided,
+                ..
+            } => area_radiance(emission, *two_sided, n, w),
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// What leaves the point `sample_le` chose, with normal `n`, in
+    /// direction `w`: radiance for area lights, intensity for point and spot
+    /// lights. Directional lights can't be seen from a single point.
+    pub fn emitted_towards(&self, n: &Vec3, w: &Vec3) -> Vec3 {
+        match self {
+            Light::Point { intensity, .. } => intensity.clone(),
+            Light::Spot {
+                frame,
+                intensity,
+                cos_total,
+                cos_falloff,
+                ..
+            } => intensity * spot_falloff(&frame.to_local(w), *cos_total, *cos_falloff),
+            Light::Area { .. } => self.l(n, w),
+            Light::Distant { .. } | Light::Infinite { .. } => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Radiance arriving along a ray that left the scene.
+    pub fn le(&self, _ray: &Ray) -> Vec3 {
+        match self {
+            Light::Infinite { radiance, .. } => radiance.clone(),
+            _ => Vec3::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Total emitted power, for choosing lights in proportion to it.
+    pub fn power(&self) -> Vec3 {
+        match self {
+            Light::Point { intensity, .. } => intensity * (4.0 * PI),
+            Light::Spot {
+                intensity,
+                cos_total,
+                cos_falloff,
+                ..
+            } => intensity * (2.0 * PI * (1.0 - 0.5 * (cos_falloff + cos_total))),
+            Light::Distant {
+                radiance,
+                world_radius,
+                ..
+            } => radiance * (PI * world_radius * world_radius),
+            Light::Infinite {
+                radiance,
+                world_radius,
+                ..
+            } => radiance * (4.0 * PI * PI * world_radius * world_radius),
+            Light::Area {
+                shape,
+                emission,
+                two_sided,
+            } => emission * (PI * shape.area() * if *two_sided { 2.0 } else { 1.0 }),
+        }
+    }
+}
+
+fn area_radiance(emission: &Vec3, two_sided: bool, n: &Vec3, w: &Vec3) -> Vec3 {
+    if two_sided || n % w > 0.0 {
+        emission.clone()
+    } else {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+}
+
+/// Uniformly distributed point on the disk of radius `radius` through
+/// `center`, perpendicular to `axis`.
+fn disk_point(center: &Vec3, radius: Float, axis: &Vec3, u: (Float, Float)) -> Vec3 {
+    let frame = Frame::new(axis);
+    let (x, y) = concentric_disk(u);
+    center + frame.to_world(&Vec3::new(x * radius, y * radius, 0.0))
+}
+
+fn spot_falloff(w: &Vec3, cos_total: Float, cos_falloff: Float) -> Float {
+    if w.z < cos_total {
+        0.0
+    } else if w.z >= cos_falloff {
+        1.0
+    } else {
+        let delta = (w.z - cos_total) / (cos_falloff - cos_total);
+        (delta * delta) * (delta * delta)
+    }
+}
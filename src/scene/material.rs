@@ -0,0 +1,252 @@
+use crate::sample::*;
+use crate::vec::*;
+
+use Material::*;
+
+use std::f32::consts::PI;
+
+/// What a surface does to light. All directions are in world space and
+/// point away from the surface; `n` is the geometric normal, which may
+/// face either way.
+#[derive(Clone, Debug)]
+pub enum Material {
+    /// pbrt's "matte": ideal diffuse reflection.
+    Lambertian(Vec3),
+    /// pbrt's "mirror": perfect specular reflection.
+    Mirror(Vec3),
+    /// pbrt's "glass": specular reflection and refraction with the exact
+    /// dielectric Fresnel term. Fields are reflectance, transmittance and IOR.
+    Dielectric(Vec3, Vec3, Float),
+    /// pbrt's "metal", approximated by a normalised Phong lobe around the
+    /// mirror direction. Fields are normal-incidence reflectance and Phong
+    /// exponent.
+    Metal(Vec3, Float),
+    /// pbrt's "plastic": a diffuse base under a glossy Phong coat. Fields
+    /// are diffuse reflectance, specular reflectance and Phong exponent.
+    Plastic(Vec3, Vec3, Float),
+}
+
+/// Whether light or importance is being carried along a path. Refraction
+/// scales radiance by the squared ratio of IORs, but not importance.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Transport {
+    Radiance,
+    Importance,
+}
+
+pub struct BsdfSample {
+    pub wi: Vec3,
+    /// For specular lobes this already includes the `1 / |cos|` factor, so
+    /// `f * |cos| / pdf` is always the path throughput update.
+    pub f: Vec3,
+    pub pdf: Float,
+    pub specular: bool,
+    /// Ratio of the IORs on either side, `eta_t / eta_i`, if `wi` was
+    /// refracted; 1 otherwise.
+    pub eta: Float,
+}
+
+/// Converts a pbrt roughness into a Phong exponent with a similar lobe width.
+pub fn roughness_to_exponent(roughness: Float) -> Float {
+    let alpha = roughness.max(1e-3);
+    (2.0 / (alpha * alpha) - 2.0).clamp(1.0, 1e5)
+}
+
+impl Material {
+    /// True if the material has only delta lobes, so light sampling and
+    /// BSDF evaluation are pointless.
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Mirror(_) | Dielectric(..))
+    }
+
+    /// Rough colour of the surface, for the albedo AOV.
+    pub fn albedo(&self) -> Vec3 {
+        match self {
+            Lambertian(r) => r.clone(),
+            Mirror(r) => r.clone(),
+            Dielectric(r, t, _) => (r + t) * 0.5,
+            Metal(r, _) => r.clone(),
+            Plastic(d, s, _) => d + s,
+        }
+    }
+
+    pub fn f(&self, wo: &Vec3, wi: &Vec3, n: &Vec3) -> Vec3 {
+        let (cos_o, cos_i) = (wo % n, wi % n);
+        let black = Vec3::new(0.0, 0.0, 0.0);
+        if cos_o * cos_i <= 0.0 {
+            return black;
+        }
+
+        match self {
+            Lambertian(r) => r / PI,
+            Mirror(_) | Dielectric(..) => black,
+            Metal(r, exponent) => fresnel_schlick(r, cos_o.abs()) * phong(wo, wi, n, *exponent),
+            Plastic(d, s, exponent) => d / PI + s * phong(wo, wi, n, *exponent),
+        }
+    }
+
+    pub fn pdf(&self, wo: &Vec3, wi: &Vec3, n: &Vec3) -> Float {
+        let (cos_o, cos_i) = (wo % n, wi % n);
+        if cos_o * cos_i <= 0.0 {
+            return 0.0;
+        }
+
+        match self {
+            Lambertian(_) => cosine_hemisphere_pdf(cos_i.abs()),
+            Mirror(_) | Dielectric(..) => 0.0,
+            Metal(_, exponent) => phong_pdf(wo, wi, n, *exponent),
+            Plastic(d, s, exponent) => {
+                let p = diffuse_probability(d, s);
+                p * cosine_hemisphere_pdf(cos_i.abs()) + (1.0 - p) * phong_pdf(wo, wi, n, *exponent)
+            }
+        }
+    }
+
+    pub fn sample(
+        &self,
+        wo: &Vec3,
+        n: &Vec3,
+        u: (Float, Float),
+        uc: Float,
+        mode: Transport,
+    ) -> Option<BsdfSample> {
+        let cos_o = wo % n;
+        if cos_o == 0.0 {
+            return None;
+        }
+        // Work on the side of the surface `wo` is on.
+        let facing = if cos_o > 0.0 { n.clone() } else { n.negate() };
+
+        match self {
+            Lambertian(_) => {
+                let wi = Frame::new(&facing).to_world(&cosine_hemisphere(u));
+                self.sampled(wo, wi, n)
+            }
+            Mirror(r) => {
+                let wi = reflect(&wo.negate(), &facing);
+                let cos = (&wi % n).abs();
+                Some(BsdfSample {
+                    f: r / cos,
+                    wi,
+                    pdf: 1.0,
+                    specular: true,
+                    eta: 1.0,
+                })
+            }
+            Dielectric(r, t, eta) => {
+                let entering = cos_o > 0.0;
+                let (eta_i, eta_t) = if entering { (1.0, *eta) } else { (*eta, 1.0) };
+                let fresnel = fresnel_dielectric(cos_o.abs(), eta_i, eta_t);
+
+                if uc < fresnel {
+                    let wi = reflect(&wo.negate(), &facing);
+                    let cos = (&wi % n).abs();
+                    return Some(BsdfSample {
+                        f: r * (fresnel / cos),
+                        wi,
+                        pdf: fresnel,
+                        specular: true,
+                        eta: 1.0,
+                    });
+                }
+
+                let wi = refract(&wo.negate(), &facing, eta_i / eta_t)?.to_unit();
+                let cos = (&wi % n).abs();
+                let mut f = t * ((1.0 - fresnel) / cos);
+                if mode == Transport::Radiance {
+                    f = f * ((eta_i * eta_i) / (eta_t * eta_t));
+                }
+                Some(BsdfSample {
+                    f,
+                    wi,
+                    pdf: 1.0 - fresnel,
+                    specular: true,
+                    eta: eta_t / eta_i,
+                })
+            }
+            Metal(_, exponent) => {
+                let wi = sample_phong(wo, &facing, *exponent, u);
+                self.sampled(wo, wi, n)
+            }
+            Plastic(d, s, exponent) => {
+                let wi = if uc < diffuse_probability(d, s) {
+                    Frame::new(&facing).to_world(&cosine_hemisphere(u))
+                } else {
+                    sample_phong(wo, &facing, *exponent, u)
+                };
+                self.sampled(wo, wi, n)
+            }
+        }
+    }
+
+    fn sampled(&self, wo: &Vec3, wi: Vec3, n: &Vec3) -> Option<BsdfSample> {
+        let pdf = self.pdf(wo, &wi, n);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            f: self.f(wo, &wi, n),
+            wi,
+            pdf,
+            specular: false,
+            eta: 1.0,
+        })
+    }
+}
+
+fn diffuse_probability(d: &Vec3, s: &Vec3) -> Float {
+    let (d, s) = (d.luminance(), s.luminance());
+    if d + s <= 0.0 {
+        0.5
+    } else {
+        d / (d + s)
+    }
+}
+
+/// Energy-normalised Phong lobe around the mirror direction of `wo`.
+fn phong(wo: &Vec3, wi: &Vec3, n: &Vec3, exponent: Float) -> Float {
+    let facing = if wo % n > 0.0 { n.clone() } else { n.negate() };
+    let mirror = reflect(&wo.negate(), &facing);
+    let cos = (&mirror % wi).max(0.0);
+    (exponent + 2.0) / (2.0 * PI) * cos.powf(exponent)
+}
+
+fn phong_pdf(wo: &Vec3, wi: &Vec3, n: &Vec3, exponent: Float) -> Float {
+    let facing = if wo % n > 0.0 { n.clone() } else { n.negate() };
+    let mirror = reflect(&wo.negate(), &facing);
+    let cos = (&mirror % wi).max(0.0);
+    (exponent + 1.0) / (2.0 * PI) * cos.powf(exponent)
+}
+
+fn sample_phong(wo: &Vec3, facing: &Vec3, exponent: Float, u: (Float, Float)) -> Vec3 {
+    let mirror = reflect(&wo.negate(), facing);
+    let cos = u.0.powf(1.0 / (exponent + 1.0));
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Frame::new(&mirror).to_world(&Vec3::new(sin * phi.cos(), sin * phi.sin(), cos))
+}
+
+fn fresnel_schlick(r0: &Vec3, cos: Float) -> Vec3 {
+    let k = (1.0 - cos).powi(5);
+    r0 + (Vec3::new(1.0, 1.0, 1.0) - r0) * k
+}
+
+/// Unpolarised Fresnel reflectance at a dielectric boundary.
+pub fn fresnel_dielectric(cos_i: Float, eta_i: Float, eta_t: Float) -> Float {
+    let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    let sin_t = eta_i / eta_t * sin_i;
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+
+    let parallel = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let perpendicular = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+/// Normal-incidence reflectance of a conductor with complex IOR `eta + ik`.
+pub fn conductor_reflectance(eta: &Vec3, k: &Vec3) -> Vec3 {
+    let r = |eta: Float, k: Float| ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k);
+    Vec3::new(r(eta.x, k.x), r(eta.y, k.y), r(eta.z, k.z))
+}
@@ -1,3 +1,229 @@
-pub trait Scene {
-    // TODO
+pub mod bvh;
+pub mod light;
+pub mod material;
+pub mod shape;
+
+use crate::vec::{Float, Ray, Vec3};
+
+use bvh::Bvh;
+use light::Light;
+use shape::{Boxable, HitRecord, Hitable, Primitive, AABB};
+
+use std::cell::Cell;
+use std::sync::Arc;
+
+thread_local! {
+    static RAYS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// How many rays this thread has traced through a `World` since the last
+/// call.
+pub fn take_ray_count() -> u64 {
+    RAYS.with(|rays| rays.replace(0))
+}
+
+pub trait Scene: Sync {
+    /// Closest intersection with `t` in `(t_min, t_max)`.
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
+
+    /// Shadow-ray query: is there anything at all in `(t_min, t_max)`?
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        self.hit(ray, t_min, t_max).is_some()
+    }
+
+    /// How much work `hit` does for this ray, for visualizing the
+    /// acceleration structure.
+    fn traversal_steps(&self, _ray: &Ray, _t_min: Float, _t_max: Float) -> u32 {
+        0
+    }
+
+    fn lights(&self) -> &[Light];
+
+    fn bounds(&self) -> AABB;
+}
+
+/// The scene as described by a pbrt file: primitives in an acceleration
+/// structure, plus the lights.
+pub struct World {
+    primitives: Bvh<Primitive>,
+    lights: Vec<Light>,
+    bbox: AABB,
+}
+
+impl World {
+    pub fn new(mut primitives: Vec<Primitive>, mut lights: Vec<Light>) -> World {
+        let mut materials = Vec::new();
+        for (id, primitive) in primitives.iter_mut().enumerate() {
+            primitive.id = id;
+            primitive.material_id = match materials
+                .iter()
+                .position(|m| Arc::ptr_eq(m, &primitive.material))
+            {
+                Some(index) => index,
+                None => {
+                    materials.push(primitive.material.clone());
+                    materials.len() - 1
+                }
+            };
+        }
+
+        let bbox = if primitives.is_empty() {
+            AABB {
+                min: Vec3::new(0.0, 0.0, 0.0),
+                max: Vec3::new(0.0, 0.0, 0.0),
+            }
+        } else {
+            primitives.get_bbox()
+        };
+
+        for light in lights.iter_mut() {
+            light.preprocess(&bbox);
+        }
+
+        World {
+            primitives: Bvh::new(primitives),
+            lights,
+            bbox,
+        }
+    }
+
+    /// Moves things between frames of an animation: `update` is called on
+    /// every primitive, and may change its shape's position or a mesh's
+    /// vertices, but not what's in the scene. A mesh's triangles share it,
+    /// so to move its vertices, clone it once with the new positions and
+    /// point each of its triangles at the copy. The hierarchy is refitted
+    /// rather than built again, and area lights follow their shapes.
+    pub fn refit(&mut self, update: impl Fn(&mut Primitive) + Send + Sync) {
+        self.primitives.refit(update);
+        let lights = &mut self.lights;
+        for primitive in self.primitives.items() {
+            if let Some(Light::Area { shape, .. }) = primitive.light.map(|i| &mut lights[i]) {
+                *shape = primitive.shape.clone();
+            }
+        }
+
+        if let Some(bbox) = self.primitives.bounds() {
+            self.bbox = bbox;
+        }
+        for light in self.lights.iter_mut() {
+            light.preprocess(&self.bbox);
+        }
+    }
+}
+
+impl Scene for World {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        RAYS.with(|rays| rays.set(rays.get() + 1));
+        self.primitives.hit(ray, t_min, t_max)
+    }
+
+    fn occluded(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        RAYS.with(|rays| rays.set(rays.get() + 1));
+        self.primitives.occluded(ray, t_min, t_max)
+    }
+
+    fn traversal_steps(&self, ray: &Ray, t_min: Float, t_max: Float) -> u32 {
+        self.primitives.traversal_steps(ray, t_min, t_max)
+    }
+
+    fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    fn bounds(&self) -> AABB {
+        self.bbox.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::EPSILON;
+    use material::Material;
+    use shape::{Shape, Sphere, Triangle, TriangleMesh};
+
+    fn primitive(shape: Shape, light: Option<usize>) -> Primitive {
+        Primitive {
+            shape,
+            material: Arc::new(Material::Lambertian(Vec3::new(0.5, 0.5, 0.5))),
+            light,
+            id: 0,
+            material_id: 0,
+        }
+    }
+
+    fn down(x: Float, y: Float) -> Ray {
+        Ray::new(Vec3::new(x, y, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0)
+    }
+
+    #[test]
+    fn refit_moves_shapes_meshes_and_lights() {
+        let sphere = Shape::Sphere(Sphere {
+            center: Vec3::new(0.0, 0.0, 0.0),
+            radius: 1.0,
+        });
+        let mesh = Arc::new(TriangleMesh {
+            indices: vec![[0, 1, 2], [0, 2, 3]],
+            positions: vec![
+                Vec3::new(4.0, -1.0, 0.0),
+                Vec3::new(6.0, -1.0, 0.0),
+                Vec3::new(6.0, 1.0, 0.0),
+                Vec3::new(4.0, 1.0, 0.0),
+            ],
+            normals: None,
+            tangents: None,
+            uvs: None,
+            reverse_orientation: false,
+        });
+        let mut primitives = vec![primitive(sphere.clone(), Some(0))];
+        primitives.extend((0..2).map(|index| {
+            let triangle = Triangle {
+                mesh: Arc::clone(&mesh),
+                index,
+            };
+            primitive(Shape::Triangle(triangle), None)
+        }));
+        let light = Light::Area {
+            shape: sphere,
+            emission: Vec3::new(1.0, 1.0, 1.0),
+            two_sided: false,
+        };
+        let mut world = World::new(primitives, vec![light]);
+
+        let mut moved = (*mesh).clone();
+        for p in moved.positions.iter_mut() {
+            p.y += 10.0;
+        }
+        let moved = Arc::new(moved);
+        world.refit(|primitive| match &mut primitive.shape {
+            Shape::Sphere(sphere) => sphere.center.x -= 10.0,
+            Shape::Triangle(triangle) => triangle.mesh = Arc::clone(&moved),
+        });
+
+        assert!(world
+            .hit(&down(0.0, 0.0), EPSILON, Float::INFINITY)
+            .is_none());
+        assert!(world
+            .hit(&down(5.0, 0.0), EPSILON, Float::INFINITY)
+            .is_none());
+        let hit = world
+            .hit(&down(-10.0, 0.0), EPSILON, Float::INFINITY)
+            .unwrap();
+        assert_eq!(hit.light, Some(0));
+        assert!(world
+            .hit(&down(5.0, 10.0), EPSILON, Float::INFINITY)
+            .is_some());
+
+        match &world.lights()[0] {
+            Light::Area {
+                shape: Shape::Sphere(sphere),
+                ..
+            } => assert_eq!(sphere.center.x, -10.0),
+            _ => panic!("the light isn't a sphere any more"),
+        }
+        let bounds = world.bounds();
+        let corners = |v: &Vec3| [v.x, v.y, v.z];
+        assert_eq!(corners(&bounds.min), [-11.0, -1.0, -1.0]);
+        assert_eq!(corners(&bounds.max), [6.0, 11.0, 1.0]);
+    }
 }
@@ -0,0 +1,743 @@
+use crate::sample::*;
+use crate::scene::material::Material;
+use crate::vec::*;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+pub struct HitRecord<'a> {
+    pub point: Vec3,
+    /// Shading normal.
+    pub normal: Vec3,
+    /// Normal of the surface as intersected, before any interpolation.
+    pub geometric_normal: Vec3,
+    pub pos: Float,
+    /// Surface parameterization at the hit, each in `[0, 1]` unless a mesh
+    /// says otherwise.
+    pub uv: (Float, Float),
+    /// Only triangles have barycentric coordinates.
+    pub barycentric: Option<(Float, Float)>,
+    /// Unit shading tangent, along increasing u, where there is one.
+    pub tangent: Option<Vec3>,
+    pub material: &'a Material,
+    /// Index into `Scene::lights` if the surface is emissive.
+    pub light: Option<usize>,
+    /// Which primitive and which material were hit, numbered from 0 in
+    /// the order they were defined.
+    pub primitive: usize,
+    pub material_id: usize,
+}
+
+pub trait Hitable {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
+}
+
+pub trait Boxable {
+    fn get_bbox(&self) -> AABB;
+}
+
+impl<T: Boxable> Boxable for Vec<T> {
+    fn get_bbox(&self) -> AABB {
+        self.iter()
+            .map(|i| i.get_bbox())
+            .reduce(|a, b| a.absorb(&b))
+            .unwrap() // Don't call w/ empty vectors
+    }
+}
+
+/// A point sampled on a shape, as seen from some reference point.
+pub struct ShapeSample {
+    pub point: Vec3,
+    pub normal: Vec3,
+    /// With respect to solid angle at the reference point.
+    pub pdf: Float,
+}
+
+#[derive(Clone)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: Float,
+}
+
+impl Sphere {
+    /// Distance along the ray to the nearest intersection in range, and the
+    /// outward normal there.
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<(Float, Vec3)> {
+        let oc = &ray.origin - &self.center;
+        let a = &ray.direction % &ray.direction;
+        let b = &oc % &ray.direction;
+        let c = (&oc % &oc) - (self.radius * self.radius);
+        let discriminant = (b * b) - (a * c);
+
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        for temp in &[
+            (-b - discriminant.sqrt()) / a,
+            (-b + discriminant.sqrt()) / a,
+        ] {
+            if *temp < t_max && *temp > t_min {
+                let point = ray.point_at(*temp);
+                let normal = ((&point - &self.center) / self.radius).to_unit();
+                return Some((*temp, normal));
+            }
+        }
+
+        None
+    }
+
+    pub fn area(&self) -> Float {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    /// pbrt's parameterization: u around the z axis, v from the bottom.
+    fn uv(&self, point: &Vec3) -> (Float, Float) {
+        let p = point - &self.center;
+        let phi = p.y.atan2(p.x).rem_euclid(2.0 * PI);
+        let theta = (p.z / self.radius).clamp(-1.0, 1.0).acos();
+        (phi / (2.0 * PI), 1.0 - theta / PI)
+    }
+
+    /// Uniformly distributed point on the surface, with its normal.
+    pub fn sample_area(&self, u: (Float, Float)) -> (Vec3, Vec3) {
+        let normal = uniform_sphere(u);
+        (&self.center + &normal * self.radius, normal)
+    }
+
+    /// Samples the cone of directions the sphere subtends from `reference`,
+    /// or the whole surface if `reference` is inside it.
+    pub fn sample(&self, reference: &Vec3, u: (Float, Float)) -> Option<ShapeSample> {
+        let to_center = &self.center - reference;
+        let dist_sq = &to_center % &to_center;
+        let r_sq = self.radius * self.radius;
+
+        if dist_sq <= r_sq {
+            let (point, normal) = self.sample_area(u);
+            let to_point = &point - reference;
+            let dist_sq = &to_point % &to_point;
+            let cos = (&normal % &to_point.to_unit()).abs();
+            if cos == 0.0 {
+                return None;
+            }
+            return Some(ShapeSample {
+                pdf: dist_sq / (cos * self.area()),
+                point,
+                normal,
+            });
+        }
+
+        let cos_max = (1.0 - r_sq / dist_sq).max(0.0).sqrt();
+        let frame = Frame::new(&to_center.to_unit());
+        let direction = frame.to_world(&uniform_cone(u, cos_max));
+
+        // Project the centre onto the sampled direction; when the direction
+        // grazes the silhouette, rounding can leave it a hair outside.
+        let along = &to_center % &direction;
+        let closest_sq = (dist_sq - along * along).max(0.0);
+        let t = along - (r_sq - closest_sq).max(0.0).sqrt();
+        let point = reference + &direction * t;
+        let normal = (&point - &self.center).to_unit();
+
+        Some(ShapeSample {
+            point,
+            normal,
+            pdf: uniform_cone_pdf(cos_max),
+        })
+    }
+
+    /// Solid-angle density of `sample` choosing `wi` from `reference`.
+    pub fn pdf(&self, reference: &Vec3, wi: &Vec3) -> Float {
+        let to_center = &self.center - reference;
+        let dist_sq = &to_center % &to_center;
+        let r_sq = self.radius * self.radius;
+
+        let ray = Ray::new(reference.clone(), wi.clone(), 0.0);
+        let (t, normal) = match self.intersect(&ray, 0.0, Float::INFINITY) {
+            Some(hit) => hit,
+            None => return 0.0,
+        };
+
+        if dist_sq <= r_sq {
+            let cos = (&normal % wi).abs();
+            if cos == 0.0 {
+                return 0.0;
+            }
+            return t * t / (cos * self.area());
+        }
+
+        uniform_cone_pdf((1.0 - r_sq / dist_sq).max(0.0).sqrt())
+    }
+}
+
+impl Boxable for Sphere {
+    fn get_bbox(&self) -> AABB {
+        let radius = self.radius;
+        AABB {
+            min: Vec3::new(
+                self.center.x - radius,
+                self.center.y - radius,
+                self.center.z - radius,
+            ),
+            max: Vec3::new(
+                self.center.x + radius,
+                self.center.y + radius,
+                self.center.z + radius,
+            ),
+        }
+    }
+}
+
+/// Triangles sharing vertices, already in world space; pbrt's
+/// "trianglemesh". The per-vertex data is optional, and interpolated
+/// across each triangle.
+#[derive(Clone)]
+pub struct TriangleMesh {
+    pub indices: Vec<[usize; 3]>,
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub tangents: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<(Float, Float)>>,
+    /// Whether to flip the normals that the winding order implies; the
+    /// shading normals win where there are some.
+    pub reverse_orientation: bool,
+}
+
+/// Triangle `index` of a mesh.
+#[derive(Clone)]
+pub struct Triangle {
+    pub mesh: Arc<TriangleMesh>,
+    pub index: usize,
+}
+
+/// Triangles that subtend less solid angle than this are sampled by area
+/// instead, as are those that subtend so much that spherical sampling
+/// gets inaccurate; pbrt's thresholds.
+const MIN_SPHERICAL_SAMPLE_AREA: Float = 3e-4;
+const MAX_SPHERICAL_SAMPLE_AREA: Float = 6.22;
+
+impl Triangle {
+    fn vertices(&self) -> (&Vec3, &Vec3, &Vec3) {
+        let [i0, i1, i2] = self.mesh.indices[self.index];
+        let p = &self.mesh.positions;
+        (&p[i0], &p[i1], &p[i2])
+    }
+
+    /// Distance along the ray to the intersection in range, and its
+    /// barycentric coordinates, by Woop, Benthin and Wald's watertight
+    /// test: in a space where the ray runs along +z from the origin, the
+    /// edge functions are evaluated the same way from both triangles that
+    /// share an edge, so rays can't slip between them.
+    fn intersect(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<(Float, [Float; 3])> {
+        let (p0, p1, p2) = self.vertices();
+        let d = [ray.direction.x, ray.direction.y, ray.direction.z];
+        let kz = if d[0].abs() > d[1].abs() && d[0].abs() > d[2].abs() {
+            0
+        } else if d[1].abs() > d[2].abs() {
+            1
+        } else {
+            2
+        };
+        let (kx, ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        let (shear_x, shear_y, shear_z) = (-d[kx] / d[kz], -d[ky] / d[kz], 1.0 / d[kz]);
+
+        // Move the vertices to ray space.
+        let to_ray = |p: &Vec3| {
+            let p = p - &ray.origin;
+            let p = [p.x, p.y, p.z];
+            (
+                p[kx] + shear_x * p[kz],
+                p[ky] + shear_y * p[kz],
+                p[kz] * shear_z,
+            )
+        };
+        let (a, b, c) = (to_ray(p0), to_ray(p1), to_ray(p2));
+
+        let mut e0 = b.0 * c.1 - b.1 * c.0;
+        let mut e1 = c.0 * a.1 - c.1 * a.0;
+        let mut e2 = a.0 * b.1 - a.1 * b.0;
+        // On an edge, in single precision: settle it in double.
+        if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+            let edge = |p: (Float, Float, Float), q: (Float, Float, Float)| {
+                (p.0 as f64 * q.1 as f64 - p.1 as f64 * q.0 as f64) as Float
+            };
+            e0 = edge(b, c);
+            e1 = edge(c, a);
+            e2 = edge(a, b);
+        }
+        // Exactly on an edge or a vertex: decide as if the ray were nudged
+        // by a tiny (ε, ε²), which leaves it in just one of the triangles
+        // that share it.
+        let side = |e: Float, p: (Float, Float, Float), q: (Float, Float, Float)| {
+            if e != 0.0 {
+                e
+            } else if q.1 != p.1 {
+                p.1 - q.1
+            } else {
+                q.0 - p.0
+            }
+        };
+        let (s0, s1, s2) = (side(e0, b, c), side(e1, c, a), side(e2, a, b));
+        if (s0 < 0.0 || s1 < 0.0 || s2 < 0.0) && (s0 > 0.0 || s1 > 0.0 || s2 > 0.0) {
+            return None;
+        }
+        let det = e0 + e1 + e2;
+        if det == 0.0 {
+            return None;
+        }
+
+        let t = (e0 * a.2 + e1 * b.2 + e2 * c.2) / det;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        Some((t, [e0 / det, e1 / det, e2 / det]))
+    }
+
+    pub fn area(&self) -> Float {
+        let (p0, p1, p2) = self.vertices();
+        0.5 * (p1 - p0).cross(&(p2 - p0)).norm()
+    }
+
+    fn interpolate(&self, values: &[Vec3], b: &[Float; 3]) -> Vec3 {
+        let [i0, i1, i2] = self.mesh.indices[self.index];
+        &(&values[i0] * b[0] + &values[i1] * b[1]) + &values[i2] * b[2]
+    }
+
+    /// The unit shading normal at barycentrics `b`, if the mesh has
+    /// normals and they don't cancel out there.
+    fn shading_normal(&self, b: &[Float; 3]) -> Option<Vec3> {
+        let n = self.interpolate(self.mesh.normals.as_ref()?, b);
+        if n.norm() > 0.0 {
+            Some(n.to_unit())
+        } else {
+            None
+        }
+    }
+
+    /// The true normal, on the side of the shading normal at `b` if there
+    /// is one.
+    fn geometric_normal(&self, b: &[Float; 3]) -> Vec3 {
+        let (p0, p1, p2) = self.vertices();
+        let n = (p0 - p2).cross(&(p1 - p2)).to_unit();
+        let flip = match self.shading_normal(b) {
+            Some(ns) => &n % &ns < 0.0,
+            None => self.mesh.reverse_orientation,
+        };
+        if flip {
+            n.negate()
+        } else {
+            n
+        }
+    }
+
+    fn uvs(&self) -> [(Float, Float); 3] {
+        let [i0, i1, i2] = self.mesh.indices[self.index];
+        match &self.mesh.uvs {
+            Some(uv) => [uv[i0], uv[i1], uv[i2]],
+            None => [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0)],
+        }
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<SurfaceHit> {
+        let (pos, b) = self.intersect(ray, t_min, t_max)?;
+        let (p0, p1, p2) = self.vertices();
+        let point = &(p0 * b[0] + p1 * b[1]) + p2 * b[2];
+        let geometric_normal = self.geometric_normal(&b);
+        let normal = self
+            .shading_normal(&b)
+            .unwrap_or_else(|| geometric_normal.clone());
+
+        let uv = self.uvs();
+        let interpolated = (
+            b[0] * uv[0].0 + b[1] * uv[1].0 + b[2] * uv[2].0,
+            b[0] * uv[0].1 + b[1] * uv[1].1 + b[2] * uv[2].1,
+        );
+
+        // The tangent is the mesh's if it has them, or else dp/du, made
+        // perpendicular to the shading normal.
+        let tangent = match &self.mesh.tangents {
+            Some(tangents) => self.interpolate(tangents, &b),
+            None => {
+                let (du02, dv02) = (uv[0].0 - uv[2].0, uv[0].1 - uv[2].1);
+                let (du12, dv12) = (uv[1].0 - uv[2].0, uv[1].1 - uv[2].1);
+                let det = du02 * dv12 - dv02 * du12;
+                if det.abs() < 1e-9 {
+                    Vec3::new(0.0, 0.0, 0.0)
+                } else {
+                    (&((p0 - p2) * dv12) - &((p1 - p2) * dv02)) / det
+                }
+            }
+        };
+        let tangent = &tangent - &(&normal * (&normal % &tangent));
+        let tangent = if tangent.norm() > 0.0 {
+            Some(tangent.to_unit())
+        } else {
+            None
+        };
+
+        Some(SurfaceHit {
+            pos,
+            point,
+            normal,
+            geometric_normal,
+            uv: interpolated,
+            barycentric: Some((b[1], b[2])),
+            tangent,
+        })
+    }
+
+    /// Uniformly distributed point on the triangle, with its normal.
+    pub fn sample_area(&self, u: (Float, Float)) -> (Vec3, Vec3) {
+        let b = uniform_triangle(u);
+        let (p0, p1, p2) = self.vertices();
+        let point = &(p0 * b[0] + p1 * b[1]) + p2 * b[2];
+        (point, self.geometric_normal(&b))
+    }
+
+    /// The solid angle the triangle subtends from `reference`.
+    fn solid_angle(&self, reference: &Vec3) -> Float {
+        let (p0, p1, p2) = self.vertices();
+        spherical_triangle_area(
+            &(p0 - reference).to_unit(),
+            &(p1 - reference).to_unit(),
+            &(p2 - reference).to_unit(),
+        )
+    }
+
+    /// Samples the directions the triangle subtends from `reference`
+    /// uniformly, unless it subtends too little or too much of the sphere,
+    /// when it falls back to sampling by area.
+    pub fn sample(&self, reference: &Vec3, u: (Float, Float)) -> Option<ShapeSample> {
+        let solid_angle = self.solid_angle(reference);
+        if !(MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle) {
+            let (point, normal) = self.sample_area(u);
+            let to_point = &point - reference;
+            let dist_sq = &to_point % &to_point;
+            let cos = (&normal % &to_point.to_unit()).abs();
+            if cos == 0.0 {
+                return None;
+            }
+            return Some(ShapeSample {
+                pdf: dist_sq / (cos * self.area()),
+                point,
+                normal,
+            });
+        }
+
+        let (p0, p1, p2) = self.vertices();
+        let direction = uniform_spherical_triangle(
+            &(p0 - reference).to_unit(),
+            &(p1 - reference).to_unit(),
+            &(p2 - reference).to_unit(),
+            u,
+        )?;
+        // Rounding can leave the direction a hair outside the triangle.
+        let ray = Ray::new(reference.clone(), direction, 0.0);
+        let (t, b) = self.intersect(&ray, 0.0, Float::INFINITY)?;
+        Some(ShapeSample {
+            point: ray.point_at(t),
+            normal: self.geometric_normal(&b),
+            pdf: 1.0 / solid_angle,
+        })
+    }
+
+    /// Solid-angle density of `sample` choosing `wi` from `reference`.
+    pub fn pdf(&self, reference: &Vec3, wi: &Vec3) -> Float {
+        let ray = Ray::new(reference.clone(), wi.clone(), 0.0);
+        let (t, b) = match self.intersect(&ray, 0.0, Float::INFINITY) {
+            Some(hit) => hit,
+            None => return 0.0,
+        };
+
+        let solid_angle = self.solid_angle(reference);
+        if (MIN_SPHERICAL_SAMPLE_AREA..=MAX_SPHERICAL_SAMPLE_AREA).contains(&solid_angle) {
+            return 1.0 / solid_angle;
+        }
+        let cos = (&self.geometric_normal(&b) % wi).abs();
+        if cos == 0.0 {
+            return 0.0;
+        }
+        t * t / (cos * self.area())
+    }
+}
+
+impl Boxable for Triangle {
+    fn get_bbox(&self) -> AABB {
+        let (p0, p1, p2) = self.vertices();
+        AABB {
+            min: p0.elem_min(p1).elem_min(p2),
+            max: p0.elem_max(p1).elem_max(p2),
+        }
+    }
+}
+
+/// What a ray found on a shape, before the primitive adds what it's made
+/// of.
+struct SurfaceHit {
+    pos: Float,
+    point: Vec3,
+    normal: Vec3,
+    geometric_normal: Vec3,
+    uv: (Float, Float),
+    barycentric: Option<(Float, Float)>,
+    tangent: Option<Vec3>,
+}
+
+#[derive(Clone)]
+pub enum Shape {
+    Sphere(Sphere),
+    Triangle(Triangle),
+}
+
+impl Shape {
+    pub fn area(&self) -> Float {
+        match self {
+            Shape::Sphere(sphere) => sphere.area(),
+            Shape::Triangle(triangle) => triangle.area(),
+        }
+    }
+
+    /// Uniformly distributed point on the surface, with its normal.
+    pub fn sample_area(&self, u: (Float, Float)) -> (Vec3, Vec3) {
+        match self {
+            Shape::Sphere(sphere) => sphere.sample_area(u),
+            Shape::Triangle(triangle) => triangle.sample_area(u),
+        }
+    }
+
+    /// A point on the surface for lighting `reference`, chosen with respect
+    /// to solid angle there.
+    pub fn sample(&self, reference: &Vec3, u: (Float, Float)) -> Option<ShapeSample> {
+        match self {
+            Shape::Sphere(sphere) => sphere.sample(reference, u),
+            Shape::Triangle(triangle) => triangle.sample(reference, u),
+        }
+    }
+
+    /// Solid-angle density of `sample` choosing `wi` from `reference`.
+    pub fn pdf(&self, reference: &Vec3, wi: &Vec3) -> Float {
+        match self {
+            Shape::Sphere(sphere) => sphere.pdf(reference, wi),
+            Shape::Triangle(triangle) => triangle.pdf(reference, wi),
+        }
+    }
+
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<SurfaceHit> {
+        match self {
+            Shape::Sphere(sphere) => {
+                let (pos, normal) = sphere.intersect(ray, t_min, t_max)?;
+                let point = ray.point_at(pos);
+                // dp/du points around the z axis.
+                let p = &point - &sphere.center;
+                let tangent = if p.x != 0.0 || p.y != 0.0 {
+                    Some(Vec3::new(-p.y, p.x, 0.0).to_unit())
+                } else {
+                    None
+                };
+                Some(SurfaceHit {
+                    uv: sphere.uv(&point),
+                    pos,
+                    point,
+                    geometric_normal: normal.clone(),
+                    normal,
+                    barycentric: None,
+                    tangent,
+                })
+            }
+            Shape::Triangle(triangle) => triangle.hit(ray, t_min, t_max),
+        }
+    }
+}
+
+impl Boxable for Shape {
+    fn get_bbox(&self) -> AABB {
+        match self {
+            Shape::Sphere(sphere) => sphere.get_bbox(),
+            Shape::Triangle(triangle) => triangle.get_bbox(),
+        }
+    }
+}
+
+/// A shape together with what it is made of.
+#[derive(Clone)]
+pub struct Primitive {
+    pub shape: Shape,
+    pub material: Arc<Material>,
+    pub light: Option<usize>,
+    /// Assigned by `World::new`.
+    pub id: usize,
+    pub material_id: usize,
+}
+
+impl Boxable for Primitive {
+    fn get_bbox(&self) -> AABB {
+        self.shape.get_bbox()
+    }
+}
+
+impl Hitable for Primitive {
+    fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        let hit = self.shape.hit(ray, t_min, t_max)?;
+        Some(HitRecord {
+            point: hit.point,
+            normal: hit.normal,
+            geometric_normal: hit.geometric_normal,
+            pos: hit.pos,
+            uv: hit.uv,
+            barycentric: hit.barycentric,
+            tangent: hit.tangent,
+            material: &self.material,
+            light: self.light,
+            primitive: self.id,
+            material_id: self.material_id,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[allow(clippy::upper_case_acronyms)]
+pub struct AABB {
+    pub max: Vec3,
+    pub min: Vec3,
+}
+
+impl AABB {
+    pub fn absorb(&self, other: &AABB) -> AABB {
+        AABB {
+            max: self.max.elem_max(&other.max),
+            min: self.min.elem_min(&other.min),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (&self.min + &self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> Float {
+        let d = &self.max - &self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Centre and radius of a sphere containing the box.
+    pub fn bounding_sphere(&self) -> (Vec3, Float) {
+        let center = self.centroid();
+        let radius = (&self.max - &center).norm();
+        (center, radius)
+    }
+
+    /// Slab test: does the ray pass through the box within `[t_min, t_max]`?
+    pub fn hit(&self, ray: &Ray, t_min: Float, t_max: Float) -> bool {
+        let mut imin = t_min;
+        let mut imax = t_max;
+
+        for dir in &[Direction::X, Direction::Y, Direction::Z] {
+            let inv = ray.dir_inv.get(*dir);
+            let origin = ray.origin.get(*dir);
+            let p = (self.min.get(*dir) - origin) * inv;
+            let q = (self.max.get(*dir) - origin) * inv;
+
+            imin = imin.max(p.min(q));
+            imax = imax.min(q.max(p));
+        }
+
+        imin <= imax
+    }
+}
+
+impl Boxable for AABB {
+    fn get_bbox(&self) -> AABB {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Eight triangles around a vertex at the origin, in the z = 0 plane,
+    /// with every other edge along an axis.
+    fn fan() -> Vec<Triangle> {
+        let ring = 8;
+        let mut positions = vec![Vec3::new(0.0, 0.0, 0.0)];
+        for k in 0..ring {
+            let phi = k as Float * std::f32::consts::TAU / ring as Float;
+            positions.push(Vec3::new(phi.cos(), phi.sin(), 0.0));
+        }
+        // Exactly on the axes, rather than off by a rounding error.
+        positions[1] = Vec3::new(1.0, 0.0, 0.0);
+        positions[3] = Vec3::new(0.0, 1.0, 0.0);
+        positions[5] = Vec3::new(-1.0, 0.0, 0.0);
+        positions[7] = Vec3::new(0.0, -1.0, 0.0);
+        let mesh = Arc::new(TriangleMesh {
+            indices: (0..ring).map(|k| [0, 1 + k, 1 + (k + 1) % ring]).collect(),
+            positions,
+            normals: None,
+            tangents: None,
+            uvs: None,
+            reverse_orientation: false,
+        });
+        (0..ring)
+            .map(|index| Triangle {
+                mesh: Arc::clone(&mesh),
+                index,
+            })
+            .collect()
+    }
+
+    fn hits(triangles: &[Triangle], ray: &Ray) -> usize {
+        triangles
+            .iter()
+            .filter(|t| t.intersect(ray, 0.0, Float::INFINITY).is_some())
+            .count()
+    }
+
+    /// Points on the edges the fan's triangles share, and the vertex they
+    /// all share.
+    fn shared_points(triangles: &[Triangle]) -> Vec<Vec3> {
+        let mut points = vec![Vec3::new(0.0, 0.0, 0.0)];
+        for vertex in &triangles[0].mesh.positions[1..] {
+            for &s in &[0.1, 0.25, 0.5, 0.9] {
+                points.push(vertex * s);
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn shared_edges_and_vertices_are_hit_once_head_on() {
+        let triangles = fan();
+        for target in shared_points(&triangles) {
+            for &dz in &[-1.0, 1.0] {
+                let origin = &target - &Vec3::new(0.0, 0.0, dz);
+                let ray = Ray::new(origin, Vec3::new(0.0, 0.0, dz), 0.0);
+                assert_eq!(hits(&triangles, &ray), 1, "ray at {:?}", target);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_edges_and_vertices_are_hit_once_at_an_angle() {
+        let triangles = fan();
+        let mut rng = StdRng::seed_from_u64(1);
+        for target in shared_points(&triangles) {
+            for _ in 0..200 {
+                let d = Vec3::new(
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(-1.0, 1.0),
+                    rng.gen_range(0.2, 1.0) * if rng.gen() { 1.0 } else { -1.0 },
+                );
+                let ray = Ray::new(&target - &(&d * 3.0), d.clone(), 0.0);
+                assert_eq!(
+                    hits(&triangles, &ray),
+                    1,
+                    "ray at {:?} along {:?}",
+                    target,
+                    d
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,637 @@
+use crate::image::Image;
+use crate::integrator::debug::DebugMode;
+use crate::integrator::Progressive;
+use crate::parse::parse_source;
+use crate::post::{self, Effect};
+use crate::progress::{Observer, Status};
+use crate::tiles::Tile;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often a job's preview is updated, unless `--snapshot-interval` says
+/// otherwise.
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Boundary between the frames of a streamed preview.
+const BOUNDARY: &str = "frame";
+
+/// Largest request body accepted, which is plenty for a scene file.
+const MAX_BODY: usize = 16 << 20;
+
+/// What the server renders with: everything but the scene, which comes
+/// with each job.
+pub struct Settings {
+    pub progressive: Progressive,
+    pub debug: Option<DebugMode>,
+    pub effects: Vec<Effect>,
+}
+
+/// Renders scenes POSTed to `addr`, one at a time, in the order they
+/// arrive. The API:
+///
+/// - `POST /jobs` with a pbrt scene as the body queues it, and answers with
+///   the job's id. The scene can't include other files, nor be bigger
+///   than `MAX_BODY`; `seed`,
+///   `time-budget` and `noise-threshold` in the query override the
+///   server's own.
+/// - `GET /jobs` and `GET /jobs/<id>` describe every job, or one.
+/// - `GET /jobs/<id>/preview.png` is the latest preview, and
+///   `GET /jobs/<id>/preview` streams each one as it's made, as
+///   `multipart/x-mixed-replace`, which an `<img>` tag can show.
+/// - `GET /jobs/<id>/image.exr` and `image.png` are the final image.
+/// - `DELETE /jobs/<id>` cancels a job, keeping what it rendered so far,
+///   or forgets it once it has finished.
+///
+/// For example: `curl --data-binary @scene.pbrt http://127.0.0.1:8080/jobs`.
+pub fn serve(addr: &str, settings: Settings) -> Result<(), Box<dyn Error>> {
+    let shared = Arc::new(Shared {
+        settings,
+        jobs: Mutex::new(Jobs {
+            next_id: 1,
+            all: BTreeMap::new(),
+            queue: VecDeque::new(),
+        }),
+        queued: Condvar::new(),
+    });
+
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("serving on http://{}", listener.local_addr()?);
+    {
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || render_jobs(&shared));
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("warning: couldn't accept a connection: {}", e);
+                continue;
+            }
+        };
+        let shared = Arc::clone(&shared);
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &shared) {
+                eprintln!("warning: couldn't answer a request: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+struct Shared {
+    settings: Settings,
+    jobs: Mutex<Jobs>,
+    queued: Condvar,
+}
+
+struct Jobs {
+    next_id: u64,
+    all: BTreeMap<u64, Arc<Job>>,
+    /// Jobs waiting for the renderer, oldest first.
+    queue: VecDeque<Arc<Job>>,
+}
+
+struct Job {
+    id: u64,
+    scene: String,
+    progressive: Progressive,
+    state: Mutex<JobState>,
+    /// Signalled on every new preview, and when the job ends.
+    changed: Condvar,
+    cancelled: AtomicBool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Queued,
+    Rendering,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Queued => "queued",
+            Phase::Rendering => "rendering",
+            Phase::Done => "done",
+            Phase::Cancelled => "cancelled",
+            Phase::Failed => "failed",
+        }
+    }
+
+    fn finished(self) -> bool {
+        matches!(self, Phase::Done | Phase::Cancelled | Phase::Failed)
+    }
+}
+
+struct JobState {
+    phase: Phase,
+    status: Option<Status>,
+    /// The latest preview as a PNG, and how many there have been.
+    preview: Option<Arc<Vec<u8>>>,
+    previews: u64,
+    /// The final image, with the effects applied.
+    image: Option<Arc<Image>>,
+    error: Option<String>,
+}
+
+/// Takes jobs off the queue and renders them, forever.
+fn render_jobs(shared: &Shared) {
+    loop {
+        let job = {
+            let mut jobs = shared.jobs.lock().unwrap();
+            loop {
+                match jobs.queue.pop_front() {
+                    // Under the lock, so it can't be cancelled as if queued.
+                    Some(job) => {
+                        job.state.lock().unwrap().phase = Phase::Rendering;
+                        break job;
+                    }
+                    None => jobs = shared.queued.wait(jobs).unwrap(),
+                }
+            }
+        };
+        render_job(shared, &job);
+    }
+}
+
+/// Renders `job`, leaving the image or the error in its state.
+fn render_job(shared: &Shared, job: &Job) {
+    let effects = &shared.settings.effects;
+    let observer = JobObserver { job, effects };
+    let rendered = panic::catch_unwind(AssertUnwindSafe(|| {
+        let (scene, mut integrator) = parse_source(&job.scene, shared.settings.debug)?;
+        integrator.render_progressive(scene.as_ref(), &job.progressive, &observer)
+    }))
+    .unwrap_or_else(|_| Err("the renderer panicked".into()));
+
+    let mut state = job.state.lock().unwrap();
+    match rendered {
+        Ok(image) => {
+            let image = post::apply_all(effects, image);
+            state.preview = Some(Arc::new(png(&image)));
+            state.previews += 1;
+            state.image = Some(Arc::new(image));
+            state.phase = if job.cancelled.load(Ordering::Relaxed) {
+                Phase::Cancelled
+            } else {
+                Phase::Done
+            };
+        }
+        Err(e) => {
+            state.error = Some(e.to_string());
+            state.phase = Phase::Failed;
+        }
+    }
+    job.changed.notify_all();
+}
+
+struct JobObserver<'a> {
+    job: &'a Job,
+    effects: &'a [Effect],
+}
+
+impl Observer for JobObserver<'_> {
+    fn tile_done(&self, _tile: &Tile, status: &Status) {
+        self.job.state.lock().unwrap().status = Some(status.clone());
+    }
+
+    fn snapshot(&self, image: Image) {
+        let preview = png(&post::apply_all(self.effects, image));
+        let mut state = self.job.state.lock().unwrap();
+        state.preview = Some(Arc::new(preview));
+        state.previews += 1;
+        self.job.changed.notify_all();
+    }
+
+    fn cancelled(&self) -> bool {
+        self.job.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Answers the one request on `stream`, then hangs up.
+fn handle(stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    let request = match read_request(&mut input) {
+        Ok(request) => request,
+        Err(e) if e.get_ref().is_some_and(|e| e.is::<TooLarge>()) => {
+            return respond(&mut out, "413 Payload Too Large", &format!("{}\n", e));
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return respond(&mut out, "400 Bad Request", &format!("{}\n", e));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let job = match segments.get(1) {
+        Some(id) => match id.parse().ok().and_then(|id| find(shared, id)) {
+            Some(job) => Some(job),
+            None => return respond(&mut out, "404 Not Found", "no such job\n"),
+        },
+        None => None,
+    };
+
+    match (request.method.as_str(), &segments[..], job) {
+        ("POST", ["jobs"], _) => submit(&mut out, shared, &request),
+        ("GET", ["jobs"], _) => {
+            let jobs: Vec<Arc<Job>> = shared.jobs.lock().unwrap().all.values().cloned().collect();
+            let list: Vec<String> = jobs.iter().map(|job| describe(job)).collect();
+            send(
+                &mut out,
+                "200 OK",
+                "application/json",
+                format!("[{}]\n", list.join(",")).as_bytes(),
+            )
+        }
+        ("GET", ["jobs", _], Some(job)) => send(
+            &mut out,
+            "200 OK",
+            "application/json",
+            format!("{}\n", describe(&job)).as_bytes(),
+        ),
+        ("DELETE", ["jobs", _], Some(job)) => cancel(&mut out, shared, &job),
+        ("GET", ["jobs", _, "preview.png"], Some(job)) => {
+            let preview = job.state.lock().unwrap().preview.clone();
+            match preview {
+                Some(png) => send(&mut out, "200 OK", "image/png", &png),
+                None => respond(&mut out, "404 Not Found", "no preview yet\n"),
+            }
+        }
+        ("GET", ["jobs", _, "preview"], Some(job)) => stream_previews(&mut out, &job),
+        ("GET", ["jobs", _, file @ ("image.exr" | "image.png")], Some(job)) => {
+            let (phase, image) = {
+                let state = job.state.lock().unwrap();
+                (state.phase, state.image.clone())
+            };
+            let image = match image {
+                Some(image) => image,
+                None if phase == Phase::Failed => {
+                    return respond(&mut out, "404 Not Found", "the job failed\n");
+                }
+                None => return respond(&mut out, "409 Conflict", "the job isn't finished\n"),
+            };
+            if *file == "image.exr" {
+                let mut exr = Vec::new();
+                image.write_exr(&mut exr)?;
+                send(&mut out, "200 OK", "image/x-exr", &exr)
+            } else {
+                send(&mut out, "200 OK", "image/png", &png(&image))
+            }
+        }
+        (_, ["jobs"], _) | (_, ["jobs", _], _) => {
+            respond(&mut out, "405 Method Not Allowed", "method not allowed\n")
+        }
+        _ => respond(&mut out, "404 Not Found", "not found\n"),
+    }
+}
+
+fn find(shared: &Shared, id: u64) -> Option<Arc<Job>> {
+    shared.jobs.lock().unwrap().all.get(&id).cloned()
+}
+
+fn submit(out: &mut dyn Write, shared: &Shared, request: &Request) -> io::Result<()> {
+    let scene = match String::from_utf8(request.body.clone()) {
+        Ok(scene) => scene,
+        Err(_) => return respond(out, "400 Bad Request", "the scene isn't UTF-8\n"),
+    };
+    let progressive = match job_settings(&shared.settings.progressive, &request.query) {
+        Ok(progressive) => progressive,
+        Err(e) => return respond(out, "400 Bad Request", &format!("{}\n", e)),
+    };
+
+    let mut jobs = shared.jobs.lock().unwrap();
+    let id = jobs.next_id;
+    jobs.next_id += 1;
+    let job = Arc::new(Job {
+        id,
+        scene,
+        progressive,
+        state: Mutex::new(JobState {
+            phase: Phase::Queued,
+            status: None,
+            preview: None,
+            previews: 0,
+            image: None,
+            error: None,
+        }),
+        changed: Condvar::new(),
+        cancelled: AtomicBool::new(false),
+    });
+    jobs.all.insert(id, Arc::clone(&job));
+    jobs.queue.push_back(job);
+    shared.queued.notify_one();
+    drop(jobs);
+
+    send(
+        out,
+        "201 Created",
+        "application/json",
+        format!("{{\"id\":{}}}\n", id).as_bytes(),
+    )
+}
+
+/// The server's settings, with whatever the query overrides.
+fn job_settings(
+    defaults: &Progressive,
+    query: &[(String, String)],
+) -> Result<Progressive, Box<dyn Error>> {
+    let mut progressive = defaults.clone();
+    if progressive.snapshot_interval.is_none() {
+        progressive.snapshot_interval = Some(PREVIEW_INTERVAL);
+    }
+    for (name, value) in query {
+        let bad = |e: &dyn Error| format!("bad {}: {}", name, e);
+        match name.as_str() {
+            "seed" => progressive.seed = value.parse().map_err(|e| bad(&e))?,
+            "time-budget" => {
+                let seconds: f64 = value.parse().map_err(|e| bad(&e))?;
+                progressive.time_budget = Some(Duration::from_secs_f64(seconds.max(0.0)));
+            }
+            "noise-threshold" => {
+                progressive.noise_threshold = Some(value.parse().map_err(|e| bad(&e))?)
+            }
+            _ => return Err(format!("unknown parameter {}", name).into()),
+        }
+    }
+    Ok(progressive)
+}
+
+/// Stops a job that hasn't finished, or forgets one that has.
+fn cancel(out: &mut dyn Write, shared: &Shared, job: &Arc<Job>) -> io::Result<()> {
+    let mut jobs = shared.jobs.lock().unwrap();
+    let mut state = job.state.lock().unwrap();
+    match state.phase {
+        Phase::Queued => {
+            jobs.queue.retain(|queued| queued.id != job.id);
+            state.phase = Phase::Cancelled;
+            job.changed.notify_all();
+        }
+        Phase::Rendering => job.cancelled.store(true, Ordering::Relaxed),
+        _ => {
+            jobs.all.remove(&job.id);
+            return respond(out, "200 OK", "forgotten\n");
+        }
+    }
+    respond(out, "202 Accepted", "cancelling\n")
+}
+
+/// Sends every preview from now until the job ends, the last being the
+/// final image.
+fn stream_previews(out: &mut BufWriter<TcpStream>, job: &Job) -> io::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache\r\n\
+         Connection: close\r\n\r\n",
+        BOUNDARY
+    )?;
+    let mut seen = 0;
+    loop {
+        let (preview, finished) = {
+            let mut state = job.state.lock().unwrap();
+            while state.previews == seen && !state.phase.finished() {
+                state = job.changed.wait(state).unwrap();
+            }
+            seen = state.previews;
+            (state.preview.clone(), state.phase.finished())
+        };
+        if let Some(png) = preview {
+            write!(
+                out,
+                "--{}\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                png.len()
+            )?;
+            out.write_all(&png)?;
+            out.write_all(b"\r\n")?;
+            out.flush()?;
+        }
+        if finished {
+            write!(out, "--{}--\r\n", BOUNDARY)?;
+            return out.flush();
+        }
+    }
+}
+
+/// The job as JSON.
+fn describe(job: &Job) -> String {
+    let state = job.state.lock().unwrap();
+    let mut fields = vec![
+        format!("\"id\":{}", job.id),
+        format!("\"state\":\"{}\"", state.phase.name()),
+    ];
+    if let Some(status) = &state.status {
+        fields.push(format!("\"pass\":{}", status.pass + 1));
+        fields.push(format!("\"elapsed\":{:.3}", status.elapsed.as_secs_f64()));
+        fields.push(format!("\"rays_per_second\":{:.0}", status.rays_per_second));
+        if state.phase == Phase::Rendering {
+            if let (Some(fraction), Some(eta)) = (status.fraction, status.eta) {
+                fields.push(format!("\"fraction\":{:.4}", fraction));
+                fields.push(format!("\"eta\":{:.3}", eta.as_secs_f64()));
+            }
+        }
+    }
+    if let Some(e) = &state.error {
+        fields.push(format!("\"error\":{}", json_string(e)));
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn png(image: &Image) -> Vec<u8> {
+    let mut png = Vec::new();
+    image.write_png(&mut png).unwrap();
+    png
+}
+
+/// Reads a request with its body, which has to come with a length.
+fn read_request(input: &mut dyn BufRead) -> io::Result<Request> {
+    let bad = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(bad("malformed request line")),
+    };
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad("malformed header"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = value.parse().map_err(|_| bad("bad Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(bad(
+                "chunked bodies aren't supported; send a Content-Length",
+            ));
+        }
+    }
+    if length > MAX_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query,
+        body,
+    })
+}
+
+/// A request body over `MAX_BODY`.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the body is over {} bytes", MAX_BODY)
+    }
+}
+
+impl Error for TooLarge {}
+
+fn respond(out: &mut dyn Write, status: &str, message: &str) -> io::Result<()> {
+    send(out, status, "text/plain; charset=utf-8", message.as_bytes())
+}
+
+fn send(out: &mut dyn Write, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    out.write_all(body)?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::io::Read;
+
+    fn shared() -> Shared {
+        Shared {
+            settings: Settings {
+                progressive: Progressive::default(),
+                debug: None,
+                effects: Vec::new(),
+            },
+            jobs: Mutex::new(Jobs {
+                next_id: 1,
+                all: BTreeMap::new(),
+                queue: VecDeque::new(),
+            }),
+            queued: Condvar::new(),
+        }
+    }
+
+    /// Sends `request` to `handle` over a real connection, and returns the
+    /// response.
+    fn exchange(shared: &Shared, request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        handle(stream, shared).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_unread() {
+        let shared = shared();
+        let request = "POST /jobs HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n";
+        let response = exchange(&shared, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 413 "), "{}", response);
+        assert!(shared.jobs.lock().unwrap().all.is_empty());
+    }
+
+    #[test]
+    fn scenes_cant_include_files() {
+        let secret = "SecretDirective";
+        let path = std::env::temp_dir().join(format!("serve-include-{}.pbrt", std::process::id()));
+        fs::write(&path, secret).unwrap();
+
+        let shared = shared();
+        let scene = format!("Include \"{}\"\nWorldBegin\nWorldEnd\n", path.display());
+        let request = format!(
+            "POST /jobs HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            scene.len(),
+            scene
+        );
+        let response = exchange(&shared, request.as_bytes());
+        assert!(response.starts_with("HTTP/1.1 201 "), "{}", response);
+
+        let job = shared.jobs.lock().unwrap().queue.pop_front().unwrap();
+        render_job(&shared, &job);
+        fs::remove_file(&path).unwrap();
+        assert!(job.state.lock().unwrap().phase == Phase::Failed);
+        let description = describe(&job);
+        assert!(description.contains("can't include"), "{}", description);
+        assert!(!description.contains(secret), "{}", description);
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Width and height of a tile, in pixels.
+pub const TILE_SIZE: usize = 16;
+
+/// A rectangle of pixels, `x0..x1` by `y0..y1`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    /// All of a `width` by `height` image, for parts of a pass that aren't
+    /// confined to one tile of it, such as a batch of photons.
+    pub fn whole(width: usize, height: usize) -> Tile {
+        Tile {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        }
+    }
+}
+
+/// The order tiles are handed to threads in. It only changes which parts
+/// of the image fill in first, never the result.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum TileOrder {
+    /// Outwards from the middle of the image, where the subject usually is.
+    #[default]
+    Spiral,
+    /// Along a Z-order curve, which keeps consecutive tiles close together
+    /// and so the scene data they touch in cache.
+    Morton,
+}
+
+impl TileOrder {
+    pub const NAMES: &'static [&'static str] = &["spiral", "morton"];
+
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "spiral" => Some(TileOrder::Spiral),
+            "morton" => Some(TileOrder::Morton),
+            _ => None,
+        }
+    }
+
+    /// Every tile of a `width` by `height` image, in this order.
+    pub fn tiles(self, width: usize, height: usize) -> Vec<Tile> {
+        let (nx, ny) = (width.div_ceil(TILE_SIZE), height.div_ceil(TILE_SIZE));
+        let mut coords: Vec<(usize, usize)> = (0..ny)
+            .flat_map(|ty| (0..nx).map(move |tx| (tx, ty)))
+            .collect();
+
+        match self {
+            TileOrder::Spiral => {
+                // Ring by ring, each starting from the left.
+                let (cx, cy) = ((nx as f64 - 1.0) / 2.0, (ny as f64 - 1.0) / 2.0);
+                let key = |&(tx, ty): &(usize, usize)| {
+                    let (dx, dy) = (tx as f64 - cx, ty as f64 - cy);
+                    (dx.abs().max(dy.abs()), dy.atan2(-dx))
+                };
+                coords.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+            }
+            TileOrder::Morton => coords.sort_by_key(|&(tx, ty)| morton(tx as u32, ty as u32)),
+        }
+
+        coords
+            .into_iter()
+            .map(|(tx, ty)| Tile {
+                x0: tx * TILE_SIZE,
+                y0: ty * TILE_SIZE,
+                x1: ((tx + 1) * TILE_SIZE).min(width),
+                y1: ((ty + 1) * TILE_SIZE).min(height),
+            })
+            .collect()
+    }
+}
+
+/// Interleaves the bits of `x` and `y`, `x` in the even ones.
+fn morton(x: u32, y: u32) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    }
+    spread(x) | (spread(y) << 1)
+}
+
+/// Calls `f` on every tile, on all of rayon's threads, starting the tiles
+/// in the order they're given.
+pub fn for_each_tile<F: Fn(&Tile) + Sync>(tiles: &[Tile], f: F) {
+    let next = AtomicUsize::new(0);
+    rayon::scope(|s| {
+        for _ in 0..rayon::current_num_threads() {
+            s.spawn(|_| loop {
+                match tiles.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    Some(tile) => f(tile),
+                    None => return,
+                }
+            });
+        }
+    });
+}
@@ -0,0 +1,210 @@
+use crate::vec::{Float, Vec3};
+
+use std::f32::consts::PI;
+
+type Matrix = [[Float; 4]; 4];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+    [0.0, 0.0, 0.0, 1.0],
+];
+
+/// An affine transform, stored alongside its inverse so normals and
+/// inverse mappings don't need a matrix inversion each time.
+#[derive(Clone, Debug)]
+pub struct Transform {
+    m: Matrix,
+    inv: Matrix,
+}
+
+impl Transform {
+    pub const fn identity() -> Transform {
+        Transform {
+            m: IDENTITY,
+            inv: IDENTITY,
+        }
+    }
+
+    /// Builds a transform from a row-major matrix. Returns `None` if the
+    /// matrix is singular.
+    pub fn new(m: Matrix) -> Option<Transform> {
+        let inv = invert(&m)?;
+        Some(Transform { m, inv })
+    }
+
+    pub fn translate(delta: &Vec3) -> Transform {
+        let mut m = IDENTITY;
+        m[0][3] = delta.x;
+        m[1][3] = delta.y;
+        m[2][3] = delta.z;
+        let mut inv = IDENTITY;
+        inv[0][3] = -delta.x;
+        inv[1][3] = -delta.y;
+        inv[2][3] = -delta.z;
+        Transform { m, inv }
+    }
+
+    pub fn scale(x: Float, y: Float, z: Float) -> Transform {
+        let mut m = IDENTITY;
+        m[0][0] = x;
+        m[1][1] = y;
+        m[2][2] = z;
+        let mut inv = IDENTITY;
+        inv[0][0] = 1.0 / x;
+        inv[1][1] = 1.0 / y;
+        inv[2][2] = 1.0 / z;
+        Transform { m, inv }
+    }
+
+    /// Rotation by `degrees` around `axis`.
+    pub fn rotate(degrees: Float, axis: &Vec3) -> Transform {
+        let a = axis.to_unit();
+        let (sin, cos) = (degrees * PI / 180.0).sin_cos();
+
+        let mut m = IDENTITY;
+        m[0][0] = a.x * a.x + (1.0 - a.x * a.x) * cos;
+        m[0][1] = a.x * a.y * (1.0 - cos) - a.z * sin;
+        m[0][2] = a.x * a.z * (1.0 - cos) + a.y * sin;
+        m[1][0] = a.x * a.y * (1.0 - cos) + a.z * sin;
+        m[1][1] = a.y * a.y + (1.0 - a.y * a.y) * cos;
+        m[1][2] = a.y * a.z * (1.0 - cos) - a.x * sin;
+        m[2][0] = a.x * a.z * (1.0 - cos) - a.y * sin;
+        m[2][1] = a.y * a.z * (1.0 - cos) + a.x * sin;
+        m[2][2] = a.z * a.z + (1.0 - a.z * a.z) * cos;
+
+        Transform {
+            inv: transpose(&m),
+            m,
+        }
+    }
+
+    /// pbrt's `LookAt`: the world-to-camera transform for a camera at `eye`.
+    /// Like pbrt, the camera space is left-handed, looking down +z with +y up.
+    pub fn look_at(eye: &Vec3, look: &Vec3, up: &Vec3) -> Option<Transform> {
+        let dir = (look - eye).to_unit();
+        let right = up.to_unit().cross(&dir);
+        if right.norm() == 0.0 {
+            return None;
+        }
+        let right = right.to_unit();
+        let new_up = dir.cross(&right);
+
+        let camera_to_world = [
+            [right.x, new_up.x, dir.x, eye.x],
+            [right.y, new_up.y, dir.y, eye.y],
+            [right.z, new_up.z, dir.z, eye.z],
+            [0.0, 0.0, 0.0, 1.0],
+        ];
+        Transform::new(camera_to_world).map(|t| t.inverse())
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            m: self.inv,
+            inv: self.m,
+        }
+    }
+
+    /// `self * other`: applies `other` first.
+    pub fn compose(&self, other: &Transform) -> Transform {
+        Transform {
+            m: multiply(&self.m, &other.m),
+            inv: multiply(&other.inv, &self.inv),
+        }
+    }
+
+    pub fn point(&self, p: &Vec3) -> Vec3 {
+        let m = &self.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 {
+            Vec3::new(x, y, z)
+        } else {
+            Vec3::new(x / w, y / w, z / w)
+        }
+    }
+
+    pub fn vector(&self, v: &Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Normals transform by the inverse transpose.
+    pub fn normal(&self, n: &Vec3) -> Vec3 {
+        let inv = &self.inv;
+        Vec3::new(
+            inv[0][0] * n.x + inv[1][0] * n.y + inv[2][0] * n.z,
+            inv[0][1] * n.x + inv[1][1] * n.y + inv[2][1] * n.z,
+            inv[0][2] * n.x + inv[1][2] * n.y + inv[2][2] * n.z,
+        )
+    }
+
+    /// Determinant of the upper 3x3, i.e. how much the transform scales volumes.
+    pub fn determinant(&self) -> Float {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut r = [[0.0; 4]; 4];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    r
+}
+
+fn transpose(m: &Matrix) -> Matrix {
+    let mut r = [[0.0; 4]; 4];
+    for (i, row) in r.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = m[j][i];
+        }
+    }
+    r
+}
+
+/// Gauss-Jordan elimination with partial pivoting.
+fn invert(m: &Matrix) -> Option<Matrix> {
+    let mut a = *m;
+    let mut inv = IDENTITY;
+
+    for col in 0..4 {
+        let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        inv.swap(col, pivot);
+
+        let scale = 1.0 / a[col][col];
+        for j in 0..4 {
+            a[col][j] *= scale;
+            inv[col][j] *= scale;
+        }
+
+        for row in 0..4 {
+            if row != col {
+                let factor = a[row][col];
+                for j in 0..4 {
+                    a[row][j] -= factor * a[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+    }
+
+    Some(inv)
+}
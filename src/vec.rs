@@ -78,9 +78,9 @@ impl Vec3 {
     }
 
     pub fn as_ppm(&self) -> String {
-        let r = (self.x.sqrt() * 255.99).floor();
-        let g = (self.y.sqrt() * 255.99).floor();
-        let b = (self.y.sqrt() * 255.99).floor();
+        let r = (self.x.clamp(0.0, 1.0).sqrt() * 255.99).floor();
+        let g = (self.y.clamp(0.0, 1.0).sqrt() * 255.99).floor();
+        let b = (self.z.clamp(0.0, 1.0).sqrt() * 255.99).floor();
         format!("{} {} {}\n", r, g, b)
     }
 
@@ -96,6 +96,22 @@ impl Vec3 {
         Vec3 { x: a, y: b, z: c }
     }
 
+    pub fn max_component(&self) -> Float {
+        self.x.max(self.y).max(self.z)
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.x <= 0.0 && self.y <= 0.0 && self.z <= 0.0
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+
+    pub fn luminance(&self) -> Float {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
     pub fn cross(&self, other: &Vec3) -> Vec3 {
         Vec3 {
             x: self.y * other.z - self.z * other.y,
@@ -105,7 +121,7 @@ impl Vec3 {
     }
 }
 
-impl<'a, 'b> ops::Mul<&'a Float> for &'b Vec3 {
+impl ops::Mul<&Float> for &Vec3 {
     type Output = Vec3;
 
     fn mul(self, b: &Float) -> Self::Output {
@@ -118,7 +134,7 @@ impl<'a, 'b> ops::Mul<&'a Float> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Mul<Float> for &'a Vec3 {
+impl ops::Mul<Float> for &Vec3 {
     type Output = Vec3;
 
     fn mul(self, b: Float) -> Self::Output {
@@ -131,7 +147,7 @@ impl<'a> ops::Mul<Float> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Mul<&'a Float> for Vec3 {
+impl ops::Mul<&Float> for Vec3 {
     type Output = Vec3;
 
     fn mul(self, b: &Float) -> Self::Output {
@@ -157,7 +173,7 @@ impl ops::Mul<Float> for Vec3 {
     }
 }
 
-impl<'a, 'b> ops::Mul<&'a Vec3> for &'b Float {
+impl ops::Mul<&Vec3> for &Float {
     type Output = Vec3;
 
     fn mul(self, a: &Vec3) -> Self::Output {
@@ -169,7 +185,7 @@ impl<'a, 'b> ops::Mul<&'a Vec3> for &'b Float {
         }
     }
 }
-impl<'a> ops::Mul<Vec3> for &'a Float {
+impl ops::Mul<Vec3> for &Float {
     type Output = Vec3;
 
     fn mul(self, a: Vec3) -> Self::Output {
@@ -182,7 +198,7 @@ impl<'a> ops::Mul<Vec3> for &'a Float {
     }
 }
 
-impl<'a> ops::Mul<&'a Vec3> for Float {
+impl ops::Mul<&Vec3> for Float {
     type Output = Vec3;
 
     fn mul(self, a: &Vec3) -> Self::Output {
@@ -208,7 +224,7 @@ impl ops::Mul<Vec3> for Float {
     }
 }
 
-impl<'a, 'b> ops::Div<&'a Float> for &'b Vec3 {
+impl ops::Div<&Float> for &Vec3 {
     type Output = Vec3;
 
     fn div(self, b: &Float) -> Self::Output {
@@ -222,7 +238,7 @@ impl<'a, 'b> ops::Div<&'a Float> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Div<Float> for &'a Vec3 {
+impl ops::Div<Float> for &Vec3 {
     type Output = Vec3;
 
     fn div(self, b: Float) -> Self::Output {
@@ -235,7 +251,7 @@ impl<'a> ops::Div<Float> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Div<&'a Float> for Vec3 {
+impl ops::Div<&Float> for Vec3 {
     type Output = Vec3;
 
     fn div(self, b: &Float) -> Self::Output {
@@ -268,14 +284,14 @@ impl ops::Div<&Vec3> for Float {
     fn div(self, a: &Vec3) -> Self::Output {
         let b = self;
         Vec3 {
-            x: a.x / b,
-            y: a.y / b,
-            z: a.z / b,
+            x: b / a.x,
+            y: b / a.y,
+            z: b / a.z,
         }
     }
 }
 
-impl<'a, 'b> ops::Mul<&'a Vec3> for &'b Vec3 {
+impl ops::Mul<&Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn mul(self, b: &Vec3) -> Self::Output {
@@ -289,7 +305,7 @@ impl<'a, 'b> ops::Mul<&'a Vec3> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Mul<Vec3> for &'a Vec3 {
+impl ops::Mul<Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn mul(self, b: Vec3) -> Self::Output {
@@ -303,7 +319,7 @@ impl<'a> ops::Mul<Vec3> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Mul<&'a Vec3> for Vec3 {
+impl ops::Mul<&Vec3> for Vec3 {
     type Output = Vec3;
 
     fn mul(self, b: &Vec3) -> Self::Output {
@@ -331,7 +347,7 @@ impl ops::Mul<Vec3> for Vec3 {
     }
 }
 
-impl<'a, 'b> ops::Div<&'a Vec3> for &'b Vec3 {
+impl ops::Div<&Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn div(self, b: &Vec3) -> Self::Output {
@@ -345,7 +361,7 @@ impl<'a, 'b> ops::Div<&'a Vec3> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Div<Vec3> for &'a Vec3 {
+impl ops::Div<Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn div(self, b: Vec3) -> Self::Output {
@@ -359,7 +375,7 @@ impl<'a> ops::Div<Vec3> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Div<&'a Vec3> for Vec3 {
+impl ops::Div<&Vec3> for Vec3 {
     type Output = Vec3;
 
     fn div(self, b: &Vec3) -> Self::Output {
@@ -387,7 +403,7 @@ impl ops::Div<Vec3> for Vec3 {
     }
 }
 
-impl<'a, 'b> ops::Add<&'a Vec3> for &'b Vec3 {
+impl ops::Add<&Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn add(self, b: &Vec3) -> Self::Output {
@@ -401,7 +417,7 @@ impl<'a, 'b> ops::Add<&'a Vec3> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Add<Vec3> for &'a Vec3 {
+impl ops::Add<Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn add(self, b: Vec3) -> Self::Output {
@@ -415,7 +431,7 @@ impl<'a> ops::Add<Vec3> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Add<&'a Vec3> for Vec3 {
+impl ops::Add<&Vec3> for Vec3 {
     type Output = Vec3;
 
     fn add(self, b: &Vec3) -> Self::Output {
@@ -443,7 +459,7 @@ impl ops::Add<Vec3> for Vec3 {
     }
 }
 
-impl<'a, 'b> ops::Sub<&'a Vec3> for &'b Vec3 {
+impl ops::Sub<&Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn sub(self, b: &Vec3) -> Self::Output {
@@ -457,7 +473,7 @@ impl<'a, 'b> ops::Sub<&'a Vec3> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Sub<Vec3> for &'a Vec3 {
+impl ops::Sub<Vec3> for &Vec3 {
     type Output = Vec3;
 
     fn sub(self, b: Vec3) -> Self::Output {
@@ -471,7 +487,7 @@ impl<'a> ops::Sub<Vec3> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Sub<&'a Vec3> for Vec3 {
+impl ops::Sub<&Vec3> for Vec3 {
     type Output = Vec3;
 
     fn sub(self, b: &Vec3) -> Self::Output {
@@ -499,7 +515,7 @@ impl ops::Sub<Vec3> for Vec3 {
     }
 }
 
-impl<'a, 'b> ops::Rem<&'a Vec3> for &'b Vec3 {
+impl ops::Rem<&Vec3> for &Vec3 {
     type Output = Float;
 
     fn rem(self, b: &Vec3) -> Self::Output {
@@ -508,7 +524,7 @@ impl<'a, 'b> ops::Rem<&'a Vec3> for &'b Vec3 {
     }
 }
 
-impl<'a> ops::Rem<Vec3> for &'a Vec3 {
+impl ops::Rem<Vec3> for &Vec3 {
     type Output = Float;
 
     fn rem(self, b: Vec3) -> Self::Output {
@@ -518,7 +534,7 @@ impl<'a> ops::Rem<Vec3> for &'a Vec3 {
     }
 }
 
-impl<'a> ops::Rem<&'a Vec3> for Vec3 {
+impl ops::Rem<&Vec3> for Vec3 {
     type Output = Float;
 
     fn rem(self, b: &Vec3) -> Self::Output {
@@ -538,7 +554,7 @@ impl ops::Rem<Vec3> for Vec3 {
     }
 }
 
-impl<'a> ops::MulAssign<&'a Vec3> for Vec3 {
+impl ops::MulAssign<&Vec3> for Vec3 {
     fn mul_assign(&mut self, b: &Vec3) {
         *self = &(*self) * b;
     }